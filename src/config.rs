@@ -0,0 +1,130 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::rules::Rule;
+
+/// Structured configuration, loaded from the (plain json) file given
+/// by `--config`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub(crate) rules: Vec<Rule>,
+    /// Additional semantic convention mappings, from attribute name to
+    /// the name used for tag matching.
+    #[serde(default)]
+    pub(crate) semconv: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) service_key: ServiceKeyConfig,
+    /// Names to publish item and relation types under.
+    #[serde(default)]
+    pub(crate) type_names: BTreeMap<String, String>,
+    /// Names to publish properties under.
+    #[serde(default)]
+    pub(crate) property_names: BTreeMap<String, String>,
+    /// Destinations for the discovered graph.
+    #[serde(default = "default_sinks")]
+    pub(crate) sinks: Vec<SinkConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum SinkConfig {
+    /// The ContinuousC Relation Graph Engine at `url`, or `--rg-url`
+    /// if not given.
+    RelationGraph {
+        #[serde(default)]
+        url: Option<Url>,
+    },
+    /// Timestamped files in the directory at `path`, one per cycle.
+    File {
+        path: PathBuf,
+        #[serde(default)]
+        gzip: bool,
+    },
+    /// Gauges on the metrics endpoint (see `--metrics-addr`).
+    Prometheus {
+        /// Also expose Grafana service graph compatible counters.
+        #[serde(default)]
+        service_graph: bool,
+    },
+    /// A webhook receiving a summary of the changes whenever the graph
+    /// changes.
+    Webhook {
+        url: Url,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// A Kafka topic, receiving the payload (or delta) of every cycle.
+    #[cfg(feature = "kafka")]
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+        #[serde(default)]
+        partition: i32,
+        #[serde(default)]
+        delta: bool,
+    },
+    /// A Neo4j database, reached over the bolt protocol.
+    #[cfg(feature = "neo4j")]
+    Neo4j {
+        uri: String,
+        user: String,
+        password: String,
+    },
+}
+
+/// Components identifying a service. By default, a service is
+/// identified by its namespace, name and instance id.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ServiceKeyConfig {
+    #[serde(default = "default_true")]
+    pub(crate) namespace: bool,
+    #[serde(default = "default_true")]
+    pub(crate) instance_id: bool,
+    /// Tag to use as service name instead of the process' service name.
+    #[serde(default)]
+    pub(crate) name_tag: Option<String>,
+    /// Additional tags identifying the service.
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            semconv: BTreeMap::new(),
+            service_key: ServiceKeyConfig::default(),
+            type_names: BTreeMap::new(),
+            property_names: BTreeMap::new(),
+            sinks: default_sinks(),
+        }
+    }
+}
+
+impl Default for ServiceKeyConfig {
+    fn default() -> Self {
+        Self {
+            namespace: true,
+            instance_id: true,
+            name_tag: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::RelationGraph { url: None }]
+}
+
+fn default_true() -> bool {
+    true
+}
@@ -0,0 +1,454 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Incremental, chunked and partitioned updates to the relation graph.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::discovery::{Domain, Item, Items, Relation, TypeSet};
+
+/// Hashes of the items and relations last pushed to the relation graph.
+#[derive(Debug)]
+pub(crate) struct Fingerprints {
+    items: BTreeMap<Uuid, u64>,
+    relations: BTreeMap<Uuid, u64>,
+}
+
+/// Changes to the world since the last push.
+#[derive(Serialize, Debug)]
+pub(crate) struct ItemsDelta<'a> {
+    domain: &'a Domain,
+    items: DeltaSet<'a, Item>,
+    relations: DeltaSet<'a, Relation>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeltaSet<'a, T> {
+    updated: BTreeMap<Uuid, &'a T>,
+    removed: BTreeSet<Uuid>,
+}
+
+/// A part of the world, scoped to a subset of the item or relation
+/// types.
+#[derive(Serialize, Debug)]
+pub(crate) struct Partition<'a> {
+    domain: Domain,
+    items: PartitionWorld<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionWorld<'a> {
+    items: BTreeMap<Uuid, &'a Item>,
+    relations: BTreeMap<Uuid, &'a Relation>,
+}
+
+/// A single change, in the order in which changes are sent: items
+/// before the relations referring to them, and relations removed
+/// before their endpoints.
+enum Change<'a> {
+    Item(Uuid, &'a Item),
+    Relation(Uuid, &'a Relation),
+    RemovedRelation(Uuid),
+    RemovedItem(Uuid),
+}
+
+impl Fingerprints {
+    pub(crate) fn new(items: &Items) -> Self {
+        Self {
+            items: fingerprints(&items.items.items),
+            relations: fingerprints(&items.items.relations),
+        }
+    }
+}
+
+impl<'a> ItemsDelta<'a> {
+    pub(crate) fn new(old: &Fingerprints, new: &Fingerprints, items: &'a Items) -> Self {
+        Self {
+            domain: &items.domain,
+            items: DeltaSet::new(&old.items, &new.items, &items.items.items),
+            relations: DeltaSet::new(&old.relations, &new.relations, &items.items.relations),
+        }
+    }
+
+    /// A delta adding the complete world.
+    pub(crate) fn full(items: &'a Items) -> Self {
+        Self {
+            domain: &items.domain,
+            items: DeltaSet::all(&items.items.items),
+            relations: DeltaSet::all(&items.items.relations),
+        }
+    }
+
+    /// Split the delta into chunks of at most `size` changes. Always
+    /// returns at least one chunk, so domain updates are sent even if
+    /// nothing else changed.
+    pub(crate) fn split(self, size: usize) -> Vec<Self> {
+        let changes = self
+            .items
+            .updated
+            .into_iter()
+            .map(|(id, item)| Change::Item(id, item))
+            .chain(
+                self.relations
+                    .updated
+                    .into_iter()
+                    .map(|(id, rel)| Change::Relation(id, rel)),
+            )
+            .chain(
+                self.relations
+                    .removed
+                    .into_iter()
+                    .map(Change::RemovedRelation),
+            )
+            .chain(self.items.removed.into_iter().map(Change::RemovedItem))
+            .collect::<Vec<_>>();
+
+        let mut chunks = changes
+            .chunks(size.max(1))
+            .map(|changes| {
+                let mut chunk = Self::empty(self.domain);
+                for change in changes {
+                    match *change {
+                        Change::Item(id, item) => {
+                            chunk.items.updated.insert(id, item);
+                        }
+                        Change::Relation(id, rel) => {
+                            chunk.relations.updated.insert(id, rel);
+                        }
+                        Change::RemovedRelation(id) => {
+                            chunk.relations.removed.insert(id);
+                        }
+                        Change::RemovedItem(id) => {
+                            chunk.items.removed.insert(id);
+                        }
+                    }
+                }
+                chunk
+            })
+            .collect::<Vec<_>>();
+
+        if chunks.is_empty() {
+            chunks.push(Self::empty(self.domain));
+        }
+
+        chunks
+    }
+
+    fn empty(domain: &'a Domain) -> Self {
+        Self {
+            domain,
+            items: DeltaSet::default(),
+            relations: DeltaSet::default(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.updated.len()
+            + self.items.removed.len()
+            + self.relations.updated.len()
+            + self.relations.removed.len()
+    }
+}
+
+impl<T> Default for DeltaSet<'_, T> {
+    fn default() -> Self {
+        Self {
+            updated: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        }
+    }
+}
+
+impl<'a, T> DeltaSet<'a, T> {
+    fn all(values: &'a BTreeMap<Uuid, T>) -> Self {
+        Self {
+            updated: values.iter().map(|(id, value)| (*id, value)).collect(),
+            removed: BTreeSet::new(),
+        }
+    }
+
+    fn new(
+        old: &BTreeMap<Uuid, u64>,
+        new: &BTreeMap<Uuid, u64>,
+        values: &'a BTreeMap<Uuid, T>,
+    ) -> Self {
+        Self {
+            updated: values
+                .iter()
+                .filter(|(id, _)| old.get(id) != new.get(id))
+                .map(|(id, value)| (*id, value))
+                .collect(),
+            removed: old
+                .keys()
+                .filter(|id| !new.contains_key(id))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+impl<'a> Partition<'a> {
+    /// Split the world into operations, other items and relations,
+    /// each with its own domain. Only the first partition carries the
+    /// roots, so operations and relations are not marked as roots.
+    pub(crate) fn split(items: &'a Items) -> [Self; 3] {
+        let partition = |roots, item_types: BTreeSet<String>, relation_types| Self {
+            items: PartitionWorld {
+                items: items
+                    .items
+                    .items
+                    .iter()
+                    .filter(|(_, item)| item_types.contains(item.item_type()))
+                    .map(|(id, item)| (*id, item))
+                    .collect(),
+                relations: match relation_types {
+                    true => items
+                        .items
+                        .relations
+                        .iter()
+                        .map(|(id, rel)| (*id, rel))
+                        .collect(),
+                    false => BTreeMap::new(),
+                },
+            },
+            domain: Domain {
+                roots,
+                types: TypeSet {
+                    items: item_types,
+                    relations: match relation_types {
+                        true => items.domain.types.relations.clone(),
+                        false => BTreeSet::new(),
+                    },
+                },
+            },
+        };
+
+        /* Operations are the items with a parent, whatever their type
+         * is mapped to. */
+        let operation_types = items
+            .items
+            .items
+            .values()
+            .filter(|item| item.parent().is_some())
+            .map(Item::item_type)
+            .collect::<BTreeSet<_>>();
+        let (operations, others) = items
+            .domain
+            .types
+            .items
+            .iter()
+            .cloned()
+            .partition(|item_type| operation_types.contains(item_type.as_str()));
+
+        [
+            partition(items.domain.roots.clone(), others, false),
+            partition(Some(BTreeSet::new()), operations, false),
+            partition(Some(BTreeSet::new()), BTreeSet::new(), true),
+        ]
+    }
+}
+
+/// A stable hash of the complete payload, used to detect unchanged
+/// graphs.
+pub(crate) fn payload_hash(items: &Items) -> u64 {
+    hash(items)
+}
+
+fn fingerprints<T: Serialize>(values: &BTreeMap<Uuid, T>) -> BTreeMap<Uuid, u64> {
+    values
+        .iter()
+        .map(|(id, value)| (*id, hash(value)))
+        .collect()
+}
+
+fn hash<T: Serialize>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(value).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::discovery::{TypeSet, World};
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    fn service(name: &str) -> Item {
+        serde_json::from_value(json!({
+            "item_type": "jaeger/service",
+            "properties": { "jaeger/service_name": { "string": name } },
+        }))
+        .unwrap()
+    }
+
+    fn operation(parent: Uuid, name: &str) -> Item {
+        serde_json::from_value(json!({
+            "item_type": "jaeger/operation",
+            "parent": parent,
+            "properties": { "jaeger/operation_name": { "string": name } },
+        }))
+        .unwrap()
+    }
+
+    fn invokes(source: Uuid, target: Uuid) -> Relation {
+        serde_json::from_value(json!({
+            "relation_type": "jaeger/service_invokes",
+            "source": source,
+            "target": target,
+            "properties": {
+                "jaeger/confidence": { "float": 1.0 },
+                "jaeger/observations_24h": { "integer": 1 },
+                "jaeger/observations_previous_24h": { "integer": 0 },
+                "jaeger/active_hours": { "integer": 1 },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn items(items: Vec<(Uuid, Item)>, relations: Vec<(Uuid, Relation)>) -> Items {
+        let world = World {
+            items: items.into_iter().collect(),
+            relations: relations.into_iter().collect(),
+        };
+        Items {
+            domain: Domain {
+                roots: Some(BTreeSet::from([id(100)])),
+                types: TypeSet {
+                    items: world
+                        .items
+                        .values()
+                        .map(|item| item.item_type().to_string())
+                        .collect(),
+                    relations: world
+                        .relations
+                        .values()
+                        .map(|rel| rel.relation_type().to_string())
+                        .collect(),
+                },
+            },
+            items: world,
+        }
+    }
+
+    #[test]
+    fn delta_contains_changed_and_removed_entries() {
+        let old = items(
+            vec![(id(1), service("a")), (id(2), service("b"))],
+            vec![(id(10), invokes(id(1), id(2)))],
+        );
+        let new = items(vec![(id(1), service("a2")), (id(3), service("c"))], vec![]);
+        let delta = ItemsDelta::new(&Fingerprints::new(&old), &Fingerprints::new(&new), &new);
+
+        assert_eq!(
+            delta.items.updated.keys().copied().collect::<Vec<_>>(),
+            [id(1), id(3)]
+        );
+        assert_eq!(delta.items.removed, BTreeSet::from([id(2)]));
+        assert!(delta.relations.updated.is_empty());
+        assert_eq!(delta.relations.removed, BTreeSet::from([id(10)]));
+        assert_eq!(delta.len(), 4);
+    }
+
+    #[test]
+    fn split_orders_items_relations_and_removals() {
+        let old = items(
+            vec![(id(1), service("a")), (id(9), service("z"))],
+            vec![(id(19), invokes(id(1), id(9)))],
+        );
+        let new = items(
+            vec![(id(1), service("a2")), (id(2), service("b"))],
+            vec![(id(10), invokes(id(1), id(2)))],
+        );
+        let chunks =
+            ItemsDelta::new(&Fingerprints::new(&old), &Fingerprints::new(&new), &new).split(2);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[0].items.updated.keys().copied().collect::<Vec<_>>(),
+            [id(1), id(2)]
+        );
+        assert_eq!(
+            chunks[1]
+                .relations
+                .updated
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            [id(10)]
+        );
+        assert_eq!(chunks[1].relations.removed, BTreeSet::from([id(19)]));
+        assert_eq!(chunks[2].items.removed, BTreeSet::from([id(9)]));
+        assert_eq!(chunks[2].len(), 1);
+    }
+
+    #[test]
+    fn split_unchanged_delta_yields_one_chunk() {
+        let world = items(vec![(id(1), service("a"))], vec![]);
+        let fingerprints = Fingerprints::new(&world);
+        let chunks = ItemsDelta::new(&fingerprints, &fingerprints, &world).split(10);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), 0);
+    }
+
+    #[test]
+    fn partition_finds_operations_by_parent() {
+        let world = items(
+            vec![
+                (id(1), service("a")),
+                (id(2), operation(id(1), "GET /")),
+                (id(3), operation(id(1), "POST /")),
+            ],
+            vec![(id(10), invokes(id(1), id(1)))],
+        );
+        let [others, operations, relations] = Partition::split(&world);
+
+        assert_eq!(others.domain.roots, world.domain.roots);
+        assert_eq!(
+            others.domain.types.items,
+            BTreeSet::from([String::from("jaeger/service")])
+        );
+        assert_eq!(
+            others.items.items.keys().copied().collect::<Vec<_>>(),
+            [id(1)]
+        );
+        assert!(others.items.relations.is_empty());
+
+        assert_eq!(operations.domain.roots, Some(BTreeSet::new()));
+        assert_eq!(
+            operations.domain.types.items,
+            BTreeSet::from([String::from("jaeger/operation")])
+        );
+        assert_eq!(
+            operations.items.items.keys().copied().collect::<Vec<_>>(),
+            [id(2), id(3)]
+        );
+
+        assert!(relations.domain.types.items.is_empty());
+        assert!(relations.items.items.is_empty());
+        assert_eq!(
+            relations
+                .items
+                .relations
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            [id(10)]
+        );
+        assert_eq!(
+            relations.domain.types.relations,
+            world.domain.types.relations
+        );
+    }
+}
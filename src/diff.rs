@@ -0,0 +1,251 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Differences between the graphs built in consecutive cycles, or
+//! between the graph built and the graph in the relation graph.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    discovery::{Item, Items, World},
+    rename::Renames,
+};
+
+/// Human-readable labels of the items and relations in a graph.
+#[derive(Default, Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) items: BTreeMap<Uuid, String>,
+    pub(crate) relations: BTreeMap<Uuid, String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub(crate) struct GraphDiff {
+    pub(crate) added_items: Vec<String>,
+    pub(crate) removed_items: Vec<String>,
+    pub(crate) added_relations: Vec<String>,
+    pub(crate) removed_relations: Vec<String>,
+}
+
+/// The world as returned by the relation graph. Items and relations
+/// are kept as json, since they need not have been written by us.
+#[derive(Deserialize, Debug)]
+pub(crate) struct RemoteWorld {
+    #[serde(default)]
+    pub(crate) items: BTreeMap<Uuid, Value>,
+    #[serde(default)]
+    pub(crate) relations: BTreeMap<Uuid, Value>,
+}
+
+/// Differences between the world in the relation graph (restricted to
+/// our domain) and the world we would push.
+#[derive(Default, Debug)]
+pub(crate) struct LiveDiff {
+    pub(crate) added: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) changed: Vec<String>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(world: &World) -> Self {
+        let name = |id: &Uuid| match world.items.get(id) {
+            Some(item) => item_name(world, item),
+            None => id.to_string(),
+        };
+        Self {
+            items: world
+                .items
+                .iter()
+                .map(|(id, item)| (*id, format!("{} {}", item.item_type(), name(id))))
+                .collect(),
+            relations: world
+                .relations
+                .iter()
+                .map(|(id, rel)| {
+                    let (source, target) = rel.endpoints();
+                    (
+                        *id,
+                        format!(
+                            "{} {} -> {}",
+                            rel.relation_type(),
+                            name(&source),
+                            name(&target)
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+fn item_name(world: &World, item: &Item) -> String {
+    match item.parent().and_then(|parent| world.items.get(&parent)) {
+        Some(parent) => format!("{}: {}", item_name(world, parent), item.name()),
+        None => item.name(),
+    }
+}
+
+impl GraphDiff {
+    pub(crate) fn new(old: &Snapshot, new: &Snapshot) -> Self {
+        Self {
+            added_items: added(&old.items, &new.items),
+            removed_items: added(&new.items, &old.items),
+            added_relations: added(&old.relations, &new.relations),
+            removed_relations: added(&new.relations, &old.relations),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added_items.is_empty()
+            && self.removed_items.is_empty()
+            && self.added_relations.is_empty()
+            && self.removed_relations.is_empty()
+    }
+
+    pub(crate) fn log(&self, structured: bool) {
+        if structured {
+            log::info!(
+                "{}",
+                serde_json::json!({ "event": "graph_diff", "diff": self })
+            );
+        } else {
+            log::info!(
+                "Graph changed: +{}/-{} items, +{}/-{} relations.",
+                self.added_items.len(),
+                self.removed_items.len(),
+                self.added_relations.len(),
+                self.removed_relations.len()
+            );
+            self.added_items
+                .iter()
+                .for_each(|item| log::info!("added item: {item}"));
+            self.removed_items
+                .iter()
+                .for_each(|item| log::info!("removed item: {item}"));
+            self.added_relations
+                .iter()
+                .for_each(|rel| log::info!("added relation: {rel}"));
+            self.removed_relations
+                .iter()
+                .for_each(|rel| log::info!("removed relation: {rel}"));
+        }
+    }
+}
+
+impl LiveDiff {
+    pub(crate) fn new(remote: &RemoteWorld, local: &Items, renames: &Renames) -> Self {
+        let types = &local.domain.types;
+        let remote_items = remote
+            .items
+            .iter()
+            .filter(|(_, item)| has_type(item, "item_type", &types.items))
+            .collect::<BTreeMap<_, _>>();
+        let remote_relations = remote
+            .relations
+            .iter()
+            .filter(|(_, rel)| has_type(rel, "relation_type", &types.relations))
+            .collect::<BTreeMap<_, _>>();
+
+        /* Label remote entries as we would, where they can be parsed. */
+        let parsed = World {
+            items: remote_items
+                .iter()
+                .filter_map(|(id, item)| Some((**id, renames.parse_item((*item).clone())?)))
+                .collect(),
+            relations: remote_relations
+                .iter()
+                .filter_map(|(id, rel)| Some((**id, renames.parse_relation((*rel).clone())?)))
+                .collect(),
+        };
+        let remote_labels = Snapshot::new(&parsed);
+        let local_labels = Snapshot::new(&local.items);
+        let remote_label = |labels: &BTreeMap<Uuid, String>, id: &Uuid, value: &Value, key| {
+            labels.get(id).cloned().unwrap_or_else(|| {
+                let typ = value.get(key).and_then(Value::as_str).unwrap_or("unknown");
+                format!("{typ} {id}")
+            })
+        };
+
+        let mut diff = Self::default();
+        for (id, item) in &local.items.items {
+            match remote_items.get(id) {
+                None => diff.added.push(local_labels.items[id].clone()),
+                Some(value) if serde_json::to_value(item).unwrap() != **value => {
+                    diff.changed.push(local_labels.items[id].clone())
+                }
+                Some(_) => {}
+            }
+        }
+        for (id, value) in &remote_items {
+            if !local.items.items.contains_key(id) {
+                diff.removed
+                    .push(remote_label(&remote_labels.items, id, value, "item_type"));
+            }
+        }
+        for (id, rel) in &local.items.relations {
+            match remote_relations.get(id) {
+                None => diff.added.push(local_labels.relations[id].clone()),
+                Some(value) if serde_json::to_value(rel).unwrap() != **value => {
+                    diff.changed.push(local_labels.relations[id].clone())
+                }
+                Some(_) => {}
+            }
+        }
+        for (id, value) in &remote_relations {
+            if !local.items.relations.contains_key(id) {
+                diff.removed.push(remote_label(
+                    &remote_labels.relations,
+                    id,
+                    value,
+                    "relation_type",
+                ));
+            }
+        }
+        diff
+    }
+
+    /// Render the differences as text, one line per item or relation.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        for (prefix, labels) in [
+            ("+", &self.added),
+            ("-", &self.removed),
+            ("~", &self.changed),
+        ] {
+            for label in labels {
+                writeln!(out, "{prefix} {label}").unwrap();
+            }
+        }
+        writeln!(
+            out,
+            "{} to add, {} to remove, {} to change",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+        .unwrap();
+        out
+    }
+}
+
+fn has_type(value: &Value, key: &str, types: &BTreeSet<String>) -> bool {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .is_some_and(|typ| types.contains(typ))
+}
+
+/// Labels of entries in `new` that are not in `old`.
+fn added(old: &BTreeMap<Uuid, String>, new: &BTreeMap<Uuid, String>) -> Vec<String> {
+    new.iter()
+        .filter(|(id, _)| !old.contains_key(id))
+        .map(|(_, label)| label.clone())
+        .collect()
+}
@@ -3,43 +3,180 @@
  ******************************************************************************/
 
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
     fmt::Display,
-    num::ParseIntError,
+    num::{NonZeroU32, NonZeroUsize, ParseIntError},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, TimeDelta, Utc};
-use reqwest::{
-    header::{HeaderMap, HeaderValue},
-    Client,
-};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use hashbrown::HashMap;
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
+use tracing::Instrument;
 use url::Url;
 use uuid::Uuid;
 
 use crate::{
+    config::Config,
+    diff::{GraphDiff, Snapshot},
     error::Error,
-    load_cert, load_identity, load_json,
-    query::EsPit,
-    save_json,
+    fetch::{self, Window},
+    fold::{self, Observed, Tracer},
+    graph::{Changes, Graph, Part},
+    indices,
+    inspect::{self, SUMMARY_REQUEST},
+    intern::{self, Name},
+    load_cert, load_config, load_identity,
+    lru::{TraceLimits, TraceLru},
+    preflight::{self, ServiceBucket},
+    query::BatchSize,
+    rename::Renames,
+    required,
+    schema::PayloadSchema,
+    semconv::Semconv,
+    sink::{self, GraphSink, PushQueue, PushTask},
     state::{
-        OperationKey, OperationName, OperationState, ServiceInstanceId, ServiceKey, ServiceName,
-        ServiceNamespace, ServiceState, SpanId, State, TraceId, TraceInfo,
+        BufferedTrace, DestinationName, DestinationState, ExternalCall, ExternalEndpointState,
+        HttpStatusCounts, OperationKey, OperationName, OperationState, ProducesState,
+        QueryPosition, RelationState, RelationTarget, RunInfo, RunRecord, ServiceInstanceId,
+        ServiceKey, ServiceName, ServiceNamespace, ServiceState, SpanId, SpanKind, State, TraceId,
+        TraceInfo,
     },
+    status::Status,
+    store::{StateFormat, StateStore},
+    summary::{CycleSummary, Phase},
+    wal::{Batch, SpanLog},
     Args,
 };
 
+#[cfg(feature = "jemalloc")]
+use crate::heap;
+#[cfg(feature = "sled")]
+use crate::spill::TraceSpill;
+
+/// Period after which services, operations and relations no longer
+/// seen are removed.
+pub(crate) const RETENTION: TimeDelta = TimeDelta::days(7);
+
+/// Period after which the spans of a service are scanned again with
+/// `--preflight`, even without new operations. New relations between
+/// known operations of services without new operations are only found
+/// then.
+const PREFLIGHT_REFRESH: TimeDelta = TimeDelta::hours(1);
+
+/// Window over which relation observations are summed for the
+/// published trend properties.
+const DAY: TimeDelta = TimeDelta::days(1);
+
 pub(crate) struct Discovery {
-    state_path: PathBuf,
-    state: State,
-    rg_client: Client,
-    es_client: Client,
-    es_url: Url,
-    rg_url: Url,
+    store: StateStore,
+    pub(crate) state: State,
+    granularity: Granularity,
+    function_items: bool,
+    merge_instances: bool,
+    instance_window: TimeDelta,
+    stale_grace: TimeDelta,
+    /// Order of the traces for eviction, if the trace map is limited.
+    lru: Option<TraceLru>,
+    /// Period after the last span a trace is kept for finding
+    /// relations between its spans.
+    trace_retention: TimeDelta,
+    /// Wall-clock period after which traces no longer updated are
+    /// removed, even if no newer spans are seen.
+    trace_max_age: Option<TimeDelta>,
+    /// Start of the current cycle.
+    cycle_start: DateTime<Utc>,
+    /// Number of cycles kept in the run history.
+    run_history: usize,
+    /// Directory to write the status of every cycle to.
+    status_dir: Option<PathBuf>,
+    /// Period after which relations observed within a single hour
+    /// only are removed.
+    one_off_retention: Option<TimeDelta>,
+    /// Derive ids from keys, so states of several shards can be
+    /// merged.
+    deterministic_ids: bool,
+    /// Traces moved to disk to bound the trace map in memory.
+    #[cfg(feature = "sled")]
+    spill: Option<TraceSpill>,
+    /// Number of slices of the span query, fetched concurrently.
+    query_slices: NonZeroU32,
+    /// Number of spans fetched per request, adapted within bounds.
+    batch_size: BatchSize,
+    docvalue_fields: bool,
+    /// Search only the indices covering the window to fetch, if it
+    /// spans at most this many days.
+    target_indices: Option<u32>,
+    /// Scan only the spans of services with new operations, as found
+    /// by a pre-flight aggregation, and of services not scanned
+    /// recently.
+    preflight: bool,
+    /// Time of the last scan of the spans of each service.
+    preflight_scans: HashMap<String, DateTime<Utc>>,
+    /// Write-ahead log of the batches processed since the state was
+    /// saved.
+    span_log: Option<SpanLog>,
+    /// Save the state every given number of batches, so a restart
+    /// during a long cycle (e.g. a backfill) resumes from there.
+    checkpoint_batches: Option<NonZeroUsize>,
+    /// Trace the spans of a batch in this many threads, partitioned by
+    /// trace id.
+    fold_threads: Option<NonZeroUsize>,
+    /// Stop fetching spans once a cycle has processed this many, and
+    /// continue with the backlog in the next cycle.
+    max_cycle_spans: Option<NonZeroUsize>,
+    /// Publish the graph at this interval during long cycles (e.g. a
+    /// backfill), instead of only at the end.
+    interim_push: Option<TimeDelta>,
+    semconv: Semconv,
+    renames: Arc<Renames>,
+    structured_diff: bool,
+    discovery_item: bool,
+    roots: Roots,
+    quiescence: Option<TimeDelta>,
+    previous: Option<Snapshot>,
+    /// Keep the payload most recently published, for the admin api.
+    keep_latest: bool,
+    latest: Option<Arc<Items>>,
+    /// The graph published in the previous cycle, and the changes to
+    /// the state since.
+    graph: Graph,
+    changes: Changes,
+    config: Config,
+    sinks: Vec<Box<dyn GraphSink>>,
+    queue: Option<PushQueue>,
+    /// Push each graph while the next cycle scans for spans, waiting
+    /// for it before publishing the next graph.
+    overlap_push: bool,
+    pushing: Option<PushTask>,
+    schema: Option<PayloadSchema>,
+    es: Option<EsConnection>,
+}
+
+/// Level of detail of the discovered topology.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum Granularity {
+    /// Discover services and their relations only.
+    Service,
+    /// Discover services, operations and their relations.
+    Operation,
+}
+
+/// Items published as domain roots.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum Roots {
+    /// No explicit roots: all jaeger objects.
+    None,
+    /// All services.
+    Services,
+    /// Services not invoked by any other service.
+    EntryServices,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -49,9 +186,12 @@ pub(crate) struct Service {
     pub(crate) operation_name: OperationName,
 }
 
+/// A span as stored by Jaeger. Tag keys and string values borrow from
+/// the document they are deserialized from where possible, since most
+/// are only inspected.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct Span {
+pub(crate) struct Span<'a> {
     #[serde(rename = "traceID")]
     pub(crate) trace_id: TraceId,
     #[serde(rename = "spanID")]
@@ -61,9 +201,11 @@ pub(crate) struct Span {
     pub(crate) start_time: i64,
     pub(crate) start_time_millis: i64,
     pub(crate) duration: u64,
-    pub(crate) tags: Vec<Tag>,
+    #[serde(borrow)]
+    pub(crate) tags: Vec<Tag<'a>>,
     pub(crate) logs: Vec<Log>,
-    pub(crate) process: Process,
+    #[serde(borrow)]
+    pub(crate) process: Process<'a>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -76,19 +218,21 @@ pub(crate) struct Reference {
     pub(crate) span_id: SpanId,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub(crate) enum RefType {
     ChildOf,
+    FollowsFrom,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct Tag {
-    pub(crate) key: String,
-    #[serde(flatten)]
-    pub(crate) value: TagValue, // pub(crate) r#type: TagType,
-                                // pub(crate) value: String,
+pub(crate) struct Tag<'a> {
+    #[serde(borrow)]
+    pub(crate) key: Cow<'a, str>,
+    #[serde(flatten, borrow)]
+    pub(crate) value: TagValue<'a>, // pub(crate) r#type: TagType,
+                                    // pub(crate) value: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -101,8 +245,8 @@ pub(crate) enum TagType {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "value", rename_all = "camelCase")]
-pub(crate) enum TagValue {
-    String(String),
+pub(crate) enum TagValue<'a> {
+    String(#[serde(borrow)] Cow<'a, str>),
     Int64(Int64),
     Bool(Bool),
 }
@@ -136,48 +280,85 @@ pub(crate) struct Log {}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct Process {
+pub(crate) struct Process<'a> {
     pub(crate) service_name: ServiceName,
-    pub(crate) tags: Vec<Tag>,
+    #[serde(borrow)]
+    pub(crate) tags: Vec<Tag<'a>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct Items {
     pub(crate) domain: Domain,
     pub(crate) items: World,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct Domain {
     pub roots: Option<BTreeSet<Uuid>>,
     pub types: TypeSet,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct TypeSet {
     pub items: BTreeSet<String>,
     pub relations: BTreeSet<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub(crate) struct World {
     pub(crate) items: BTreeMap<Uuid, Item>,
     pub(crate) relations: BTreeMap<Uuid, Relation>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "item_type")]
 pub(crate) enum Item {
     #[serde(rename = "jaeger/service")]
     Service { properties: Box<ServiceProps> },
+    #[serde(rename = "jaeger/function")]
+    Function { properties: Box<ServiceProps> },
     #[serde(rename = "jaeger/operation")]
     Operation {
         parent: Uuid,
         properties: Box<OperationProps>,
     },
+    #[serde(rename = "jaeger/messaging_destination")]
+    MessagingDestination { properties: Box<DestinationProps> },
+    #[serde(rename = "jaeger/external_endpoint")]
+    ExternalEndpoint {
+        properties: Box<ExternalEndpointProps>,
+    },
+    #[serde(rename = "jaeger/discovery")]
+    Discovery { properties: Box<DiscoveryProps> },
+    #[serde(untagged)]
+    Custom(CustomItem),
+    /// Only built by `Renames`, which parses renamed items as their
+    /// original type.
+    #[serde(untagged, skip_deserializing)]
+    Renamed(RenamedItem),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// An item with its type and property names mapped (see
+/// `type_names` and `property_names` in the configuration).
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct RenamedItem {
+    pub(crate) item_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) parent: Option<Uuid>,
+    pub(crate) properties: serde_json::Map<String, serde_json::Value>,
+    /// Name of the original item, for log messages.
+    #[serde(skip)]
+    pub(crate) name: String,
+}
+
+/// An item derived by a custom rule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CustomItem {
+    item_type: String,
+    properties: BTreeMap<String, StringProperty>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct ServiceProps {
     #[serde(
         default,
@@ -193,8 +374,16 @@ pub(crate) struct ServiceProps {
         skip_serializing_if = "Option::is_none"
     )]
     service_instance_id: Option<StringProperty<ServiceInstanceId>>,
+    #[serde(
+        default,
+        rename = "jaeger/service_instance_count",
+        skip_serializing_if = "Option::is_none"
+    )]
+    instance_count: Option<IntegerProperty>,
     #[serde(flatten)]
     meta: ServiceMeta,
+    #[serde(flatten)]
+    staleness: Option<StalenessProps>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
@@ -331,15 +520,121 @@ pub(crate) struct ServiceMeta {
         skip_serializing_if = "Option::is_none"
     )]
     k8s_cronjob_uid: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/faas_name",
+        skip_serializing_if = "Option::is_none"
+    )]
+    faas_name: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/faas_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    faas_id: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/faas_trigger",
+        skip_serializing_if = "Option::is_none"
+    )]
+    faas_trigger: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/cloud_platform",
+        skip_serializing_if = "Option::is_none"
+    )]
+    cloud_platform: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/container_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    container_id: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/container_image_name",
+        skip_serializing_if = "Option::is_none"
+    )]
+    container_image_name: Option<StringProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/process_pid",
+        skip_serializing_if = "Option::is_none"
+    )]
+    process_pid: Option<IntegerProperty>,
+    #[serde(
+        default,
+        rename = "jaeger/process_executable_name",
+        skip_serializing_if = "Option::is_none"
+    )]
+    process_executable_name: Option<StringProperty>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct OperationProps {
     #[serde(rename = "jaeger/operation_name")]
     operation_name: StringProperty<OperationName>,
+    #[serde(
+        default,
+        rename = "jaeger/span_kind",
+        skip_serializing_if = "Option::is_none"
+    )]
+    span_kind: Option<StringProperty>,
+    #[serde(flatten)]
+    staleness: Option<StalenessProps>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Properties marking items kept beyond the retention period.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct StalenessProps {
+    #[serde(rename = "jaeger/stale")]
+    stale: BooleanProperty,
+    #[serde(rename = "jaeger/last_seen")]
+    last_seen: TimeProperty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DestinationProps {
+    #[serde(rename = "jaeger/destination_name")]
+    destination_name: StringProperty<DestinationName>,
+    #[serde(
+        default,
+        rename = "jaeger/messaging_system",
+        skip_serializing_if = "Option::is_none"
+    )]
+    messaging_system: Option<StringProperty>,
+}
+
+/// Metadata on the discovery run that produced the graph.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DiscoveryProps {
+    #[serde(rename = "jaeger/last_run")]
+    last_run: TimeProperty,
+    #[serde(rename = "jaeger/spans_processed")]
+    spans_processed: IntegerProperty,
+    #[serde(
+        default,
+        rename = "jaeger/source_cluster",
+        skip_serializing_if = "Option::is_none"
+    )]
+    source_cluster: Option<StringProperty>,
+    #[serde(rename = "jaeger/discovery_version")]
+    version: StringProperty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ExternalEndpointProps {
+    #[serde(rename = "jaeger/external_host")]
+    host: StringProperty,
+    #[serde(
+        default,
+        rename = "jaeger/path_templates",
+        skip_serializing_if = "Option::is_none"
+    )]
+    paths: Option<StringProperty>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "relation_type")]
 pub(crate) enum Relation {
     #[serde(rename = "jaeger/service_invokes")]
@@ -354,10 +649,89 @@ pub(crate) enum Relation {
         target: Uuid,
         properties: InvokesProps,
     },
+    #[serde(rename = "jaeger/service_links")]
+    ServiceLinks {
+        source: Uuid,
+        target: Uuid,
+        properties: InvokesProps,
+    },
+    #[serde(rename = "jaeger/operation_links")]
+    OperationLinks {
+        source: Uuid,
+        target: Uuid,
+        properties: InvokesProps,
+    },
+    #[serde(rename = "jaeger/service_produces")]
+    ServiceProduces {
+        source: Uuid,
+        target: Uuid,
+        properties: ProducesProps,
+    },
+    #[serde(rename = "jaeger/service_calls_external")]
+    ServiceCallsExternal {
+        source: Uuid,
+        target: Uuid,
+        properties: InvokesProps,
+    },
+    #[serde(untagged)]
+    Custom(CustomRelation),
+    /// Only built by `Renames`, which parses renamed relations as
+    /// their original type.
+    #[serde(untagged, skip_deserializing)]
+    Renamed(RenamedRelation),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct InvokesProps {}
+/// A relation with its type and property names mapped.
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct RenamedRelation {
+    pub(crate) relation_type: String,
+    pub(crate) source: Uuid,
+    pub(crate) target: Uuid,
+    pub(crate) properties: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A relation derived by a custom rule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CustomRelation {
+    relation_type: String,
+    source: Uuid,
+    target: Uuid,
+    properties: BTreeMap<String, StringProperty>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct InvokesProps {
+    #[serde(rename = "jaeger/confidence")]
+    confidence: FloatProperty,
+    #[serde(flatten)]
+    http_status: Option<HttpStatusProps>,
+    #[serde(rename = "jaeger/observations_24h")]
+    observations_24h: IntegerProperty,
+    #[serde(rename = "jaeger/observations_previous_24h")]
+    observations_previous_24h: IntegerProperty,
+    #[serde(rename = "jaeger/active_hours")]
+    active_hours: IntegerProperty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ProducesProps {
+    #[serde(rename = "jaeger/consumer_observed")]
+    consumer_observed: BooleanProperty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct HttpStatusProps {
+    #[serde(rename = "jaeger/http_status_1xx")]
+    informational: IntegerProperty,
+    #[serde(rename = "jaeger/http_status_2xx")]
+    success: IntegerProperty,
+    #[serde(rename = "jaeger/http_status_3xx")]
+    redirection: IntegerProperty,
+    #[serde(rename = "jaeger/http_status_4xx")]
+    client_error: IntegerProperty,
+    #[serde(rename = "jaeger/http_status_5xx")]
+    server_error: IntegerProperty,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct StringProperty<T = String> {
@@ -370,436 +744,2080 @@ impl<T> StringProperty<T> {
     }
 }
 
-impl Discovery {
-    pub(crate) async fn new(args: &Args) -> Result<Self, Error> {
-        let state_path = args.state.join("state.json.gz");
-        let state = if state_path.exists() {
-            load_json::<State>(&state_path).await?
-        } else {
-            State::new()
-        };
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct IntegerProperty {
+    integer: u64,
+}
 
-        let mut headers = HeaderMap::new();
-        headers.insert("X-PROXY-ROLE", HeaderValue::try_from("Editor").unwrap());
-
-        let rg_client = Client::builder()
-            .timeout(std::time::Duration::from_secs(60))
-            .default_headers(headers)
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true) // TODO: disable
-            .build()
-            .map_err(Error::Reqwest)?;
-        let es_client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(60))
-            .add_root_certificate(load_cert(&args.es_ca).await?)
-            .identity(load_identity(&args.es_cert, &args.es_key).await?)
-            .danger_accept_invalid_hostnames(true) // TODO: disable!
-            .build()
-            .map_err(Error::Reqwest)?;
-        let es_url = args.es_url.clone();
-        let rg_url = args.rg_url.clone();
-
-        Ok(Self {
-            state_path,
-            state,
-            rg_client,
-            es_client,
-            es_url,
-            rg_url,
-        })
+impl IntegerProperty {
+    fn new(integer: u64) -> Self {
+        Self { integer }
     }
+}
 
-    pub(crate) async fn discover(&mut self) -> Result<(), Error> {
-        log::info!("running discovery");
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FloatProperty {
+    float: f64,
+}
 
-        let now = Utc::now();
-        let oper_threshold = now - TimeDelta::try_days(7).unwrap();
-
-        let mut pit = EsPit::new(&self.es_client, &self.es_url, "jaeger-span-*", "1m").await?;
-        let mut query = pit.query::<_, serde_json::Value, (i64,), Span>(
-            json!({
-                "range": {
-                    "startTime": {
-                        "gte": oper_threshold.timestamp_micros()
-                    }
-                }
-            }),
-            Some(json!([{ "startTime": { "order": "asc" } }])),
-            self.state.last_span.map(|v| (v.timestamp_micros(),)),
-            1000,
-        );
+impl FloatProperty {
+    fn new(float: f64) -> Self {
+        Self { float }
+    }
+}
 
-        let mut n = 0;
-        let res = async {
-            while let Some(res) = query.next().await? {
-                n += res.hits.hits.len();
-                if let Some(last) = res
-                    .hits
-                    .hits
-                    .last()
-                    .and_then(|hit| hit.sort.as_ref())
-                    .map(|sort| sort.0)
-                {
-                    self.state.last_span = Some(
-                        DateTime::from_timestamp_micros(last)
-                            .ok_or(Error::TimestampOutOfBounds(last))?,
-                    );
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct BooleanProperty {
+    boolean: bool,
+}
+
+impl BooleanProperty {
+    fn new(boolean: bool) -> Self {
+        Self { boolean }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TimeProperty {
+    time: DateTime<Utc>,
+}
+
+impl TimeProperty {
+    fn new(time: DateTime<Utc>) -> Self {
+        Self { time }
+    }
+}
+
+impl Item {
+    pub(crate) fn item_type(&self) -> &str {
+        match self {
+            Item::Service { .. } => "jaeger/service",
+            Item::Function { .. } => "jaeger/function",
+            Item::Operation { .. } => "jaeger/operation",
+            Item::MessagingDestination { .. } => "jaeger/messaging_destination",
+            Item::ExternalEndpoint { .. } => "jaeger/external_endpoint",
+            Item::Discovery { .. } => "jaeger/discovery",
+            Item::Custom(item) => &item.item_type,
+            Item::Renamed(item) => &item.item_type,
+        }
+    }
+
+    pub(crate) fn parent(&self) -> Option<Uuid> {
+        match self {
+            Item::Operation { parent, .. } => Some(*parent),
+            Item::Renamed(item) => item.parent,
+            _ => None,
+        }
+    }
+
+    /// A short name for the item, unique among its siblings.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            Item::Service { properties } | Item::Function { properties } => {
+                let mut name = String::new();
+                if let Some(ns) = &properties.service_namespace {
+                    name.push_str(&format!("{}/", ns.string));
                 }
+                name.push_str(&properties.service_name.string.to_string());
+                if let Some(inst) = &properties.service_instance_id {
+                    name.push_str(&format!(" {}", inst.string));
+                }
+                name
+            }
+            Item::Operation { properties, .. } => properties.operation_name.string.to_string(),
+            Item::MessagingDestination { properties } => {
+                properties.destination_name.string.to_string()
+            }
+            Item::ExternalEndpoint { properties } => properties.host.string.clone(),
+            Item::Discovery { properties } => match &properties.source_cluster {
+                Some(source) => source.string.clone(),
+                None => String::from("discovery"),
+            },
+            Item::Custom(item) => item
+                .properties
+                .values()
+                .map(|prop| prop.string.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+            Item::Renamed(item) => item.name.clone(),
+        }
+    }
+}
 
-                for hit in res.hits.hits {
-                    let span = hit.source;
-                    let t = DateTime::from_timestamp_micros(span.start_time)
-                        .ok_or(Error::TimestampOutOfBounds(span.start_time))?;
+impl Relation {
+    pub(crate) fn relation_type(&self) -> &str {
+        match self {
+            Relation::ServiceInvokes { .. } => "jaeger/service_invokes",
+            Relation::OperationInvokes { .. } => "jaeger/operation_invokes",
+            Relation::ServiceLinks { .. } => "jaeger/service_links",
+            Relation::OperationLinks { .. } => "jaeger/operation_links",
+            Relation::ServiceProduces { .. } => "jaeger/service_produces",
+            Relation::ServiceCallsExternal { .. } => "jaeger/service_calls_external",
+            Relation::Custom(rel) => &rel.relation_type,
+            Relation::Renamed(rel) => &rel.relation_type,
+        }
+    }
 
-                    /* Find service key.*/
+    pub(crate) fn endpoints(&self) -> (Uuid, Uuid) {
+        match self {
+            Relation::ServiceInvokes { source, target, .. }
+            | Relation::OperationInvokes { source, target, .. }
+            | Relation::ServiceLinks { source, target, .. }
+            | Relation::OperationLinks { source, target, .. }
+            | Relation::ServiceProduces { source, target, .. }
+            | Relation::ServiceCallsExternal { source, target, .. } => (*source, *target),
+            Relation::Custom(rel) => (rel.source, rel.target),
+            Relation::Renamed(rel) => (rel.source, rel.target),
+        }
+    }
+}
 
-                    let service_key = ServiceKey {
-                        namespace: span
-                            .process
-                            .tags
-                            .iter()
-                            .filter(|tag| &tag.key == "service.namespace")
-                            .find_map(|tag| match &tag.value {
-                                TagValue::String(s) => Some(ServiceNamespace(s.to_string())),
-                                _ => None,
-                            }),
-                        name: span.process.service_name.clone(),
-                        instance_id: span
-                            .process
-                            .tags
-                            .iter()
-                            .filter(|tag| (&tag.key == "service.instance.id"))
-                            .find_map(|tag| match &tag.value {
-                                TagValue::String(s) => Some(ServiceInstanceId(s.to_string())),
-                                _ => None,
-                            }),
-                    };
+impl StalenessProps {
+    fn new(last_seen: DateTime<Utc>, threshold: DateTime<Utc>) -> Option<Self> {
+        (last_seen < threshold).then(|| Self {
+            stale: BooleanProperty::new(true),
+            last_seen: TimeProperty::new(last_seen),
+        })
+    }
+}
 
-                    let svc_meta = ServiceMeta::from_span(&span);
+impl InvokesProps {
+    fn new(rel: &RelationState, now: DateTime<Utc>, retention: TimeDelta) -> Self {
+        Self {
+            confidence: FloatProperty::new(rel.confidence(now, retention)),
+            http_status: (!rel.http_status.is_empty())
+                .then(|| HttpStatusProps::new(&rel.http_status)),
+            observations_24h: IntegerProperty::new(rel.observations(now - DAY, now)),
+            observations_previous_24h: IntegerProperty::new(
+                rel.observations(now - DAY - DAY, now - DAY),
+            ),
+            active_hours: IntegerProperty::new(rel.hourly.len() as u64),
+        }
+    }
+}
 
-                    /* Insert into trace and span map. */
+impl HttpStatusProps {
+    fn new(counts: &HttpStatusCounts) -> Self {
+        Self {
+            informational: IntegerProperty::new(counts.informational),
+            success: IntegerProperty::new(counts.success),
+            redirection: IntegerProperty::new(counts.redirection),
+            client_error: IntegerProperty::new(counts.client_error),
+            server_error: IntegerProperty::new(counts.server_error),
+        }
+    }
+}
 
-                    let trace_info = self
-                        .state
-                        .traces
-                        .entry(span.trace_id.clone())
-                        .and_modify(|info| info.last_seen = t)
-                        .or_insert_with(|| TraceInfo {
-                            last_seen: t,
-                            spans: BTreeMap::new(),
-                        });
-
-                    let span_info = trace_info.spans.entry(span.span_id.clone()).or_default();
-                    span_info.key = Some(OperationKey {
-                        service_key: service_key.clone(),
-                        operation_name: span.operation_name.clone(),
-                    });
-
-                    /* Update services and operations.  */
-
-                    let svc_state = self
-                        .state
-                        .services
-                        .entry(service_key.clone())
-                        .and_modify(|svc| svc.meta = svc_meta.clone())
-                        .or_insert_with(|| ServiceState {
-                            id: Uuid::new_v4(),
-                            meta: svc_meta.clone(),
-                            relations: BTreeMap::new(),
-                            operations: BTreeMap::new(),
-                        });
+/// Connection to the Opensearch cluster holding the Jaeger spans.
+#[derive(Clone)]
+struct EsConnection {
+    client: Client,
+    url: Url,
+}
 
-                    let oper_state = svc_state
-                        .operations
-                        .entry(span.operation_name.clone())
-                        .and_modify(|state| state.last_seen = t)
-                        .or_insert_with(|| OperationState {
-                            id: Uuid::new_v4(),
-                            relations: BTreeMap::new(),
-                            last_seen: t,
-                        });
+impl Discovery {
+    /// Load state and configuration, and connect to Opensearch and the
+    /// configured sinks.
+    pub(crate) async fn new(args: &Args) -> Result<Self, Error> {
+        let mut discovery = Self::load(args).await?;
+        discovery.sinks = sink::build(&discovery.config.sinks, args, &discovery.renames)?;
+        for sink in &mut discovery.sinks {
+            sink.init().await?;
+        }
+        if args.background_push {
+            discovery.queue = Some(PushQueue::spawn(std::mem::take(&mut discovery.sinks)));
+        }
+        if args.validate {
+            discovery.schema = Some(PayloadSchema::load(args.schema.as_deref()).await?);
+        }
+        let mut client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .gzip(!args.es_no_gzip)
+            .pool_idle_timeout(Duration::from_secs(args.es_pool_idle_timeout))
+            .tcp_keepalive(args.es_tcp_keepalive.map(Duration::from_secs))
+            .add_root_certificate(load_cert(required(&args.es_ca, "--es-ca")?).await?)
+            .identity(
+                load_identity(
+                    required(&args.es_cert, "--es-cert")?,
+                    required(&args.es_key, "--es-key")?,
+                )
+                .await?,
+            )
+            .danger_accept_invalid_hostnames(true); // TODO: disable!
+        if let Some(max) = args.es_pool_max_idle {
+            client = client.pool_max_idle_per_host(max);
+        }
+        if args.es_http1 {
+            client = client.http1_only();
+        }
+        discovery.es = Some(EsConnection {
+            client: client.build().map_err(Error::Reqwest)?,
+            url: required(&args.es_url, "--es-url")?.clone(),
+        });
+        Ok(discovery)
+    }
 
-                    /* Update relations. */
+    /// Load state and configuration only, for offline use.
+    pub(crate) async fn load(args: &Args) -> Result<Self, Error> {
+        let mut store = StateStore::open(args)?;
+        let state = store.load().await?;
 
-                    let parent_of = std::mem::take(&mut span_info.parent_of);
+        let config = match &args.config {
+            Some(path) => load_config::<Config>(path).await?,
+            None => Config::default(),
+        };
 
-                    if let Some(r) = span
-                        .references
-                        .iter()
-                        .find(|r| r.ref_type == RefType::ChildOf)
-                    {
-                        let parent_trace = self
-                            .state
-                            .traces
-                            .entry(r.trace_id.clone())
-                            .and_modify(|info| info.last_seen = t)
-                            .or_insert_with(|| TraceInfo {
-                                last_seen: t,
-                                spans: BTreeMap::new(),
-                            });
-                        let parent_span = parent_trace.spans.entry(r.span_id.clone()).or_default();
-
-                        if let Some(parent_key) = &parent_span.key {
-                            if parent_key.service_key != service_key {
-                                svc_state
-                                    .relations
-                                    .entry(parent_key.service_key.clone())
-                                    .and_modify(|relation| relation.last_seen = t)
-                                    .or_insert_with(|| super::state::RelationState {
-                                        id: Uuid::new_v4(),
-                                        last_seen: t,
-                                    });
-                            }
-
-                            oper_state
-                                .relations
-                                .entry(parent_key.service_key.clone())
-                                .or_default()
-                                .entry(parent_key.operation_name.clone())
-                                .and_modify(|relation| relation.last_seen = t)
-                                .or_insert_with(|| super::state::RelationState {
-                                    id: Uuid::new_v4(),
-                                    last_seen: t,
-                                });
-                        } else {
-                            parent_span.parent_of.push(OperationKey {
-                                service_key: service_key.clone(),
-                                operation_name: span.operation_name.clone(),
-                            })
-                        }
-                    }
+        if args.min_batch_size > args.max_batch_size {
+            return Err(Error::BatchSizeBounds(
+                args.min_batch_size.get(),
+                args.max_batch_size.get(),
+            ));
+        }
 
-                    for child_key in parent_of {
-                        if child_key.service_key != service_key {
-                            if let Some(svc_state) =
-                                self.state.services.get_mut(&child_key.service_key)
-                            {
-                                svc_state
-                                    .relations
-                                    .entry(service_key.clone())
-                                    .and_modify(|relation| relation.last_seen = t)
-                                    .or_insert_with(|| super::state::RelationState {
-                                        id: Uuid::new_v4(),
-                                        last_seen: t,
-                                    });
-                            }
-                        }
+        /* The pre-flight aggregation finds services by their name
+         * in Jaeger. */
+        if args.preflight && config.service_key.name_tag.is_some() {
+            return Err(Error::Preflight("a service name tag"));
+        }
 
-                        if let Some(oper_state) = self
-                            .state
-                            .services
-                            .get_mut(&child_key.service_key)
-                            .and_then(|svc_state| {
-                                svc_state.operations.get_mut(&child_key.operation_name)
-                            })
-                        {
-                            oper_state
-                                .relations
-                                .entry(service_key.clone())
-                                .or_default()
-                                .entry(span.operation_name.clone())
-                                .and_modify(|relation| relation.last_seen = t)
-                                .or_insert_with(|| super::state::RelationState {
-                                    id: Uuid::new_v4(),
-                                    last_seen: t,
-                                });
-                        }
-                    }
-                }
+        /* Doc values cover the span fields needed to discover
+         * operations, but no tags or process metadata. */
+        if args.docvalue_fields {
+            let conflict = if args.granularity != Granularity::Operation {
+                Some("service granularity")
+            } else if args.function_items {
+                Some("--function-items")
+            } else if !config.rules.is_empty() {
+                Some("custom rules")
+            } else if config.service_key.name_tag.is_some() || !config.service_key.tags.is_empty() {
+                Some("service key tags")
+            } else if config.service_key.namespace || config.service_key.instance_id {
+                /* Both are process tags: keys would differ from those
+                 * of the full spans. */
+                Some("service namespaces or instance ids in the service key")
+            } else {
+                None
+            };
+            if let Some(conflict) = conflict {
+                return Err(Error::DocvalueFields(conflict));
+            }
+        }
 
-                /* Cleanup trace and span map. */
+        let limits = TraceLimits {
+            traces: args.max_traces,
+            spans: args.max_spans,
+            bytes: args.max_trace_bytes,
+        };
+        let lru = TraceLru::new(limits, &state.traces);
+        let mut discovery = Self {
+            store,
+            state,
+            granularity: args.granularity,
+            function_items: args.function_items,
+            merge_instances: args.merge_instances,
+            instance_window: TimeDelta::try_seconds(args.instance_window)
+                .ok_or(Error::InvalidDuration(args.instance_window))?,
+            stale_grace: TimeDelta::try_seconds(args.stale_grace)
+                .ok_or(Error::InvalidDuration(args.stale_grace))?,
+            lru,
+            trace_retention: TimeDelta::try_seconds(args.trace_retention)
+                .ok_or(Error::InvalidDuration(args.trace_retention))?,
+            trace_max_age: args
+                .trace_max_age
+                .map(|secs| TimeDelta::try_seconds(secs).ok_or(Error::InvalidDuration(secs)))
+                .transpose()?,
+            cycle_start: Utc::now(),
+            run_history: args.run_history,
+            status_dir: args.state.clone().filter(|_| !args.no_state),
+            one_off_retention: args
+                .one_off_retention
+                .map(|secs| TimeDelta::try_seconds(secs).ok_or(Error::InvalidDuration(secs)))
+                .transpose()?,
+            deterministic_ids: args.deterministic_ids,
+            #[cfg(feature = "sled")]
+            spill: args
+                .spill_traces
+                .map(|limit| {
+                    let dir = required(&args.state, "--state")?;
+                    TraceSpill::open(dir.join("traces.spill"), limit)
+                })
+                .transpose()?,
+            query_slices: args.query_slices,
+            batch_size: BatchSize::new(
+                args.batch_size.get(),
+                args.min_batch_size.get(),
+                args.max_batch_size.get(),
+            ),
+            docvalue_fields: args.docvalue_fields,
+            target_indices: args.target_indices,
+            preflight: args.preflight,
+            preflight_scans: HashMap::new(),
+            span_log: None,
+            checkpoint_batches: args.checkpoint_batches,
+            fold_threads: args.fold_threads,
+            max_cycle_spans: args.max_cycle_spans,
+            interim_push: args
+                .interim_push
+                .map(|secs| TimeDelta::try_seconds(secs).ok_or(Error::InvalidDuration(secs)))
+                .transpose()?,
+            semconv: Semconv::new(&config.semconv),
+            renames: Arc::new(Renames::new(&config)),
+            structured_diff: args.structured_diff,
+            discovery_item: args.discovery_item,
+            roots: args.roots,
+            quiescence: args
+                .quiescence
+                .map(|secs| TimeDelta::try_seconds(secs).ok_or(Error::InvalidDuration(secs)))
+                .transpose()?,
+            previous: None,
+            keep_latest: args.admin_token.is_some(),
+            latest: None,
+            graph: Graph::default(),
+            changes: Changes::default(),
+            config,
+            sinks: Vec::new(),
+            queue: None,
+            overlap_push: args.overlap_push,
+            pushing: None,
+            schema: None,
+            es: None,
+        };
+        if args.span_log {
+            let dir = required(&args.state, "--state")?;
+            let (log, batches) = SpanLog::open(dir.join("spans.wal"))?;
+            discovery.replay(batches)?;
+            discovery.span_log = Some(log);
+        }
+        Ok(discovery)
+    }
 
-                if let Some(last) = self.state.last_span {
-                    let trace_threshold = last - TimeDelta::try_seconds(300).unwrap();
-                    self.state
-                        .traces
-                        .retain(|_, info| info.last_seen >= trace_threshold);
+    /// Run a discovery cycle, recording its result in the run history
+    /// and saving the state.
+    pub(crate) async fn discover(&mut self) -> Result<(), Error> {
+        let mut record = RunRecord {
+            time: Utc::now(),
+            spans: 0,
+            items: None,
+            relations: None,
+            error: None,
+        };
+        let span = tracing::info_span!(
+            "cycle",
+            id = %Uuid::new_v4(),
+            source = self
+                .es
+                .as_ref()
+                .and_then(|es| es.url.host_str())
+                .unwrap_or_default(),
+        );
+        async {
+            let mut summary = CycleSummary::new(&self.state);
+            let res = self.cycle(&mut record, &mut summary).await;
+            record.error = res.as_ref().err().map(ToString::to_string);
+            let error = record.error.clone();
+            let mut status = Status::new(&record, &self.state);
+            self.state.record_run(record, self.run_history);
+            /* The state of a failed cycle is not saved, keeping the span
+             * log and the positions saved before the failed batch; the run
+             * is recorded with the next successful save. */
+            let saved = if res.is_ok() {
+                let start = Instant::now();
+                let saved = self.save().await;
+                summary.phase(Phase::Save, start);
+                saved
+            } else {
+                Ok(())
+            };
+            summary.finish(&self.state, self.store.size(), error.as_deref());
+            if let Some(dir) = &self.status_dir {
+                status.saved(&saved);
+                if let Err(e) = status.write(dir) {
+                    log::warn!("failed to write status: {e}");
                 }
             }
+            saved?;
+            res
+        }
+        .instrument(span)
+        .await
+    }
 
-            Ok(())
+    async fn cycle(
+        &mut self,
+        record: &mut RunRecord,
+        summary: &mut CycleSummary,
+    ) -> Result<(), Error> {
+        log::info!("running discovery");
+
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.flush().await {
+                log::warn!("failed to flush {}: {e}", sink.name());
+            }
         }
-        .await;
 
-        match res {
-            Ok(()) => {
-                pit.delete().await.unwrap_or_else(|e| log::warn!("{e}"));
-                println!("Processed {n} spans");
+        let now = record.time;
+        self.cycle_start = now;
+        let retention = RETENTION;
+        let oper_threshold = now - retention;
+        let removal_threshold = oper_threshold - self.stale_grace;
+
+        /* The connection is cloned (sharing its connection pool) so that the
+         * pit does not borrow self during span processing. */
+        let es = self.es.clone().ok_or(Error::MissingArgument("--es-url"))?;
+        let from = oper_threshold.timestamp_micros();
+        let positions = self.slice_positions(self.query_slices.get() as usize);
+        let start = fetch::window_start(from, &positions);
+        let indices = match self.target_indices {
+            Some(days) => {
+                let start = DateTime::from_timestamp_micros(start)
+                    .ok_or(Error::TimestampOutOfBounds(start))?;
+                indices::resolve(&es.client, &es.url, start, days).await
             }
-            Err(e) => {
-                pit.delete().await.unwrap_or_else(|e| log::warn!("{e}"));
-                return Err(e);
+            None => indices::SPAN_INDEX_PATTERN.to_string(),
+        };
+        let phase = Instant::now();
+        let services = match self.preflight {
+            true => self.preflight(&es, &indices, start, now).await,
+            false => None,
+        };
+        summary.phase(Phase::Preflight, phase);
+
+        let n = match &services {
+            Some(services) if services.is_empty() => {
+                log::info!("no new services or operations; skipping the span scan");
+                0
+            }
+            _ => {
+                let window = Window {
+                    indices,
+                    from,
+                    services: services.clone(),
+                };
+                let phase = Instant::now();
+                let res = self.scan(&es, window, positions, record, summary).await;
+                summary.phase(Phase::Scan, phase);
+                res?
+            }
+        };
+        if let Some(services) = services {
+            for service in services {
+                self.preflight_scans.insert(service, now);
             }
         }
 
-        /* Cleanup services and operations. */
+        summary.spans = n as u64;
+        self.state.last_run = Some(RunInfo {
+            id: self
+                .state
+                .last_run
+                .as_ref()
+                .map_or_else(Uuid::new_v4, |run| run.id),
+            time: now,
+            spans: n as u64,
+            source: es.url.host_str().map(String::from),
+        });
 
-        self.state.services.retain(|_, svc_state| {
-            svc_state
-                .relations
-                .retain(|_, rel| rel.last_seen >= oper_threshold);
+        let phase = Instant::now();
+        self.expire_items(oper_threshold, removal_threshold);
+        summary.phase(Phase::Expire, phase);
+        #[cfg(feature = "jemalloc")]
+        heap::record_traces(&self.state);
+        log::debug!("{} names interned", intern::purge());
+
+        let phase = Instant::now();
+        let res = self.publish_graph(now, retention, record).await;
+        summary.phase(Phase::Publish, phase);
+        res
+    }
 
-            svc_state.operations.retain(|_, oper_state| {
-                oper_state.relations.retain(|_, svc_rels| {
-                    svc_rels.retain(|_, rel| rel.last_seen >= oper_threshold);
-                    !svc_rels.is_empty()
-                });
+    /// Aggregate the services and operations in the window starting
+    /// at `start` (in microseconds), and select the services whose
+    /// spans are to be scanned: those with unknown operations, their
+    /// callers and callees (whose spans are needed to relate the new
+    /// operations), and those not scanned within `PREFLIGHT_REFRESH`.
+    /// Returns `None` if all spans are to be scanned, which includes
+    /// the case of a new service, whose callers are not known.
+    async fn preflight(
+        &self,
+        es: &EsConnection,
+        indices: &str,
+        start: i64,
+        now: DateTime<Utc>,
+    ) -> Option<Vec<String>> {
+        let buckets = match preflight::aggregate(&es.client, &es.url, indices, start).await {
+            Ok(Some(buckets)) => buckets,
+            Ok(None) => {
+                log::info!("too many services to aggregate; scanning all spans");
+                return None;
+            }
+            Err(e) => {
+                log::warn!("pre-flight aggregation failed: {e}; scanning all spans");
+                return None;
+            }
+        };
+        let mut changed = BTreeSet::new();
+        let mut services = BTreeSet::new();
+        for bucket in buckets {
+            match self.is_known(&bucket) {
+                None => {
+                    log::info!("new service {}; scanning all spans", bucket.name);
+                    return None;
+                }
+                Some(false) => {
+                    changed.insert(bucket.name);
+                }
+                Some(true) if bucket.truncated => {
+                    changed.insert(bucket.name);
+                }
+                Some(true) => {
+                    if self
+                        .preflight_scans
+                        .get(&bucket.name)
+                        .is_none_or(|scanned| now - *scanned >= PREFLIGHT_REFRESH)
+                    {
+                        services.insert(bucket.name);
+                    }
+                }
+            }
+        }
+        services.extend(self.related_services(&changed));
+        services.extend(changed);
+        log::debug!("scanning the spans of {} service(s)", services.len());
+        Some(services.into_iter().collect())
+    }
 
-                oper_state.last_seen >= oper_threshold
-            });
+    /// Whether all operations of an aggregated service are known (at
+    /// operation granularity), or `None` if the service is not known.
+    fn is_known(&self, bucket: &ServiceBucket) -> Option<bool> {
+        let mut services = self
+            .state
+            .services
+            .iter()
+            .filter(|(key, _)| key.name.0.as_ref() == bucket.name)
+            .map(|(_, svc_state)| svc_state)
+            .peekable();
+        services.peek()?;
+        Some(
+            self.granularity == Granularity::Service
+                || bucket.operations.iter().all(|name| {
+                    let name = OperationName::new(name);
+                    services
+                        .clone()
+                        .any(|svc_state| svc_state.operations.contains_key(&name))
+                }),
+        )
+    }
+
+    /// The names of the services invoking or linking to, or invoked or
+    /// linked by, the services named in `names`.
+    fn related_services(&self, names: &BTreeSet<String>) -> BTreeSet<String> {
+        self.state
+            .services
+            .iter()
+            .flat_map(|(svc_key, svc_state)| {
+                let selected = names.contains(svc_key.name.0.as_ref());
+                svc_state
+                    .relations
+                    .keys()
+                    .chain(svc_state.links.keys())
+                    .filter_map(move |other| {
+                        if selected {
+                            Some(other.name.0.to_string())
+                        } else if names.contains(other.name.0.as_ref()) {
+                            Some(svc_key.name.0.to_string())
+                        } else {
+                            None
+                        }
+                    })
+            })
+            .collect()
+    }
+
+    /// Fetch the spans in `window` and fold them into the state,
+    /// returning the number of spans processed.
+    async fn scan(
+        &mut self,
+        es: &EsConnection,
+        window: Window,
+        positions: Vec<Option<QueryPosition>>,
+        record: &mut RunRecord,
+        summary: &mut CycleSummary,
+    ) -> Result<usize, Error> {
+        let (mut rounds, fetcher) = fetch::spawn(
+            es.client.clone(),
+            es.url.clone(),
+            window,
+            positions,
+            self.batch_size,
+            self.docvalue_fields,
+        );
 
-            !svc_state.operations.is_empty()
+        let mut n = 0;
+        let mut batches = 0;
+        let mut last_push = Utc::now();
+        let res = async {
+            while let Some(round) = rounds.recv().await {
+                let mut round = round?;
+                n += round.spans.borrow_dependent().len();
+                summary.fetched_bytes += round
+                    .spans
+                    .borrow_owner()
+                    .iter()
+                    .map(|page| page.size() as u64)
+                    .sum::<u64>();
+                batches += 1;
+                let slice_positions = Some(round.positions);
+                /* The batch is logged only once it was processed, so a
+                 * failed batch is fetched again rather than skipped. */
+                let logged = match (&self.span_log, round.watermark) {
+                    (Some(_), Some(position)) => Some(SpanLog::record(&Batch {
+                        position,
+                        slices: slice_positions.clone(),
+                        spans: round.spans.borrow_dependent().iter().collect(),
+                    })),
+                    _ => None,
+                };
+                tracing::info_span!("batch", number = batches).in_scope(|| {
+                    round.spans.with_dependent_mut(|_, spans| {
+                        self.process_batch(std::mem::take(spans), round.watermark)
+                    })
+                })?;
+                self.state.slices = slice_positions;
+                if let (Some(log), Some(logged)) = (&mut self.span_log, logged) {
+                    log.append(&logged)?;
+                }
+                if SUMMARY_REQUEST.take() {
+                    inspect::log_summary(&self.state);
+                }
+
+                if self
+                    .checkpoint_batches
+                    .is_some_and(|every| batches % every.get() == 0)
+                {
+                    log::info!("checkpointing state after {n} spans");
+                    self.save().await?;
+                }
+
+                if self
+                    .interim_push
+                    .is_some_and(|every| Utc::now() - last_push >= every)
+                {
+                    log::info!("publishing interim graph after {n} spans");
+                    if let Err(e) = self.publish_graph(record.time, RETENTION, record).await {
+                        log::warn!("failed to publish interim graph: {e}");
+                    }
+                    last_push = Utc::now();
+                }
+
+                if self.max_cycle_spans.is_some_and(|max| n >= max.get()) {
+                    log::info!("processed {n} spans; continuing in the next cycle");
+                    break;
+                }
+            }
+
+            Ok(())
+        }
+        .await;
+        record.spans = n as u64;
+
+        /* Stop fetching (on error or when the cycle is capped) and wait for the pit to be removed. */
+        drop(rounds);
+        if let Err(e) = fetcher.await {
+            log::warn!("span fetcher failed: {e}");
+        }
+
+        res.map(|()| n)
+    }
+
+    /// Bring the graph up to date with the state and publish it.
+    async fn publish_graph(
+        &mut self,
+        now: DateTime<Utc>,
+        retention: TimeDelta,
+        record: &mut RunRecord,
+    ) -> Result<(), Error> {
+        if self.deterministic_ids {
+            self.state.assign_ids();
+        }
+        self.update_graph(now, retention);
+        let world = std::mem::take(&mut self.graph.world);
+        let items = self.items(world);
+        let res = self.publish(&items, record).await;
+        self.graph.world = items.items;
+        res
+    }
+
+    /// Validate and publish the graph, with type and property names
+    /// mapped as configured.
+    async fn publish(&mut self, items: &Items, record: &mut RunRecord) -> Result<(), Error> {
+        let renamed;
+        let items = match self.renames.is_empty() {
+            true => items,
+            false => {
+                renamed = self.renames.apply(items.clone());
+                &renamed
+            }
+        };
+        log::info!(
+            "Found {} items, {} relations.",
+            items.items.items.len(),
+            items.items.relations.len()
+        );
+        record.items = Some(items.items.items.len());
+        record.relations = Some(items.items.relations.len());
+
+        /* Validate first, so a rejected payload is not taken as
+         * published. */
+        let violations = self
+            .schema
+            .as_ref()
+            .map_or(0, |schema| schema.validate(items));
+        if violations > 0 {
+            return Err(Error::InvalidPayload(violations));
+        }
+
+        let snapshot = Snapshot::new(&items.items);
+        if let Some(previous) = &self.previous {
+            let diff = GraphDiff::new(previous, &snapshot);
+            if !diff.is_empty() {
+                diff.log(self.structured_diff);
+            }
+        }
+        self.previous = Some(snapshot);
+        if self.keep_latest {
+            self.latest = Some(Arc::new(items.clone()));
+        }
+
+        let failed = match &self.queue {
+            Some(queue) => {
+                queue.push(self.state.topology(), items.clone());
+                0
+            }
+            None if self.overlap_push => {
+                let failed = self.finish_push().await;
+                let sinks = std::mem::take(&mut self.sinks);
+                self.pushing = Some(PushTask::spawn(sinks, self.state.topology(), items.clone()));
+                failed
+            }
+            None => self.push(items).await,
+        };
+        match failed {
+            0 => Ok(()),
+            n => Err(Error::SinkFailed(n)),
+        }
+    }
+
+    /// The payload most recently published, if kept for the admin api.
+    pub(crate) fn latest(&self) -> Option<Arc<Items>> {
+        self.latest.clone()
+    }
+
+    /// Build the payload to be published, with type and property names
+    /// mapped as configured.
+    pub(crate) fn payload(&self, now: DateTime<Utc>, retention: TimeDelta) -> Items {
+        self.renames.apply(self.build(now, retention))
+    }
+
+    /// The mapping of published type and property names.
+    pub(crate) fn renames(&self) -> &Arc<Renames> {
+        &self.renames
+    }
+
+    /// Build the items and relations to be published from the state.
+    pub(crate) fn build(&self, now: DateTime<Utc>, retention: TimeDelta) -> Items {
+        let mut graph = Graph::default();
+        self.build_graph(&mut graph, now, retention);
+        self.items(graph.world)
+    }
+
+    /// Bring the graph up to date with the changes to the state since
+    /// the previous cycle. The graph is built from scratch when items
+    /// were added or removed, and in the first cycle of every hour, to
+    /// refresh properties relative to the current time.
+    fn update_graph(&mut self, now: DateTime<Utc>, retention: TimeDelta) {
+        let changes = std::mem::take(&mut self.changes);
+        let hour = now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now);
+        let mut graph = std::mem::take(&mut self.graph);
+        if changes.structure || !graph.built_in(hour) {
+            graph = Graph::new(hour);
+            self.build_graph(&mut graph, now, retention);
+            log::debug!("rebuilt the graph");
+        } else {
+            for svc_key in &changes.services {
+                let part = Part::Service(svc_key.clone());
+                match self.state.services.get(svc_key) {
+                    Some(svc_state) => {
+                        let world = self.service_entries(svc_key, svc_state, now, retention);
+                        graph.replace(part, world.items, world.relations);
+                    }
+                    None => graph.remove(&part),
+                }
+            }
+            if changes.destinations {
+                graph.replace(Part::Destinations, self.destination_items(), []);
+            }
+            if changes.external_endpoints {
+                let world = self.external_endpoint_entries(now, retention);
+                graph.replace(Part::ExternalEndpoints, world.items, world.relations);
+            }
+            if changes.custom_items {
+                let world = self.custom_entries();
+                graph.replace(Part::CustomItems, world.items, world.relations);
+            }
+            graph.replace(Part::Discovery, self.discovery_item(), []);
+            log::debug!(
+                "updated the graph for {} changed services",
+                changes.services.len()
+            );
+        }
+        self.graph = graph;
+    }
+
+    /// Build all items and relations into an empty graph.
+    fn build_graph(&self, graph: &mut Graph, now: DateTime<Utc>, retention: TimeDelta) {
+        for (svc_key, svc_state) in &self.state.services {
+            let world = self.service_entries(svc_key, svc_state, now, retention);
+            graph.replace(Part::Service(svc_key.clone()), world.items, world.relations);
+        }
+        graph.replace(Part::Destinations, self.destination_items(), []);
+        let world = self.external_endpoint_entries(now, retention);
+        graph.replace(Part::ExternalEndpoints, world.items, world.relations);
+        let world = self.custom_entries();
+        graph.replace(Part::CustomItems, world.items, world.relations);
+        graph.replace(Part::Discovery, self.discovery_item(), []);
+    }
+
+    /// The items for a service and its operations, and the relations
+    /// targeting them.
+    fn service_entries(
+        &self,
+        svc_key: &ServiceKey,
+        svc_state: &ServiceState,
+        now: DateTime<Utc>,
+        retention: TimeDelta,
+    ) -> World {
+        let oper_threshold = now - retention;
+
+        let properties = Box::new(ServiceProps {
+            service_namespace: svc_key.namespace.clone().map(StringProperty::new),
+            service_name: StringProperty::new(svc_key.name.clone()),
+            service_instance_id: svc_key.instance_id.clone().map(StringProperty::new),
+            instance_count: self
+                .merge_instances
+                .then(|| IntegerProperty::new(svc_state.instances.len() as u64)),
+            meta: svc_state.meta.clone(),
+            staleness: svc_state
+                .last_activity()
+                .and_then(|t| StalenessProps::new(t, oper_threshold)),
         });
+        let items = std::iter::once((
+            svc_state.id,
+            if self.function_items && svc_state.meta.is_function() {
+                Item::Function { properties }
+            } else {
+                Item::Service { properties }
+            },
+        ))
+        .chain(svc_state.operations.iter().map(|(oper_name, oper_state)| {
+            (
+                oper_state.id,
+                Item::Operation {
+                    parent: svc_state.id,
+                    properties: Box::new(OperationProps {
+                        operation_name: StringProperty::new(oper_name.clone()),
+                        span_kind: oper_state
+                            .dominant_span_kind()
+                            .map(|kind| StringProperty::new(kind.to_string())),
+                        staleness: StalenessProps::new(oper_state.last_seen, oper_threshold),
+                    }),
+                },
+            )
+        }))
+        .collect();
 
-        /* Build item and relation map. */
+        let relations = svc_state
+            .relations
+            .iter()
+            .filter_map(|(parent_svc, rel)| {
+                Some((
+                    rel.id,
+                    Relation::ServiceInvokes {
+                        source: self.state.services.get(parent_svc)?.id,
+                        target: svc_state.id,
+                        properties: InvokesProps::new(rel, now, retention),
+                    },
+                ))
+            })
+            .chain(svc_state.operations.values().flat_map(|oper_state| {
+                oper_state
+                    .relations
+                    .iter()
+                    .flat_map(|(parent_svc, oper_rels)| {
+                        oper_rels.iter().filter_map(|(parent_oper, rel)| {
+                            Some((
+                                rel.id,
+                                Relation::OperationInvokes {
+                                    source: self
+                                        .state
+                                        .services
+                                        .get(parent_svc)?
+                                        .operations
+                                        .get(parent_oper)?
+                                        .id,
+                                    target: oper_state.id,
+                                    properties: InvokesProps::new(rel, now, retention),
+                                },
+                            ))
+                        })
+                    })
+            }))
+            .chain(svc_state.links.iter().filter_map(|(linked_svc, rel)| {
+                Some((
+                    rel.id,
+                    Relation::ServiceLinks {
+                        source: self.state.services.get(linked_svc)?.id,
+                        target: svc_state.id,
+                        properties: InvokesProps::new(rel, now, retention),
+                    },
+                ))
+            }))
+            .chain(svc_state.operations.values().flat_map(|oper_state| {
+                oper_state.links.iter().flat_map(|(linked_svc, oper_rels)| {
+                    oper_rels.iter().filter_map(|(linked_oper, rel)| {
+                        Some((
+                            rel.id,
+                            Relation::OperationLinks {
+                                source: self
+                                    .state
+                                    .services
+                                    .get(linked_svc)?
+                                    .operations
+                                    .get(linked_oper)?
+                                    .id,
+                                target: oper_state.id,
+                                properties: InvokesProps::new(rel, now, retention),
+                            },
+                        ))
+                    })
+                })
+            }))
+            .chain(
+                svc_state
+                    .produces
+                    .iter()
+                    .filter_map(|(dest_name, produces)| {
+                        Some((
+                            produces.id,
+                            Relation::ServiceProduces {
+                                source: svc_state.id,
+                                target: self.state.destinations.get(dest_name)?.id,
+                                properties: ProducesProps {
+                                    consumer_observed: BooleanProperty::new(
+                                        produces.consumer_last_seen.is_some(),
+                                    ),
+                                },
+                            },
+                        ))
+                    }),
+            )
+            .collect();
+
+        World { items, relations }
+    }
+
+    fn destination_items(&self) -> Vec<(Uuid, Item)> {
+        self.state
+            .destinations
+            .iter()
+            .map(|(dest_name, dest)| {
+                (
+                    dest.id,
+                    Item::MessagingDestination {
+                        properties: Box::new(DestinationProps {
+                            destination_name: StringProperty::new(dest_name.clone()),
+                            messaging_system: dest.system.clone().map(StringProperty::new),
+                        }),
+                    },
+                )
+            })
+            .collect()
+    }
 
+    /// The external endpoints, and the relations from the services
+    /// calling them.
+    fn external_endpoint_entries(&self, now: DateTime<Utc>, retention: TimeDelta) -> World {
         let items = self
             .state
-            .services
+            .external_endpoints
             .iter()
-            .map(|(svc_key, svc_state)| {
+            .map(|(host, endpoint)| {
                 (
-                    svc_state.id,
-                    Item::Service {
-                        properties: Box::new(ServiceProps {
-                            service_namespace: svc_key.namespace.clone().map(StringProperty::new),
-                            service_name: StringProperty::new(svc_key.name.clone()),
-                            service_instance_id: svc_key
-                                .instance_id
-                                .clone()
-                                .map(StringProperty::new),
-                            meta: svc_state.meta.clone(),
+                    endpoint.id,
+                    Item::ExternalEndpoint {
+                        properties: Box::new(ExternalEndpointProps {
+                            host: StringProperty::new(host.clone()),
+                            paths: (!endpoint.paths.is_empty()).then(|| {
+                                StringProperty::new(
+                                    endpoint
+                                        .paths
+                                        .iter()
+                                        .map(String::as_str)
+                                        .collect::<Vec<_>>()
+                                        .join("\n"),
+                                )
+                            }),
                         }),
                     },
                 )
             })
-            .chain(self.state.services.values().flat_map(|svc_state| {
-                svc_state.operations.iter().map(|(oper_name, oper_state)| {
-                    (
-                        oper_state.id,
-                        Item::Operation {
-                            parent: svc_state.id,
-                            properties: Box::new(OperationProps {
-                                operation_name: StringProperty::new(oper_name.clone()),
-                            }),
+            .collect();
+        let relations = self
+            .state
+            .external_endpoints
+            .values()
+            .flat_map(|endpoint| {
+                endpoint.callers.iter().filter_map(|(svc_key, rel)| {
+                    Some((
+                        rel.id,
+                        Relation::ServiceCallsExternal {
+                            source: self.state.services.get(svc_key)?.id,
+                            target: endpoint.id,
+                            properties: InvokesProps::new(rel, now, retention),
                         },
-                    )
+                    ))
                 })
-            }))
-            .collect::<BTreeMap<_, _>>();
+            })
+            .collect();
+        World { items, relations }
+    }
 
+    /// The custom items derived by rules, and the relations from the
+    /// services they were derived from.
+    fn custom_entries(&self) -> World {
+        let items = self
+            .state
+            .custom_items
+            .values()
+            .map(|item| {
+                (
+                    item.id,
+                    Item::Custom(CustomItem {
+                        item_type: item.item_type.clone(),
+                        properties: custom_properties(&item.properties),
+                    }),
+                )
+            })
+            .collect();
         let relations = self
             .state
-            .services
+            .custom_items
             .values()
-            .flat_map(|svc_state| {
-                svc_state.relations.iter().filter_map(|(parent_svc, rel)| {
+            .flat_map(|item| {
+                item.relations.iter().filter_map(|(svc_key, rel)| {
                     Some((
                         rel.id,
-                        Relation::ServiceInvokes {
-                            source: self.state.services.get(parent_svc)?.id,
-                            target: svc_state.id,
-                            properties: InvokesProps {},
-                        },
+                        Relation::Custom(CustomRelation {
+                            relation_type: rel.relation_type.clone(),
+                            source: self.state.services.get(svc_key)?.id,
+                            target: item.id,
+                            properties: custom_properties(&rel.properties),
+                        }),
                     ))
                 })
             })
-            .chain(self.state.services.values().flat_map(|svc_state| {
-                svc_state.operations.values().flat_map(|oper_state| {
-                    oper_state
-                        .relations
-                        .iter()
-                        .flat_map(|(parent_svc, oper_rels)| {
-                            oper_rels.iter().filter_map(|(parent_oper, rel)| {
-                                Some((
-                                    rel.id,
-                                    Relation::OperationInvokes {
-                                        source: self
-                                            .state
-                                            .services
-                                            .get(parent_svc)?
-                                            .operations
-                                            .get(parent_oper)?
-                                            .id,
-                                        target: oper_state.id,
-                                        properties: InvokesProps {},
-                                    },
-                                ))
-                            })
-                        })
-                })
-            }))
-            .collect::<BTreeMap<_, _>>();
-
-        // let items = items
-        //     .into_iter()
-        //     .filter(|(_, item)| matches!(item, Item::Service { .. }))
-        //     .collect::<BTreeMap<_, _>>();
-
-        // let relations = relations
-        //     .into_iter()
-        //     .filter(|(_, rel)| match rel {
-        //         Relation::ServiceInvokes { source, target, .. } => {
-        //             items.contains_key(source) && items.contains_key(target)
-        //         }
-        //         Relation::OperationInvokes { .. } => false,
-        //     })
-        //     .collect();
+            .collect();
+        World { items, relations }
+    }
 
-        log::info!(
-            "Found {} items, {} relations.",
-            items.len(),
-            relations.len()
-        );
+    fn discovery_item(&self) -> Option<(Uuid, Item)> {
+        let run = self
+            .state
+            .last_run
+            .as_ref()
+            .filter(|_| self.discovery_item)?;
+        Some((
+            run.id,
+            Item::Discovery {
+                properties: Box::new(DiscoveryProps {
+                    last_run: TimeProperty::new(run.time),
+                    spans_processed: IntegerProperty::new(run.spans),
+                    source_cluster: run.source.clone().map(StringProperty::new),
+                    version: StringProperty::new(env!("CARGO_PKG_VERSION").to_string()),
+                }),
+            },
+        ))
+    }
 
-        let items = Items {
+    /// The payload for a graph: the world, with its domain.
+    fn items(&self, world: World) -> Items {
+        Items {
             domain: Domain {
-                // roots: Some(
-                //     self.state
-                //         .services
-                //         .values()
-                //         .map(|svc_state| svc_state.id)
-                //         .collect(),
-                // ),
-                roots: None, /* all jaeger objects */
+                roots: match self.roots {
+                    Roots::None => None,
+                    Roots::Services => Some(services(&world)),
+                    Roots::EntryServices => Some(entry_services(&world)),
+                },
                 types: TypeSet {
-                    items: BTreeSet::from_iter([
+                    items: [
                         String::from("jaeger/service"),
+                        String::from("jaeger/function"),
                         String::from("jaeger/operation"),
-                    ]),
-                    relations: BTreeSet::from_iter([
+                        String::from("jaeger/messaging_destination"),
+                        String::from("jaeger/external_endpoint"),
+                        String::from("jaeger/discovery"),
+                    ]
+                    .into_iter()
+                    .chain(
+                        self.config
+                            .rules
+                            .iter()
+                            .map(|rule| rule.item.item_type.clone()),
+                    )
+                    .collect(),
+                    relations: [
                         String::from("jaeger/service_invokes"),
                         String::from("jaeger/operation_invokes"),
-                    ]),
+                        String::from("jaeger/service_links"),
+                        String::from("jaeger/operation_links"),
+                        String::from("jaeger/service_produces"),
+                        String::from("jaeger/service_calls_external"),
+                    ]
+                    .into_iter()
+                    .chain(
+                        self.config
+                            .rules
+                            .iter()
+                            .filter_map(|rule| Some(rule.relation.as_ref()?.relation_type.clone())),
+                    )
+                    .collect(),
                 },
             },
-            items: World { items, relations },
+            items: world,
+        }
+    }
+
+    /// Wait for the graph queued for background publishing, or the
+    /// push still running, if any.
+    pub(crate) async fn shutdown(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.close().await;
+        }
+        self.finish_push().await;
+    }
+
+    /// Wait for the push of the previous graph, if still running, and
+    /// take back the sinks. Returns the number of sinks that failed.
+    async fn finish_push(&mut self) -> usize {
+        let Some(task) = self.pushing.take() else {
+            return 0;
         };
+        let (sinks, failed) = task.finish().await;
+        self.sinks = sinks;
+        failed
+    }
 
-        let res = self
-            .rg_client
-            .put(self.rg_url.join("items")?)
-            .json(&items)
-            .send()
-            .await?;
+    /// Publish the graph to all sinks, returning the number of sinks
+    /// that failed.
+    async fn push(&mut self, items: &Items) -> usize {
+        let mut failed = 0;
+        for sink in &mut self.sinks {
+            let span = tracing::info_span!("push", sink = sink.name());
+            if let Err(e) = sink.push(&self.state, items).instrument(span).await {
+                log::warn!("failed to push to {}: {e}", sink.name());
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+impl Discovery {
+    /// Prune expired traces and items from the state, as the cleanup
+    /// at the end of a cycle would, and rewrite it.
+    pub(crate) async fn compact(&mut self) -> Result<(), Error> {
+        let before = self.state.counts();
+        let oper_threshold = Utc::now() - RETENTION;
+        self.expire_traces()?;
+        self.evict_traces();
+        self.expire_items(oper_threshold, oper_threshold - self.stale_grace);
+        let duplicates = self.state.dedup();
+        self.save().await?;
+
+        let after = self.state.counts();
+        println!("traces: {} -> {}", before.traces, after.traces);
+        println!("services: {} -> {}", before.services, after.services);
+        println!("operations: {} -> {}", before.operations, after.operations);
+        println!("relations: {} -> {}", before.relations, after.relations);
+        println!("duplicate pending relations removed: {duplicates}");
+        Ok(())
+    }
 
-        if let Err(err) = res.error_for_status_ref() {
-            let msg = res.text().await?;
-            return Err(Error::RelationGraph(err, msg));
+    /// Merge states read from files into the state and save it,
+    /// optionally pushing the merged graph to the configured sinks.
+    pub(crate) async fn merge(
+        &mut self,
+        args: &Args,
+        inputs: &[PathBuf],
+        push: bool,
+    ) -> Result<(), Error> {
+        for input in inputs {
+            let data = tokio::fs::read(input)
+                .await
+                .map_err(|e| Error::ReadFile(input.clone(), e))?;
+            let state = StateFormat::decode(&data, input)?;
+            log::info!(
+                "merging {} services from {}",
+                state.services.len(),
+                input.display()
+            );
+            self.state.merge(state);
         }
+        if self.deterministic_ids {
+            self.state.assign_ids();
+        }
+        self.save().await?;
+
+        if !push {
+            return Ok(());
+        }
+        self.sinks = sink::build(&self.config.sinks, args, &self.renames)?;
+        for sink in &mut self.sinks {
+            sink.init().await?;
+        }
+        let items = self.payload(Utc::now(), RETENTION);
+        match self.push(&items).await {
+            0 => Ok(()),
+            n => Err(Error::SinkFailed(n)),
+        }
+    }
+
+    /// Fold a batch of spans into the state, `position` being the
+    /// `startTime` of the last span in microseconds.
+    fn process_batch(&mut self, spans: Vec<Span>, position: Option<i64>) -> Result<(), Error> {
+        let last = position
+            .map(|last| {
+                DateTime::from_timestamp_micros(last).ok_or(Error::TimestampOutOfBounds(last))
+            })
+            .transpose()?;
+
+        match self.fold_threads {
+            Some(threads) if self.quiescence.is_none() => {
+                self.fold_parallel(spans, threads.get())?
+            }
+            _ => {
+                for mut span in spans {
+                    self.semconv.apply(&mut span);
+                    match self.quiescence {
+                        Some(_) => self.buffer_span(span)?,
+                        None => self.process_span(span)?,
+                    }
+                }
+            }
+        }
+
+        /* The position only advances once the spans were folded. */
+        self.state.last_span = self.state.last_span.max(last);
+
+        /* Process buffered traces that have been idle long enough. */
+
+        if let (Some(quiescence), Some(last)) = (self.quiescence, self.state.last_span) {
+            let threshold = last - quiescence;
+            let mut idle = Vec::new();
+            self.state.buffered.retain(|_, trace| {
+                let keep = trace.last_seen >= threshold;
+                if !keep {
+                    idle.push(std::mem::take(&mut trace.spans));
+                }
+                keep
+            });
+            for spans in idle {
+                for span in parents_first(spans) {
+                    self.process_span(span)?;
+                }
+            }
+        }
+
+        self.expire_traces()?;
+        self.evict_traces();
+        #[cfg(feature = "sled")]
+        if let Some(spill) = &mut self.spill {
+            let spilled = spill.spill(&mut self.state.traces)?;
+            if let Some(lru) = &mut self.lru {
+                spilled.iter().for_each(|id| lru.remove(id));
+            }
+        }
+        Ok(())
+    }
 
-        save_json(&self.state_path, &self.state).await?;
+    /// Re-apply the batches in the span log that were processed after
+    /// the state was last saved.
+    fn replay(&mut self, batches: Vec<Batch<Span>>) -> Result<(), Error> {
+        let saved = self.state.last_span.map(|t| t.timestamp_micros());
+        let batches = batches
+            .into_iter()
+            .filter(|batch| match (&batch.slices, &self.state.slices) {
+                (Some(positions), Some(saved)) => positions.iter().zip(saved).any(|(p, s)| p > s),
+                _ => saved.is_none_or(|saved| batch.position > saved),
+            })
+            .collect::<Vec<_>>();
+        if !batches.is_empty() {
+            log::info!("replaying {} batches from the span log", batches.len());
+        }
+        for batch in batches {
+            self.process_batch(batch.spans, Some(batch.position))?;
+            if batch.slices.is_some() {
+                self.state.slices = batch.slices;
+            }
+        }
+        Ok(())
+    }
+
+    /// Positions to resume the slices of the span query from. When the
+    /// number of slices changed, all slices resume from the position
+    /// of the slice that was furthest behind.
+    fn slice_positions(&self, slices: usize) -> Vec<Option<QueryPosition>> {
+        match &self.state.slices {
+            Some(positions) if positions.len() == slices => positions.clone(),
+            Some(positions) => vec![positions.iter().min().cloned().flatten(); slices],
+            None => vec![
+                self.state
+                    .last_span
+                    .map(|t| QueryPosition::after(t.timestamp_micros()));
+                slices
+            ],
+        }
+    }
+
+    /// Save the state, emptying the span log.
+    async fn save(&mut self) -> Result<(), Error> {
+        self.store.save(&self.state).await?;
+        if let Some(log) = &mut self.span_log {
+            log.truncate()?;
+        }
         Ok(())
     }
+
+    /// Remove traces not seen for a while from the trace and span
+    /// map: traces without spans in the trace retention period before
+    /// the last span, and traces not updated within the wall-clock max
+    /// age.
+    fn expire_traces(&mut self) -> Result<(), Error> {
+        let trace_threshold = self.state.last_span.map(|last| last - self.trace_retention);
+        let wall_threshold = self.trace_max_age.map(|age| Utc::now() - age);
+        let touched = self.trace_max_age.map(|_| self.cycle_start);
+        let expired = |last_seen: DateTime<Utc>, touched: Option<DateTime<Utc>>| {
+            trace_threshold.is_some_and(|t| last_seen < t)
+                || wall_threshold.zip(touched).is_some_and(|(w, t)| t < w)
+        };
+
+        let expired_ids = self
+            .state
+            .traces
+            .iter_mut()
+            .filter_map(|(id, info)| {
+                /* Traces from state written without --trace-max-age
+                 * expire by wall-clock time from now on. */
+                info.touched = info.touched.or(touched);
+                expired(info.last_seen, info.touched).then(|| id.clone())
+            })
+            .collect::<Vec<_>>();
+        if let Some(lru) = &mut self.lru {
+            expired_ids.iter().for_each(|id| lru.remove(id));
+        }
+        let mut expired_traces = expired_ids
+            .iter()
+            .filter_map(|id| self.state.traces.remove(id))
+            .collect::<Vec<_>>();
+        #[cfg(feature = "sled")]
+        if let Some(spill) = &mut self.spill {
+            expired_traces.extend(spill.expire(|s| expired(s.last_seen, s.touched))?);
+        }
+
+        self.harvest_external_calls(&mut expired_traces);
+        Ok(())
+    }
+
+    /// Register the calls in traces removed from the trace map for
+    /// good: client calls without a server span in the mesh are
+    /// considered external.
+    fn harvest_external_calls(&mut self, traces: &mut [TraceInfo]) {
+        for info in traces {
+            let external = info
+                .spans
+                .values_mut()
+                .filter(|span_info| !span_info.has_children)
+                .filter_map(|span_info| span_info.external.take())
+                .collect::<Vec<_>>();
+            if !external.is_empty() {
+                self.changes.external_endpoints = true;
+            }
+            external
+                .into_iter()
+                .for_each(|call| add_external_call(&mut self.state, call));
+        }
+    }
+
+    /// Remove services, operations and relations no longer seen.
+    fn expire_items(&mut self, oper_threshold: DateTime<Utc>, removal_threshold: DateTime<Utc>) {
+        let instance_threshold = self.state.last_span.map(|t| t - self.instance_window);
+        let one_off_threshold = self.one_off_retention.map(|r| Utc::now() - r);
+        let keep = |rel: &mut RelationState| {
+            rel.expire(oper_threshold)
+                && !one_off_threshold.is_some_and(|t| rel.is_one_off() && rel.last_seen < t)
+        };
+
+        let changes = &mut self.changes;
+        self.state.services.retain(|svc_key, svc_state| {
+            let before = entry_counts(svc_state);
+            match instance_threshold {
+                Some(threshold) if self.merge_instances => {
+                    svc_state.instances.retain(|_, seen| *seen >= threshold)
+                }
+                _ => svc_state.instances.clear(),
+            }
+            svc_state.relations.retain(|_, rel| keep(rel));
+            svc_state.links.retain(|_, rel| keep(rel));
+            svc_state.produces.retain(|_, produces| {
+                if produces
+                    .consumer_last_seen
+                    .is_some_and(|t| t < oper_threshold)
+                {
+                    produces.consumer_last_seen = None;
+                }
+                produces.last_seen >= oper_threshold
+            });
+
+            if self.granularity == Granularity::Service {
+                svc_state.operations.clear();
+            }
+
+            svc_state.operations.retain(|_, oper_state| {
+                oper_state.relations.retain(|_, svc_rels| {
+                    svc_rels.retain(|_, rel| keep(rel));
+                    !svc_rels.is_empty()
+                });
+                oper_state.links.retain(|_, svc_rels| {
+                    svc_rels.retain(|_, rel| keep(rel));
+                    !svc_rels.is_empty()
+                });
+
+                oper_state.last_seen >= removal_threshold
+            });
+
+            let retained = svc_state
+                .last_activity()
+                .is_some_and(|t| t >= removal_threshold);
+            let after = entry_counts(svc_state);
+            if !retained || after.operations != before.operations {
+                changes.structure = true;
+            } else if after != before {
+                changes.service(svc_key);
+            }
+            retained
+        });
+
+        self.state.destinations.retain(|_, dest| {
+            let retained = dest.last_seen >= oper_threshold;
+            changes.structure |= !retained;
+            retained
+        });
+
+        self.state.external_endpoints.retain(|_, endpoint| {
+            let callers = endpoint.callers.len();
+            endpoint.callers.retain(|_, rel| keep(rel));
+            let retained = endpoint.last_seen >= oper_threshold;
+            changes.external_endpoints |= !retained || endpoint.callers.len() != callers;
+            retained
+        });
+
+        self.state.custom_items.retain(|_, item| {
+            let relations = item.relations.len();
+            item.relations
+                .retain(|_, rel| rel.last_seen >= oper_threshold);
+            let retained = item.last_seen >= oper_threshold;
+            changes.custom_items |= !retained || item.relations.len() != relations;
+            retained
+        });
+    }
+
+    /// Evict the least recently updated traces while the trace map
+    /// exceeds its limits.
+    fn evict_traces(&mut self) {
+        let Some(lru) = &mut self.lru else {
+            return;
+        };
+        let mut evicted = lru.evict(&mut self.state.traces);
+        if !evicted.is_empty() {
+            let evicted_spans = evicted.iter().map(|info| info.spans.len()).sum::<usize>();
+            log::warn!(
+                "trace limits exceeded; evicted {} traces ({evicted_spans} spans)",
+                evicted.len()
+            );
+        }
+        self.harvest_external_calls(&mut evicted);
+    }
+
+    /// Keep a span until its trace has been idle for the quiescence
+    /// period.
+    fn buffer_span(&mut self, span: Span) -> Result<(), Error> {
+        let t = DateTime::from_timestamp_micros(span.start_time)
+            .ok_or(Error::TimestampOutOfBounds(span.start_time))?;
+        let trace = self
+            .state
+            .buffered
+            .entry(span.trace_id.clone())
+            .or_insert_with(|| BufferedTrace {
+                last_seen: t,
+                spans: Vec::new(),
+            });
+        trace.last_seen = trace.last_seen.max(t);
+        trace.spans.push(span.into_owned());
+        Ok(())
+    }
+
+    /// Update the state with a single span.
+    fn process_span(&mut self, span: Span) -> Result<(), Error> {
+        self.prepare_span(&span)?;
+        self.fold_span(span)
+    }
+
+    /// Read back spilled traces the span belongs or refers to, and mark
+    /// them as recently used.
+    fn prepare_span(&mut self, span: &Span) -> Result<(), Error> {
+        let ids =
+            std::iter::once(&span.trace_id).chain(span.references.iter().map(|r| &r.trace_id));
+        #[cfg(feature = "sled")]
+        if let Some(spill) = &mut self.spill {
+            for id in ids.clone() {
+                if spill.page_in(id, &mut self.state.traces)? {
+                    if let Some(lru) = &mut self.lru {
+                        lru.touch(id);
+                    }
+                }
+            }
+        }
+        if let Some(lru) = &mut self.lru {
+            ids.for_each(|id| lru.touch(id));
+        }
+        Ok(())
+    }
+
+    /// Trace a span and apply the observations to the service catalog.
+    fn fold_span(&mut self, span: Span) -> Result<(), Error> {
+        let tracer = Tracer {
+            semconv: &self.semconv,
+            key_config: &self.config.service_key,
+            merge_instances: self.merge_instances,
+            touched: self.trace_max_age.map(|_| self.cycle_start),
+        };
+        let observed = tracer.trace(&mut self.state.traces, span)?;
+        self.apply(observed);
+        Ok(())
+    }
+
+    /// Fold a batch of spans with `threads` threads tracing partitions
+    /// of the batch in parallel. Spans linking to other traces are
+    /// folded afterwards.
+    fn fold_parallel(&mut self, spans: Vec<Span>, threads: usize) -> Result<(), Error> {
+        for span in &spans {
+            self.prepare_span(span)?;
+        }
+        let (partitions, linked) = fold::partition(spans, &mut self.state.traces, threads);
+        let tracer = Tracer {
+            semconv: &self.semconv,
+            key_config: &self.config.service_key,
+            merge_instances: self.merge_instances,
+            touched: self.trace_max_age.map(|_| self.cycle_start),
+        };
+        let results = tracer.trace_partitions(partitions);
+
+        /* Merge the trace map back before applying the observations,
+         * so no traces are lost if one of the partitions failed. */
+        let mut observed = Vec::with_capacity(results.len());
+        for (traces, res) in results {
+            self.state.traces.extend(traces);
+            observed.push(res);
+        }
+        for res in observed {
+            res?.into_iter().for_each(|observed| self.apply(observed));
+        }
+
+        for mut span in linked {
+            self.semconv.apply(&mut span);
+            self.fold_span(span)?;
+        }
+        Ok(())
+    }
+
+    /// Update services, operations, destinations and relations with
+    /// what was observed from a span. Observations of parallel folds
+    /// are applied by partition rather than in time order, so times
+    /// seen are only ever moved forward.
+    fn apply(&mut self, observed: Observed) {
+        let Observed {
+            span,
+            t,
+            service_key,
+            instance_id,
+            meta,
+            span_kind,
+            destination,
+            relations,
+            consumers,
+        } = observed;
+
+        /* Update services and operations. Keys are only cloned into
+         * the maps for new entries. */
+
+        let svc_state = match self.state.services.contains_key(&service_key) {
+            true => {
+                self.changes.service(&service_key);
+                let svc = self.state.services.get_mut(&service_key).unwrap();
+                svc.meta.update(meta);
+                svc.last_seen = svc.last_seen.max(Some(t));
+                svc
+            }
+            false => {
+                self.changes.structure = true;
+                self.state
+                    .services
+                    .entry(service_key.clone())
+                    .or_insert_with(|| ServiceState {
+                        id: Uuid::new_v4(),
+                        meta,
+                        last_seen: Some(t),
+                        instances: BTreeMap::new(),
+                        relations: BTreeMap::new(),
+                        links: BTreeMap::new(),
+                        produces: BTreeMap::new(),
+                        operations: BTreeMap::new(),
+                    })
+            }
+        };
+
+        if self.merge_instances {
+            if let Some(instance_id) = instance_id {
+                let seen = svc_state.instances.entry(instance_id).or_insert(t);
+                *seen = (*seen).max(t);
+            }
+        }
+
+        if let Some((dest_name, system)) = destination {
+            match self.state.destinations.contains_key(&dest_name) {
+                true => self.changes.destinations = true,
+                false => self.changes.structure = true,
+            }
+            self.state
+                .destinations
+                .entry(dest_name.clone())
+                .and_modify(|dest| {
+                    dest.last_seen = dest.last_seen.max(t);
+                    if system.is_some() {
+                        dest.system.clone_from(&system);
+                    }
+                })
+                .or_insert_with(|| DestinationState {
+                    id: Uuid::new_v4(),
+                    system,
+                    last_seen: t,
+                });
+            svc_state
+                .produces
+                .entry(dest_name)
+                .and_modify(|produces| produces.last_seen = produces.last_seen.max(t))
+                .or_insert_with(|| ProducesState {
+                    id: Uuid::new_v4(),
+                    last_seen: t,
+                    consumer_last_seen: None,
+                });
+        }
+
+        if self.granularity == Granularity::Operation {
+            let oper_state = match svc_state.operations.get_mut(&span.operation_name) {
+                Some(state) => {
+                    state.last_seen = state.last_seen.max(t);
+                    state
+                }
+                None => {
+                    self.changes.structure = true;
+                    svc_state
+                        .operations
+                        .entry(span.operation_name.clone())
+                        .or_insert_with(|| OperationState {
+                            id: Uuid::new_v4(),
+                            relations: BTreeMap::new(),
+                            links: BTreeMap::new(),
+                            span_kinds: BTreeMap::new(),
+                            last_seen: t,
+                        })
+                }
+            };
+            *oper_state.span_kinds.entry(span_kind).or_default() += 1;
+        }
+
+        /* Update relations. */
+
+        for rel in relations {
+            self.changes.service(&rel.target.key.service_key);
+            add_relation(
+                &mut self.state.services,
+                &rel.source,
+                &rel.target,
+                &rel.ref_type,
+                t,
+            );
+        }
+
+        for (producer, dest_name) in consumers {
+            observe_consumer(&mut self.state.services, &producer, &dest_name, t);
+            self.changes.service(&producer);
+        }
+
+        /* Apply custom rules. */
+
+        for rule in &self.config.rules {
+            if rule.apply(&span, &service_key, t, &mut self.state.custom_items) {
+                self.changes.custom_items = true;
+            }
+        }
+    }
+}
+
+/// Order the spans of a trace such that parents precede their
+/// children, avoiding delayed (`parent_of`) relation processing.
+fn parents_first(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort_by_key(|span| span.start_time);
+    let ids = spans
+        .iter()
+        .map(|span| span.span_id.clone())
+        .collect::<BTreeSet<_>>();
+    let mut seen = BTreeSet::new();
+    let mut ordered = Vec::with_capacity(spans.len());
+    while !spans.is_empty() {
+        let (mut ready, pending): (Vec<_>, Vec<_>) = spans.into_iter().partition(|span| {
+            span.references
+                .iter()
+                .filter(|r| r.ref_type == RefType::ChildOf)
+                .all(|r| !ids.contains(&r.span_id) || seen.contains(&r.span_id))
+        });
+        spans = pending;
+        if ready.is_empty() {
+            /* Reference cycle; process the remainder in time order. */
+            ready = std::mem::take(&mut spans);
+        }
+        seen.extend(ready.iter().map(|span| span.span_id.clone()));
+        ordered.extend(ready);
+    }
+    ordered
+}
+
+/// Register a relation between the operation referenced by a span (the
+/// parent for `ChildOf`, the linked span for `FollowsFrom`) and the
+/// operation of the referencing span.
+fn add_relation(
+    services: &mut BTreeMap<ServiceKey, ServiceState>,
+    source_key: &OperationKey,
+    target: &RelationTarget,
+    ref_type: &RefType,
+    t: DateTime<Utc>,
+) {
+    let target_key = &target.key;
+    let svc_state = match services.get_mut(&target_key.service_key) {
+        Some(svc_state) => svc_state,
+        None => return,
+    };
+
+    if source_key.service_key != target_key.service_key {
+        let relations = match ref_type {
+            RefType::ChildOf => &mut svc_state.relations,
+            RefType::FollowsFrom => &mut svc_state.links,
+        };
+        get_or_insert(relations, &source_key.service_key, || RelationState::new(t))
+            .observe(t, target.http_status);
+    }
+
+    if let Some(oper_state) = svc_state.operations.get_mut(&target_key.operation_name) {
+        let relations = match ref_type {
+            RefType::ChildOf => &mut oper_state.relations,
+            RefType::FollowsFrom => &mut oper_state.links,
+        };
+        let relations = get_or_insert(relations, &source_key.service_key, BTreeMap::new);
+        get_or_insert(relations, &source_key.operation_name, || {
+            RelationState::new(t)
+        })
+        .observe(t, target.http_status);
+    }
+}
+
+/// Get an entry from a map, inserting it if missing. Unlike with the
+/// entry api, the key is only cloned when the entry is inserted.
+fn get_or_insert<'a, K: Ord + Clone, V>(
+    map: &'a mut BTreeMap<K, V>,
+    key: &K,
+    default: impl FnOnce() -> V,
+) -> &'a mut V {
+    if !map.contains_key(key) {
+        map.insert(key.clone(), default());
+    }
+    map.get_mut(key).unwrap()
+}
+
+/// Register a call to an endpoint outside the mesh.
+fn add_external_call(state: &mut State, call: ExternalCall) {
+    if !state.services.contains_key(&call.service_key) {
+        return;
+    }
+    let endpoint = state
+        .external_endpoints
+        .entry(call.host)
+        .or_insert_with(|| ExternalEndpointState {
+            id: Uuid::new_v4(),
+            last_seen: call.t,
+            paths: BTreeSet::new(),
+            callers: BTreeMap::new(),
+        });
+    endpoint.last_seen = endpoint.last_seen.max(call.t);
+    if let Some(path) = call.path {
+        if endpoint.paths.len() < MAX_EXTERNAL_PATHS || endpoint.paths.contains(&path) {
+            endpoint.paths.insert(path);
+        }
+    }
+    endpoint
+        .callers
+        .entry(call.service_key)
+        .or_insert_with(|| RelationState::new(call.t))
+        .observe(call.t, call.http_status);
+}
+
+/// All services (and functions).
+fn services(world: &World) -> BTreeSet<Uuid> {
+    world
+        .items
+        .iter()
+        .filter(|(_, item)| matches!(item, Item::Service { .. } | Item::Function { .. }))
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+/// Services that are not the target of any `service_invokes` relation.
+fn entry_services(world: &World) -> BTreeSet<Uuid> {
+    let targets = world
+        .relations
+        .values()
+        .filter(|rel| matches!(rel, Relation::ServiceInvokes { .. }))
+        .map(|rel| rel.endpoints().1)
+        .collect::<BTreeSet<_>>();
+    world
+        .items
+        .iter()
+        .filter(|(id, item)| {
+            matches!(item, Item::Service { .. } | Item::Function { .. }) && !targets.contains(id)
+        })
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+fn custom_properties(props: &BTreeMap<String, String>) -> BTreeMap<String, StringProperty> {
+    props
+        .iter()
+        .map(|(name, value)| (name.clone(), StringProperty::new(value.clone())))
+        .collect()
+}
+
+/// Number of entries of a service state, to find the services changed
+/// by expiry.
+#[derive(PartialEq, Eq)]
+struct EntryCounts {
+    instances: usize,
+    relations: usize,
+    produces: usize,
+    consumers: usize,
+    operations: usize,
+    operation_relations: usize,
+}
+
+fn entry_counts(svc_state: &ServiceState) -> EntryCounts {
+    EntryCounts {
+        instances: svc_state.instances.len(),
+        relations: svc_state.relations.len() + svc_state.links.len(),
+        produces: svc_state.produces.len(),
+        consumers: svc_state
+            .produces
+            .values()
+            .filter(|produces| produces.consumer_last_seen.is_some())
+            .count(),
+        operations: svc_state.operations.len(),
+        operation_relations: svc_state
+            .operations
+            .values()
+            .flat_map(|oper_state| {
+                oper_state
+                    .relations
+                    .values()
+                    .chain(oper_state.links.values())
+            })
+            .map(BTreeMap::len)
+            .sum(),
+    }
+}
+
+/// Mark a producer's destination as having an observed consumer.
+fn observe_consumer(
+    services: &mut BTreeMap<ServiceKey, ServiceState>,
+    producer: &ServiceKey,
+    dest_name: &DestinationName,
+    t: DateTime<Utc>,
+) {
+    if let Some(produces) = services
+        .get_mut(producer)
+        .and_then(|svc_state| svc_state.produces.get_mut(dest_name))
+    {
+        produces.consumer_last_seen = produces.consumer_last_seen.max(Some(t));
+    }
+}
+
+/// Replace path segments that look like identifiers (numbers, uuids,
+/// long hexadecimal strings) by `{id}`.
+fn path_template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            let is_id = !segment.is_empty()
+                && (segment.chars().all(|c| c.is_ascii_digit())
+                    || Uuid::parse_str(segment).is_ok()
+                    || (segment.len() >= 16 && segment.chars().all(|c| c.is_ascii_hexdigit())));
+            if is_id {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Maximum number of path templates kept per external endpoint.
+const MAX_EXTERNAL_PATHS: usize = 50;
+
+impl Span<'_> {
+    /// Copy the borrowed tags, to keep the span beyond the document it
+    /// was deserialized from.
+    pub(crate) fn into_owned(self) -> Span<'static> {
+        Span {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            operation_name: self.operation_name,
+            references: self.references,
+            start_time: self.start_time,
+            start_time_millis: self.start_time_millis,
+            duration: self.duration,
+            tags: self.tags.into_iter().map(Tag::into_owned).collect(),
+            logs: self.logs,
+            process: Process {
+                service_name: self.process.service_name,
+                tags: self.process.tags.into_iter().map(Tag::into_owned).collect(),
+            },
+        }
+    }
+
+    /// The span kind; spans without a kind tag are internal.
+    pub(crate) fn span_kind(&self) -> SpanKind {
+        match self.tag_str("span.kind") {
+            Some("server") => SpanKind::Server,
+            Some("client") => SpanKind::Client,
+            Some("producer") => SpanKind::Producer,
+            Some("consumer") => SpanKind::Consumer,
+            _ => SpanKind::Internal,
+        }
+    }
+
+    /// The host and templated path called by a client span.
+    pub(crate) fn external_call(&self) -> Option<(String, Option<String>)> {
+        if self.span_kind() != SpanKind::Client {
+            return None;
+        }
+        if let Some(url) = self.tag_str("http.url").and_then(|s| Url::parse(s).ok()) {
+            let host = url.host_str()?;
+            let host = match url.port() {
+                Some(port) => format!("{host}:{port}"),
+                None => host.to_string(),
+            };
+            return Some((host, Some(path_template(url.path()))));
+        }
+        let host = self.tag_str("server.address")?;
+        let host = match self.tag_value("server.port") {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        Some((host, None))
+    }
+
+    /// Look up a tag on the span or its process, formatting non-string
+    /// values.
+    pub(crate) fn tag_value(&self, key: &str) -> Option<String> {
+        self.tags
+            .iter()
+            .chain(&self.process.tags)
+            .find(|tag| tag.key == key)
+            .map(|tag| match &tag.value {
+                TagValue::String(s) => s.to_string(),
+                TagValue::Int64(n) => n.to_string(),
+                TagValue::Bool(Bool::True) => String::from("true"),
+                TagValue::Bool(Bool::False) => String::from("false"),
+            })
+    }
+
+    fn tag_str(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .filter(|tag| tag.key == key)
+            .find_map(|tag| match &tag.value {
+                TagValue::String(s) => Some(s.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// The messaging destination and system of a producer span.
+    pub(crate) fn messaging_destination(&self) -> Option<(DestinationName, Option<String>)> {
+        if self.span_kind() != SpanKind::Producer {
+            return None;
+        }
+        let name = self.tag_str("messaging.destination.name")?;
+        let system = self.tag_str("messaging.system").map(String::from);
+        Some((DestinationName(Name::new(name)), system))
+    }
+
+    pub(crate) fn http_status(&self) -> Option<u16> {
+        self.tags
+            .iter()
+            .filter(|tag| tag.key == "http.status_code")
+            .find_map(|tag| match &tag.value {
+                TagValue::Int64(Int64(n)) => u16::try_from(*n).ok(),
+                TagValue::String(s) => s.parse().ok(),
+                _ => None,
+            })
+    }
+}
+
+impl Tag<'_> {
+    fn into_owned(self) -> Tag<'static> {
+        Tag {
+            key: Cow::Owned(self.key.into_owned()),
+            value: match self.value {
+                TagValue::String(s) => TagValue::String(Cow::Owned(s.into_owned())),
+                TagValue::Int64(n) => TagValue::Int64(n),
+                TagValue::Bool(b) => TagValue::Bool(b),
+            },
+        }
+    }
 }
 
 impl ServiceMeta {
-    fn from_span(span: &Span) -> Self {
+    pub(crate) fn from_span(span: &Span) -> Self {
         let mut props = Self::default();
         span.process
             .tags
             .iter()
-            .for_each(|tag| match (tag.key.as_str(), &tag.value) {
+            .for_each(|tag| match (&*tag.key, &tag.value) {
                 ("service.version", TagValue::String(s)) => {
                     props.service_version = Some(StringProperty::new(s.to_string()))
                 }
@@ -866,8 +2884,254 @@ impl ServiceMeta {
                 ("k8s.cronjob.uid", TagValue::String(s)) => {
                     props.k8s_cronjob_uid = Some(StringProperty::new(s.to_string()))
                 }
+                ("faas.name", TagValue::String(s)) => {
+                    props.faas_name = Some(StringProperty::new(s.to_string()))
+                }
+                ("faas.id", TagValue::String(s)) => {
+                    props.faas_id = Some(StringProperty::new(s.to_string()))
+                }
+                ("cloud.platform", TagValue::String(s)) => {
+                    props.cloud_platform = Some(StringProperty::new(s.to_string()))
+                }
+                ("container.id", TagValue::String(s)) => {
+                    props.container_id = Some(StringProperty::new(s.to_string()))
+                }
+                ("container.image.name", TagValue::String(s)) => {
+                    props.container_image_name = Some(StringProperty::new(s.to_string()))
+                }
+                ("process.pid", TagValue::Int64(Int64(n))) => {
+                    props.process_pid = u64::try_from(*n).ok().map(IntegerProperty::new)
+                }
+                ("process.executable.name", TagValue::String(s)) => {
+                    props.process_executable_name = Some(StringProperty::new(s.to_string()))
+                }
                 _ => {}
             });
+        /* The trigger is a span attribute, set on the invocation span. */
+        props.faas_trigger = span
+            .tag_str("faas.trigger")
+            .map(|s| StringProperty::new(s.to_string()));
         props
     }
+
+    /// Update metadata from a new span, keeping span-level attributes
+    /// not present on the new span.
+    fn update(&mut self, other: Self) {
+        let faas_trigger = self.faas_trigger.take();
+        *self = other;
+        if self.faas_trigger.is_none() {
+            self.faas_trigger = faas_trigger;
+        }
+    }
+
+    fn is_function(&self) -> bool {
+        self.faas_name.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{sink::RecordingSink, Args};
+
+    fn oper_key(service: &str, operation: &str) -> OperationKey {
+        serde_json::from_value(json!({
+            "service_key": service,
+            "operation_name": operation,
+        }))
+        .unwrap()
+    }
+
+    /// Service states with a single operation for each of `keys`.
+    fn services(keys: &[&OperationKey], t: DateTime<Utc>) -> BTreeMap<ServiceKey, ServiceState> {
+        keys.iter()
+            .map(|key| {
+                let operation = OperationState {
+                    id: Uuid::new_v4(),
+                    relations: BTreeMap::new(),
+                    links: BTreeMap::new(),
+                    last_seen: t,
+                    span_kinds: BTreeMap::new(),
+                };
+                let service = ServiceState {
+                    id: Uuid::new_v4(),
+                    meta: ServiceMeta::default(),
+                    last_seen: Some(t),
+                    instances: BTreeMap::new(),
+                    relations: BTreeMap::new(),
+                    links: BTreeMap::new(),
+                    produces: BTreeMap::new(),
+                    operations: BTreeMap::from([(key.operation_name.clone(), operation)]),
+                };
+                (key.service_key.clone(), service)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn links_are_kept_apart_from_invocations() {
+        let t = Utc::now();
+        let frontend = oper_key("frontend", "GET /");
+        let backend = oper_key("backend", "query");
+        let batch = oper_key("batch", "run");
+        let mut services = services(&[&frontend, &backend, &batch], t);
+
+        let target = |key: &OperationKey| RelationTarget {
+            key: key.clone(),
+            http_status: None,
+            consumer: false,
+        };
+        add_relation(
+            &mut services,
+            &frontend,
+            &target(&backend),
+            &RefType::ChildOf,
+            t,
+        );
+        add_relation(
+            &mut services,
+            &frontend,
+            &target(&batch),
+            &RefType::FollowsFrom,
+            t,
+        );
+
+        let invoked = &services[&backend.service_key];
+        assert!(invoked.relations.contains_key(&frontend.service_key));
+        assert!(invoked.links.is_empty());
+
+        let linked = &services[&batch.service_key];
+        assert!(linked.relations.is_empty());
+        assert!(linked.links.contains_key(&frontend.service_key));
+        let operation = &linked.operations[&batch.operation_name];
+        assert!(operation.relations.is_empty());
+        assert!(operation.links[&frontend.service_key].contains_key(&frontend.operation_name));
+    }
+
+    /// A span of `service` at `time`, optionally a child of `parent`
+    /// in the same trace.
+    fn span(
+        id: &str,
+        service: &str,
+        operation: &str,
+        parent: Option<&str>,
+        tags: &[(&str, &str)],
+        time: DateTime<Utc>,
+    ) -> serde_json::Value {
+        json!({
+            "traceID": "t1",
+            "spanID": id,
+            "operationName": operation,
+            "references": parent.map(|parent| json!({
+                "refType": "CHILD_OF",
+                "traceID": "t1",
+                "spanID": parent,
+            })).into_iter().collect::<Vec<_>>(),
+            "startTime": time.timestamp_micros(),
+            "startTimeMillis": time.timestamp_millis(),
+            "duration": 1000,
+            "tags": tags.iter().map(|(key, value)| json!({
+                "key": key,
+                "type": "string",
+                "value": value,
+            })).collect::<Vec<_>>(),
+            "logs": [],
+            "process": { "serviceName": service, "tags": [] },
+        })
+    }
+
+    /// Fold `spans` into an in-memory discovery and publish the graph
+    /// to a recording sink, returning the published graph.
+    async fn discover(spans: &[serde_json::Value]) -> Items {
+        let args = Args::parse_from([
+            "jaeger-discovery",
+            "--es-url=http://es",
+            "--es-ca=ca.pem",
+            "--es-cert=cert.pem",
+            "--es-key=key.pem",
+            "--no-state",
+        ]);
+        let mut discovery = Discovery::load(&args).await.unwrap();
+        let sink = RecordingSink::default();
+        discovery.sinks = vec![Box::new(sink.clone())];
+
+        let data = serde_json::to_string(spans).unwrap();
+        let spans = serde_json::from_str::<Vec<Span>>(&data).unwrap();
+        let last = spans.iter().map(|span| span.start_time).max();
+        discovery.process_batch(spans, last).unwrap();
+
+        let mut record = RunRecord {
+            time: Utc::now(),
+            spans: 0,
+            items: None,
+            relations: None,
+            error: None,
+        };
+        discovery
+            .publish_graph(record.time, RETENTION, &mut record)
+            .await
+            .unwrap();
+
+        let mut pushed = sink.pushed();
+        assert_eq!(pushed.len(), 1);
+        pushed.pop().unwrap()
+    }
+
+    fn find_item(items: &Items, item_type: &str, name: &str) -> Uuid {
+        items
+            .items
+            .items
+            .iter()
+            .find(|(_, item)| item.item_type() == item_type && item.name() == name)
+            .map(|(id, _)| *id)
+            .unwrap_or_else(|| panic!("no {item_type} {name}"))
+    }
+
+    #[tokio::test]
+    async fn publishes_services_and_relations() {
+        let now = Utc::now();
+        let items = discover(&[
+            span(
+                "a",
+                "frontend",
+                "GET /",
+                None,
+                &[("span.kind", "server")],
+                now,
+            ),
+            span(
+                "b",
+                "backend",
+                "query",
+                Some("a"),
+                &[("span.kind", "server")],
+                now,
+            ),
+        ])
+        .await;
+
+        let frontend = find_item(&items, "jaeger/service", "frontend");
+        let backend = find_item(&items, "jaeger/service", "backend");
+        let get = find_item(&items, "jaeger/operation", "GET /");
+        let query = find_item(&items, "jaeger/operation", "query");
+        assert_eq!(items.items.items[&get].parent(), Some(frontend));
+        assert_eq!(items.items.items[&query].parent(), Some(backend));
+
+        let relations = items
+            .items
+            .relations
+            .values()
+            .map(|rel| (rel.relation_type(), rel.endpoints()))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            relations,
+            BTreeSet::from([
+                ("jaeger/service_invokes", (frontend, backend)),
+                ("jaeger/operation_invokes", (get, query)),
+            ])
+        );
+    }
 }
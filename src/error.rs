@@ -8,6 +8,8 @@ use std::path::PathBuf;
 pub(crate) enum Error {
     #[error("signal error: {0}")]
     Signal(std::io::Error),
+    #[error("failed to start the runtime: {0}")]
+    Runtime(std::io::Error),
     #[error("failed to read file: {0}: {1}")]
     ReadFile(PathBuf, std::io::Error),
     #[error("failed to write file: {0}: {1}")]
@@ -22,8 +24,82 @@ pub(crate) enum Error {
     InvalidUrl(#[from] url::ParseError),
     #[error("failed to delete pit")]
     DeletePit,
+    #[error("failed to parse span: {0}")]
+    SpanFormat(serde_json::Error),
+    #[error("failed to parse search response: {0}")]
+    SearchResponse(serde_json::Error),
+    #[error("search request failed: {0}")]
+    Prefetch(tokio::task::JoinError),
+    #[error("minimum batch size {0} exceeds maximum batch size {1}")]
+    BatchSizeBounds(u64, u64),
+    #[error("--docvalue-fields cannot be used with {0}")]
+    DocvalueFields(&'static str),
+    #[error("--preflight cannot be used with {0}")]
+    Preflight(&'static str),
     #[error("timestamp out of bounds: {0}")]
     TimestampOutOfBounds(i64),
+    #[error("maximum interval {0}s is shorter than the interval {1}s")]
+    IntervalBounds(u64, u64),
+    #[error("duration out of bounds: {0}s")]
+    InvalidDuration(i64),
     #[error("relation graph error: {0}: {1}")]
     RelationGraph(reqwest::Error, String),
+    #[cfg(feature = "kafka")]
+    #[error("kafka error: {0}")]
+    Kafka(#[from] rskafka::client::error::Error),
+    #[cfg(feature = "parquet")]
+    #[error("parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[cfg(feature = "kubernetes")]
+    #[error("not running in a kubernetes cluster (KUBERNETES_SERVICE_HOST is not set)")]
+    NotInCluster,
+    #[cfg(feature = "kubernetes")]
+    #[error("kubernetes api error: {0}: {1}")]
+    Kubernetes(reqwest::StatusCode, String),
+    #[cfg(feature = "object-store")]
+    #[error("object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[cfg(feature = "sled")]
+    #[error("state store error: {0}")]
+    Sled(#[from] sled::Error),
+    #[cfg(feature = "neo4j")]
+    #[error("neo4j error: {0}")]
+    Neo4j(#[from] neo4rs::Error),
+    #[error("metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+    #[error("http server error: {0}")]
+    Server(hyper::Error),
+    #[error("missing argument: {0}")]
+    MissingArgument(&'static str),
+    #[error("invalid compression level {0} for {1}")]
+    CompressionLevel(i32, &'static str),
+    #[error("state version {0} is newer than supported ({1})")]
+    StateVersion(u32, u32),
+    #[error("the state store already contains a state; use --force to replace it")]
+    StateExists,
+    #[error(
+        "state directory {0} is locked by another instance (see the process id in its lock file)"
+    )]
+    StateLocked(PathBuf),
+    #[error("state is encrypted, but no key was given: {0}")]
+    StateEncrypted(PathBuf),
+    #[cfg(feature = "encryption")]
+    #[error("failed to decrypt state (wrong key?): {0}")]
+    Decrypt(PathBuf),
+    #[cfg(feature = "encryption")]
+    #[error("invalid state key: expected 64 hex digits")]
+    InvalidStateKey,
+    #[cfg(all(feature = "encryption", any(feature = "sled", feature = "kubernetes")))]
+    #[error("state encryption is not supported by the {0} backend")]
+    EncryptionUnsupported(&'static str),
+    #[error("relation graph api version {0} is not supported (expected {1} to {2})")]
+    UnsupportedApiVersion(u32, u32, u32),
+    #[error("relation graph was modified concurrently")]
+    Conflict,
+    #[error("failed to push to {0} sink(s)")]
+    SinkFailed(usize),
+    #[error("invalid schema: {0}")]
+    InvalidSchema(String),
+    #[error("payload does not match the schema ({0} violation(s))")]
+    InvalidPayload(usize),
 }
@@ -0,0 +1,405 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Rendering of the discovered graph for visualization tools.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use uuid::Uuid;
+
+use crate::{
+    discovery::{Item, Relation, World},
+    error::Error,
+    state::{RelationState, State},
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum ExportFormat {
+    Dot,
+    Graphml,
+    Mermaid,
+    /// Service- and operation-level edge list.
+    Csv,
+    /// Service- and operation-level edge list.
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+/// Options for the (size-limited) mermaid export.
+#[derive(clap::Args, Debug)]
+pub(crate) struct MermaidOptions {
+    #[clap(long, help = "include operations (mermaid)")]
+    operations: bool,
+    #[clap(
+        long,
+        help = "only include the given number of most connected services (mermaid)"
+    )]
+    top: Option<usize>,
+}
+
+/// An invocation or link between services or operations, with its
+/// observation statistics.
+struct Edge {
+    level: &'static str,
+    kind: &'static str,
+    source_service: String,
+    source_operation: Option<String>,
+    target_service: String,
+    target_operation: Option<String>,
+    count: u64,
+    last_seen: DateTime<Utc>,
+}
+
+impl ExportFormat {
+    pub(crate) fn render(
+        self,
+        state: &State,
+        world: &World,
+        mermaid_opts: &MermaidOptions,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            Self::Dot => dot(world).into_bytes(),
+            Self::Graphml => graphml(world).into_bytes(),
+            Self::Mermaid => mermaid(world, mermaid_opts).into_bytes(),
+            Self::Csv => csv(&edges(state)).into_bytes(),
+            #[cfg(feature = "parquet")]
+            Self::Parquet => parquet(&edges(state))?,
+        })
+    }
+}
+
+fn edges(state: &State) -> Vec<Edge> {
+    let edge = |level,
+                kind,
+                source: (String, Option<String>),
+                target: (String, Option<String>),
+                rel: &RelationState| Edge {
+        level,
+        kind,
+        source_service: source.0,
+        source_operation: source.1,
+        target_service: target.0,
+        target_operation: target.1,
+        count: rel.count,
+        last_seen: rel.last_seen,
+    };
+
+    let mut edges = Vec::new();
+    for (svc_key, svc_state) in &state.services {
+        for (kind, rels) in [
+            ("invokes", &svc_state.relations),
+            ("links", &svc_state.links),
+        ] {
+            for (source_key, rel) in rels {
+                edges.push(edge(
+                    "service",
+                    kind,
+                    (source_key.to_string(), None),
+                    (svc_key.to_string(), None),
+                    rel,
+                ));
+            }
+        }
+        for (oper_name, oper_state) in &svc_state.operations {
+            for (kind, rels) in [
+                ("invokes", &oper_state.relations),
+                ("links", &oper_state.links),
+            ] {
+                for (source_key, source_opers) in rels {
+                    for (source_oper, rel) in source_opers {
+                        edges.push(edge(
+                            "operation",
+                            kind,
+                            (source_key.to_string(), Some(source_oper.to_string())),
+                            (svc_key.to_string(), Some(oper_name.to_string())),
+                            rel,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    edges
+}
+
+fn csv(edges: &[Edge]) -> String {
+    let mut out = String::from(
+        "level,kind,source_service,source_operation,target_service,target_operation,count,last_seen\n",
+    );
+    for edge in edges {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            edge.level,
+            edge.kind,
+            csv_escape(&edge.source_service),
+            csv_escape(edge.source_operation.as_deref().unwrap_or("")),
+            csv_escape(&edge.target_service),
+            csv_escape(edge.target_operation.as_deref().unwrap_or("")),
+            edge.count,
+            edge.last_seen.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(feature = "parquet")]
+fn parquet(edges: &[Edge]) -> Result<Vec<u8>, Error> {
+    use std::sync::Arc;
+
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType, Int64Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+
+    const SCHEMA: &str = "message edge {
+        REQUIRED BYTE_ARRAY level (UTF8);
+        REQUIRED BYTE_ARRAY kind (UTF8);
+        REQUIRED BYTE_ARRAY source_service (UTF8);
+        OPTIONAL BYTE_ARRAY source_operation (UTF8);
+        REQUIRED BYTE_ARRAY target_service (UTF8);
+        OPTIONAL BYTE_ARRAY target_operation (UTF8);
+        REQUIRED INT64 count;
+        REQUIRED INT64 last_seen (TIMESTAMP(MILLIS, true));
+    }";
+
+    /* Values and definition levels of an optional string column. */
+    let optional = |f: fn(&Edge) -> Option<&String>| {
+        let values = edges
+            .iter()
+            .filter_map(|edge| Some(ByteArray::from(f(edge)?.as_str())))
+            .collect::<Vec<_>>();
+        let levels = edges
+            .iter()
+            .map(|edge| i16::from(f(edge).is_some()))
+            .collect::<Vec<_>>();
+        (values, Some(levels))
+    };
+    let required = |f: fn(&Edge) -> &str| {
+        let values = edges
+            .iter()
+            .map(|edge| ByteArray::from(f(edge)))
+            .collect::<Vec<_>>();
+        (values, None)
+    };
+    let strings = [
+        required(|edge| edge.level),
+        required(|edge| edge.kind),
+        required(|edge| &edge.source_service),
+        optional(|edge| edge.source_operation.as_ref()),
+        required(|edge| &edge.target_service),
+        optional(|edge| edge.target_operation.as_ref()),
+    ];
+    let ints = [
+        edges
+            .iter()
+            .map(|edge| edge.count as i64)
+            .collect::<Vec<_>>(),
+        edges
+            .iter()
+            .map(|edge| edge.last_seen.timestamp_millis())
+            .collect(),
+    ];
+
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let mut data = Vec::new();
+    let mut writer =
+        SerializedFileWriter::new(&mut data, schema, Arc::new(WriterProperties::default()))?;
+    let mut row_group = writer.next_row_group()?;
+    for (values, levels) in &strings {
+        let mut column = row_group.next_column()?.unwrap();
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(values, levels.as_deref(), None)?;
+        column.close()?;
+    }
+    for values in &ints {
+        let mut column = row_group.next_column()?.unwrap();
+        column
+            .typed::<Int64Type>()
+            .write_batch(values, None, None)?;
+        column.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(data)
+}
+
+/// Render the graph in Graphviz DOT format. Items are drawn as nodes,
+/// with a dashed edge to their parent, if any.
+fn dot(world: &World) -> String {
+    let mut out = String::from("digraph jaeger {\n  node [shape=box];\n");
+    for (id, item) in &world.items {
+        writeln!(
+            out,
+            "  \"{id}\" [label=\"{}\\n{}\"];",
+            dot_escape(&item.name()),
+            dot_escape(item.item_type())
+        )
+        .unwrap();
+        if let Some(parent) = item.parent() {
+            writeln!(
+                out,
+                "  \"{id}\" -> \"{parent}\" [style=dashed, arrowhead=none];"
+            )
+            .unwrap();
+        }
+    }
+    for rel in world.relations.values() {
+        let (source, target) = rel.endpoints();
+        writeln!(
+            out,
+            "  \"{source}\" -> \"{target}\" [label=\"{}\"];",
+            dot_escape(rel.relation_type())
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Render the graph in GraphML format, with the item and relation
+/// types and item names as data attributes.
+fn graphml(world: &World) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        "  <key id=\"type\" for=\"all\" attr.name=\"type\" attr.type=\"string\"/>\n",
+        "  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n",
+        "  <key id=\"parent\" for=\"node\" attr.name=\"parent\" attr.type=\"string\"/>\n",
+        "  <graph id=\"jaeger\" edgedefault=\"directed\">\n",
+    ));
+    for (id, item) in &world.items {
+        writeln!(out, "    <node id=\"{id}\">").unwrap();
+        writeln!(
+            out,
+            "      <data key=\"type\">{}</data>",
+            xml_escape(item.item_type())
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "      <data key=\"name\">{}</data>",
+            xml_escape(&item.name())
+        )
+        .unwrap();
+        if let Some(parent) = item.parent() {
+            writeln!(out, "      <data key=\"parent\">{parent}</data>").unwrap();
+        }
+        out.push_str("    </node>\n");
+    }
+    for (id, rel) in &world.relations {
+        let (source, target) = rel.endpoints();
+        writeln!(
+            out,
+            "    <edge id=\"{id}\" source=\"{source}\" target=\"{target}\">"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "      <data key=\"type\">{}</data>",
+            xml_escape(rel.relation_type())
+        )
+        .unwrap();
+        out.push_str("    </edge>\n");
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
+
+/// Render services (and optionally their operations) and the
+/// invocations between them as a Mermaid flowchart.
+fn mermaid(world: &World, opts: &MermaidOptions) -> String {
+    let mut degree = world
+        .items
+        .iter()
+        .filter(|(_, item)| matches!(item, Item::Service { .. } | Item::Function { .. }))
+        .map(|(id, _)| (*id, 0))
+        .collect::<BTreeMap<Uuid, usize>>();
+    for rel in world.relations.values() {
+        if let Relation::ServiceInvokes { source, target, .. } = rel {
+            for id in [source, target] {
+                if let Some(n) = degree.get_mut(id) {
+                    *n += 1;
+                }
+            }
+        }
+    }
+
+    let mut services = degree.into_iter().collect::<Vec<_>>();
+    services.sort_by(|(_, a), (_, b)| b.cmp(a));
+    if let Some(top) = opts.top {
+        services.truncate(top);
+    }
+    let services = services
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<BTreeSet<_>>();
+
+    /* Short node ids, as mermaid does not accept all characters. */
+    let mut nodes = BTreeMap::new();
+    let mut out = String::from("flowchart LR\n");
+    for svc_id in &services {
+        let node = format!("n{}", nodes.len());
+        let name = mermaid_escape(&world.items[svc_id].name());
+        nodes.insert(*svc_id, node.clone());
+        if !opts.operations {
+            writeln!(out, "  {node}[\"{name}\"]").unwrap();
+            continue;
+        }
+        writeln!(out, "  subgraph {node}[\"{name}\"]").unwrap();
+        for (id, item) in &world.items {
+            if item.parent() == Some(*svc_id) {
+                let oper_node = format!("n{}", nodes.len());
+                writeln!(out, "    {oper_node}[\"{}\"]", mermaid_escape(&item.name())).unwrap();
+                nodes.insert(*id, oper_node);
+            }
+        }
+        out.push_str("  end\n");
+    }
+
+    for rel in world.relations.values() {
+        let (source, target) = match rel {
+            Relation::ServiceInvokes { source, target, .. } if !opts.operations => (source, target),
+            Relation::OperationInvokes { source, target, .. } if opts.operations => {
+                (source, target)
+            }
+            _ => continue,
+        };
+        if let (Some(source), Some(target)) = (nodes.get(source), nodes.get(target)) {
+            writeln!(out, "  {source} --> {target}").unwrap();
+        }
+    }
+
+    out
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
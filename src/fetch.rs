@@ -0,0 +1,321 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Fetching of spans from Opensearch, pipelined with their processing:
+//! one task fetches batches from the (sliced) query, another parses
+//! them, while the state is updated with the previous batch.
+
+use std::borrow::Cow;
+
+use reqwest::Client;
+use self_cell::self_cell;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::Instrument;
+use url::Url;
+
+use crate::{
+    discovery::{Process, RefType, Reference, Span, Tag, TagValue},
+    error::Error,
+    query::{BatchSize, EsPit, Page},
+    state::{OperationName, QueryPosition, ServiceName, SpanId, TraceId},
+};
+
+self_cell!(
+    /// The spans parsed from the pages of a round, borrowing their tags
+    /// from the response bodies.
+    pub(crate) struct Spans {
+        owner: Vec<Page<QueryPosition>>,
+        #[covariant]
+        dependent: SpanList,
+    }
+);
+
+type SpanList<'a> = Vec<Span<'a>>;
+
+/// Number of batches buffered between the stages of the pipeline.
+const PIPELINE_DEPTH: usize = 2;
+
+/// Fields read with `--docvalue-fields`. The span kind is only
+/// available as a field when Jaeger stores tags as fields. Without
+/// references, process tags and HTTP or messaging tags, no links,
+/// consumers or external calls are found in this mode.
+const DOCVALUE_FIELDS: &[&str] = &[
+    "traceID",
+    "spanID",
+    "parentSpanID",
+    "operationName",
+    "startTime",
+    "duration",
+    "process.serviceName",
+    "tag.span@kind",
+];
+
+/// The doc values of a span, as returned for `DOCVALUE_FIELDS`.
+#[derive(Deserialize)]
+struct SpanFields {
+    #[serde(rename = "traceID")]
+    trace_id: (TraceId,),
+    #[serde(rename = "spanID")]
+    span_id: (SpanId,),
+    #[serde(rename = "parentSpanID", default)]
+    parent_span_id: Vec<SpanId>,
+    #[serde(rename = "operationName")]
+    operation_name: (OperationName,),
+    #[serde(rename = "startTime")]
+    start_time: (i64,),
+    duration: (u64,),
+    #[serde(rename = "process.serviceName")]
+    service_name: (ServiceName,),
+    #[serde(rename = "tag.span@kind", default)]
+    span_kind: Vec<String>,
+}
+
+/// Spans fetched in one round, from every slice of the query (or the
+/// pages containing them, before parsing).
+pub(crate) struct Round<S> {
+    pub(crate) spans: S,
+    /// Position of every slice after this round.
+    pub(crate) positions: Vec<Option<QueryPosition>>,
+    /// Position up to which spans have been fetched from all slices:
+    /// that of the slowest slice still running, or of the last span
+    /// once all have finished.
+    pub(crate) watermark: Option<i64>,
+}
+
+/// The spans to fetch.
+pub(crate) struct Window {
+    /// Indices (or index pattern) to search.
+    pub(crate) indices: String,
+    /// Start time of the spans to fetch, in microseconds.
+    pub(crate) from: i64,
+    /// Services to fetch the spans of, or all if `None`.
+    pub(crate) services: Option<Vec<String>>,
+}
+
+/// Start time (in microseconds) of the first span still to be fetched
+/// from the `positions` of the slices, starting at `from`.
+pub(crate) fn window_start(from: i64, positions: &[Option<QueryPosition>]) -> i64 {
+    positions
+        .iter()
+        .map(|last| last.as_ref().map_or(from, |last| last.0.max(from)))
+        .min()
+        .unwrap_or(from)
+}
+
+/// Start fetching the spans in `window`, in as many slices as there
+/// are `positions` to resume from, reading only doc values if
+/// `docvalues` is set. The fetch task finishes, removing the pit, when
+/// all spans are fetched or the receiver is dropped.
+pub(crate) fn spawn(
+    client: Client,
+    url: Url,
+    window: Window,
+    positions: Vec<Option<QueryPosition>>,
+    batch_size: BatchSize,
+    docvalues: bool,
+) -> (mpsc::Receiver<Result<Round<Spans>, Error>>, JoinHandle<()>) {
+    let (raw_tx, raw_rx) = mpsc::channel(PIPELINE_DEPTH);
+    let (tx, rx) = mpsc::channel(PIPELINE_DEPTH);
+    let fetcher = tokio::spawn(
+        async move {
+            match EsPit::new(client, &url, &window.indices, "1m").await {
+                Ok(pit) => {
+                    fetch(&pit, &window, positions, batch_size, docvalues, &raw_tx).await;
+                    pit.delete().await.unwrap_or_else(|e| log::warn!("{e}"));
+                }
+                Err(e) => {
+                    let _ = raw_tx.send(Err(e)).await;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    tokio::spawn(parse(raw_rx, tx, docvalues).in_current_span());
+    (rx, fetcher)
+}
+
+async fn fetch(
+    pit: &EsPit,
+    window: &Window,
+    mut positions: Vec<Option<QueryPosition>>,
+    batch_size: BatchSize,
+    docvalues: bool,
+    tx: &mpsc::Sender<Result<Round<Vec<Page<QueryPosition>>>, Error>>,
+) {
+    let slices = positions.len() as u32;
+    let mut queries = positions
+        .iter()
+        .enumerate()
+        .map(|(i, last)| {
+            /* Spans are sorted by id within the same microsecond, so
+             * the query continues exactly after the last span, even
+             * at a page boundary. */
+            let range = json!({
+                "range": {
+                    "startTime": {
+                        "gte": window.from
+                    }
+                }
+            });
+            let query = pit.query::<_, serde_json::Value, QueryPosition>(
+                match &window.services {
+                    None => range,
+                    Some(services) => json!({
+                        "bool": {
+                            "filter": [
+                                range,
+                                { "terms": { "process.serviceName": services } }
+                            ]
+                        }
+                    }),
+                },
+                Some(json!([
+                    { "startTime": { "order": "asc" } },
+                    { "spanID": { "order": "asc" } }
+                ])),
+                last.clone(),
+                batch_size,
+            );
+            let query = match docvalues {
+                true => query.docvalue_fields(DOCVALUE_FIELDS),
+                false => query,
+            };
+            Some(match slices {
+                1 => query,
+                _ => query.slice(i as u32, slices),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        /* Fetch the next batch of every slice concurrently. */
+        let results =
+            futures::future::join_all(queries.iter_mut().enumerate().filter_map(|(i, query)| {
+                let query = query.as_mut()?;
+                Some(async move { (i, query.next().await) })
+            }))
+            .await;
+
+        let mut pages = Vec::new();
+        for (i, res) in results {
+            match res {
+                Ok(Some(page)) => {
+                    if let Some(sort) = &page.last {
+                        positions[i] = Some(sort.clone());
+                    }
+                    pages.push(page);
+                }
+                Ok(None) => queries[i] = None,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+        if pages.is_empty() {
+            return;
+        }
+
+        /* Spans up to the slowest slice still running have been
+         * fetched; traces expire relative to that. */
+        let running = positions
+            .iter()
+            .zip(&queries)
+            .filter(|(_, query)| query.is_some())
+            .map(|(position, _)| position.as_ref().map(|p| p.0))
+            .min();
+        let watermark = match running {
+            Some(position) => position,
+            None => positions.iter().flatten().map(|p| p.0).max(),
+        };
+
+        let round = Round {
+            spans: pages,
+            positions: positions.clone(),
+            watermark,
+        };
+        if tx.send(Ok(round)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Parse the fetched spans, ordering those of a sliced query by their
+/// start time.
+async fn parse(
+    mut rx: mpsc::Receiver<Result<Round<Vec<Page<QueryPosition>>>, Error>>,
+    tx: mpsc::Sender<Result<Round<Spans>, Error>>,
+    docvalues: bool,
+) {
+    while let Some(res) = rx.recv().await {
+        let res = res.and_then(|round| {
+            let spans = Spans::try_new(round.spans, |pages| {
+                let mut spans = Vec::new();
+                for page in pages {
+                    match docvalues {
+                        true => spans.extend(
+                            page.documents::<SpanFields>()
+                                .map_err(Error::SpanFormat)?
+                                .into_iter()
+                                .map(Span::from),
+                        ),
+                        false => spans.extend(page.documents::<Span>().map_err(Error::SpanFormat)?),
+                    }
+                }
+                if round.positions.len() > 1 {
+                    spans.sort_by_key(|span| span.start_time);
+                }
+                Ok::<_, Error>(spans)
+            })?;
+            Ok(Round {
+                spans,
+                positions: round.positions,
+                watermark: round.watermark,
+            })
+        });
+        if tx.send(res).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl From<SpanFields> for Span<'_> {
+    fn from(fields: SpanFields) -> Self {
+        let trace_id = fields.trace_id.0;
+        Span {
+            /* Jaeger writes an all-zero parent id for root spans. */
+            references: fields
+                .parent_span_id
+                .into_iter()
+                .filter(|id| id.0.bytes().any(|b| b != b'0'))
+                .map(|span_id| Reference {
+                    ref_type: RefType::ChildOf,
+                    trace_id: trace_id.clone(),
+                    span_id,
+                })
+                .collect(),
+            trace_id,
+            span_id: fields.span_id.0,
+            operation_name: fields.operation_name.0,
+            start_time: fields.start_time.0,
+            start_time_millis: fields.start_time.0 / 1000,
+            duration: fields.duration.0,
+            tags: fields
+                .span_kind
+                .into_iter()
+                .map(|kind| Tag {
+                    key: Cow::Borrowed("span.kind"),
+                    value: TagValue::String(Cow::Owned(kind)),
+                })
+                .collect(),
+            logs: Vec::new(),
+            process: Process {
+                service_name: fields.service_name.0,
+                tags: Vec::new(),
+            },
+        }
+    }
+}
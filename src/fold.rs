@@ -0,0 +1,322 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Folding of spans into the state, in two steps: a span is first
+//! traced in the trace and span map of its trace, which yields what
+//! was observed from it, and the observations are then applied to the
+//! service catalog. Tracing only touches the traces the span belongs
+//! or refers to, so with `--fold-threads` the spans of a batch are
+//! partitioned by trace id and traced in parallel, each partition with
+//! its own share of the trace map, while the (cheap) application to
+//! the catalog stays sequential.
+
+use std::{collections::hash_map::RandomState, hash::BuildHasher, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+
+use crate::{
+    config::ServiceKeyConfig,
+    discovery::{RefType, ServiceMeta, Span, TagValue},
+    error::Error,
+    intern::Name,
+    semconv::Semconv,
+    state::{
+        DestinationName, ExternalCall, OperationKey, RelationTarget, ServiceInstanceId, ServiceKey,
+        ServiceName, ServiceNamespace, SpanKind, TraceId, TraceInfo,
+    },
+};
+
+/// Settings for tracing spans, shared by the partitions of a batch.
+pub(crate) struct Tracer<'a> {
+    pub(crate) semconv: &'a Semconv,
+    pub(crate) key_config: &'a ServiceKeyConfig,
+    pub(crate) merge_instances: bool,
+    /// Start of the cycle, when traces are also expired by wall-clock
+    /// time.
+    pub(crate) touched: Option<DateTime<Utc>>,
+}
+
+/// What was learned from a span, to be applied to the service catalog.
+pub(crate) struct Observed<'a> {
+    pub(crate) span: Span<'a>,
+    pub(crate) t: DateTime<Utc>,
+    pub(crate) service_key: ServiceKey,
+    pub(crate) instance_id: Option<ServiceInstanceId>,
+    pub(crate) meta: ServiceMeta,
+    pub(crate) span_kind: SpanKind,
+    pub(crate) destination: Option<(DestinationName, Option<String>)>,
+    /// Relations between this span and the spans it refers to or that
+    /// refer to it, seen so far.
+    pub(crate) relations: Vec<ObservedRelation>,
+    /// Producers (by service) whose messages were consumed, by
+    /// destination.
+    pub(crate) consumers: Vec<(ServiceKey, DestinationName)>,
+}
+
+/// The spans of a partition of a batch, with the traces they belong
+/// to.
+pub(crate) type Partition<'a> = (Vec<Span<'a>>, HashMap<TraceId, TraceInfo>);
+
+/// The traces of a partition after tracing its spans, and the
+/// observations made.
+pub(crate) type Traced<'a> = (
+    HashMap<TraceId, TraceInfo>,
+    Result<Vec<Observed<'a>>, Error>,
+);
+
+/// A relation found between two spans: from the operation of the
+/// parent (or linked) span to that of the referencing span.
+pub(crate) struct ObservedRelation {
+    pub(crate) source: OperationKey,
+    pub(crate) target: RelationTarget,
+    pub(crate) ref_type: RefType,
+}
+
+impl Tracer<'_> {
+    /// Record a span in the trace and span map, returning what was
+    /// observed from it.
+    pub(crate) fn trace<'a>(
+        &self,
+        traces: &mut HashMap<TraceId, TraceInfo>,
+        span: Span<'a>,
+    ) -> Result<Observed<'a>, Error> {
+        let t = DateTime::from_timestamp_micros(span.start_time)
+            .ok_or(Error::TimestampOutOfBounds(span.start_time))?;
+
+        /* Find service key.*/
+
+        let instance_id = span
+            .process
+            .tags
+            .iter()
+            .filter(|tag| &tag.key == "service.instance.id")
+            .find_map(|tag| match &tag.value {
+                TagValue::String(s) => Some(ServiceInstanceId(Name::new(s))),
+                _ => None,
+            });
+
+        let key_config = self.key_config;
+        let service_key = ServiceKey {
+            namespace: span
+                .process
+                .tags
+                .iter()
+                .filter(|tag| key_config.namespace && &tag.key == "service.namespace")
+                .find_map(|tag| match &tag.value {
+                    TagValue::String(s) => Some(ServiceNamespace(Name::new(s))),
+                    _ => None,
+                }),
+            name: key_config
+                .name_tag
+                .as_ref()
+                .and_then(|tag| span.tag_value(tag))
+                .map_or_else(
+                    || span.process.service_name.clone(),
+                    |name| ServiceName(name.into()),
+                ),
+            instance_id: instance_id
+                .clone()
+                .filter(|_| key_config.instance_id && !self.merge_instances),
+            qualifiers: match key_config.tags.is_empty() {
+                true => Arc::default(),
+                false => key_config
+                    .tags
+                    .iter()
+                    .filter_map(|tag| Some((Name::new(tag), span.tag_value(tag)?.into())))
+                    .collect(),
+            },
+        };
+
+        /* Insert into trace and span map. */
+
+        let trace_info = traces
+            .entry_ref(&span.trace_id)
+            .and_modify(|info| {
+                info.last_seen = t;
+                info.touched = self.touched;
+            })
+            .or_insert_with(|| TraceInfo {
+                last_seen: t,
+                touched: self.touched,
+                spans: HashMap::new(),
+            });
+
+        let span_info = trace_info.spans.entry_ref(&span.span_id).or_default();
+        span_info.key = Some(OperationKey {
+            service_key: service_key.clone(),
+            operation_name: span.operation_name.clone(),
+        });
+
+        let destination = span.messaging_destination();
+        if let Some((dest_name, _)) = &destination {
+            span_info.destination = Some(dest_name.clone());
+        }
+
+        /* Find relations. Only the first ChildOf reference is
+         * considered a parent; FollowsFrom references (span links)
+         * may point to other traces. */
+
+        let target = RelationTarget {
+            key: OperationKey {
+                service_key: service_key.clone(),
+                operation_name: span.operation_name.clone(),
+            },
+            http_status: span.http_status(),
+            consumer: span.span_kind() == SpanKind::Consumer,
+        };
+
+        let parent_of = std::mem::take(&mut span_info.parent_of);
+        let linked_by = std::mem::take(&mut span_info.linked_by);
+
+        span_info.external = span.external_call().map(|(host, path)| ExternalCall {
+            service_key: service_key.clone(),
+            host,
+            path,
+            http_status: target.http_status,
+            t,
+        });
+
+        let mut relations = Vec::new();
+        let mut consumers = Vec::new();
+
+        if let Some(dest_name) = parent_of
+            .iter()
+            .chain(&linked_by)
+            .find_map(|child| span_info.consumed_by(child))
+        {
+            consumers.push((service_key.clone(), dest_name.clone()));
+        }
+
+        for r in span
+            .references
+            .iter()
+            .filter(|r| r.ref_type == RefType::ChildOf)
+            .take(1)
+            .chain(
+                span.references
+                    .iter()
+                    .filter(|r| r.ref_type == RefType::FollowsFrom),
+            )
+        {
+            let parent_trace = traces
+                .entry_ref(&r.trace_id)
+                .and_modify(|info| {
+                    info.last_seen = t;
+                    info.touched = self.touched;
+                })
+                .or_insert_with(|| TraceInfo {
+                    last_seen: t,
+                    touched: self.touched,
+                    spans: HashMap::new(),
+                });
+            let parent_span = parent_trace.spans.entry_ref(&r.span_id).or_default();
+
+            if r.ref_type == RefType::ChildOf {
+                parent_span.has_children = true;
+            }
+
+            if let Some(parent_key) = &parent_span.key {
+                relations.push(ObservedRelation {
+                    source: parent_key.clone(),
+                    target: target.clone(),
+                    ref_type: r.ref_type,
+                });
+                if let Some(dest_name) = parent_span.consumed_by(&target) {
+                    consumers.push((parent_key.service_key.clone(), dest_name.clone()));
+                }
+            } else {
+                match r.ref_type {
+                    RefType::ChildOf => parent_span.parent_of.push(target.clone()),
+                    RefType::FollowsFrom => parent_span.linked_by.push(target.clone()),
+                }
+            }
+        }
+
+        relations.extend(parent_of.into_iter().map(|child| ObservedRelation {
+            source: target.key.clone(),
+            target: child,
+            ref_type: RefType::ChildOf,
+        }));
+        relations.extend(linked_by.into_iter().map(|linking| ObservedRelation {
+            source: target.key.clone(),
+            target: linking,
+            ref_type: RefType::FollowsFrom,
+        }));
+
+        Ok(Observed {
+            meta: ServiceMeta::from_span(&span),
+            span_kind: span.span_kind(),
+            span,
+            t,
+            service_key,
+            instance_id,
+            destination,
+            relations,
+            consumers,
+        })
+    }
+
+    /// Trace the partitions of a batch in parallel, one thread per
+    /// partition. Each partition is traced against its own share of
+    /// the trace map, which is returned along with the observations in
+    /// span order.
+    pub(crate) fn trace_partitions<'a>(&self, partitions: Vec<Partition<'a>>) -> Vec<Traced<'a>> {
+        std::thread::scope(|scope| {
+            partitions
+                .into_iter()
+                .map(|(spans, mut traces)| {
+                    scope.spawn(move || {
+                        let observed = spans
+                            .into_iter()
+                            .map(|mut span| {
+                                self.semconv.apply(&mut span);
+                                self.trace(&mut traces, span)
+                            })
+                            .collect();
+                        (traces, observed)
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect()
+        })
+    }
+}
+
+/// Split a batch into `n` partitions by trace id, moving the traces
+/// of every partition out of the trace map. Spans referring to other
+/// traces (links) are returned separately, in order, to be processed
+/// once the partitions are merged back.
+pub(crate) fn partition<'a>(
+    spans: Vec<Span<'a>>,
+    traces: &mut HashMap<TraceId, TraceInfo>,
+    n: usize,
+) -> (Vec<Partition<'a>>, Vec<Span<'a>>) {
+    let hasher = RandomState::new();
+    let mut partitions = (0..n)
+        .map(|_| (Vec::new(), HashMap::new()))
+        .collect::<Vec<_>>();
+    let mut linked = Vec::new();
+    for span in spans {
+        if span.references.iter().any(|r| r.trace_id != span.trace_id) {
+            linked.push(span);
+            continue;
+        }
+        let (shard_spans, shard_traces) =
+            &mut partitions[(hasher.hash_one(&span.trace_id) % n as u64) as usize];
+        if !shard_traces.contains_key(&span.trace_id) {
+            if let Some((id, info)) = traces.remove_entry(&span.trace_id) {
+                shard_traces.insert(id, info);
+            }
+        }
+        shard_spans.push(span);
+    }
+    (partitions, linked)
+}
@@ -0,0 +1,114 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Incremental maintenance of the published graph: the items and
+//! relations built from the state are kept between cycles, and only
+//! those built from changed parts of the state are rebuilt.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    discovery::{Item, Relation, World},
+    state::ServiceKey,
+};
+
+/// The graph built from the state, with the ids of the items and
+/// relations built from every part of it.
+#[derive(Default)]
+pub(crate) struct Graph {
+    pub(crate) world: World,
+    /// Hour in which the graph was last built from scratch. Properties
+    /// relative to the current time are refreshed hourly.
+    built: Option<DateTime<Utc>>,
+    parts: BTreeMap<Part, Built>,
+}
+
+/// A part of the state the graph is built from.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub(crate) enum Part {
+    /// A service, with its operations and the relations targeting
+    /// them.
+    Service(ServiceKey),
+    Destinations,
+    ExternalEndpoints,
+    CustomItems,
+    Discovery,
+}
+
+#[derive(Default)]
+struct Built {
+    items: Vec<Uuid>,
+    relations: Vec<Uuid>,
+}
+
+/// Changes to the state since the graph was last updated.
+#[derive(Default, Debug)]
+pub(crate) struct Changes {
+    /// Services, operations or destinations were added or removed.
+    /// Relations built from other services may refer to them, so the
+    /// graph is rebuilt.
+    pub(crate) structure: bool,
+    pub(crate) services: BTreeSet<ServiceKey>,
+    pub(crate) destinations: bool,
+    pub(crate) external_endpoints: bool,
+    pub(crate) custom_items: bool,
+}
+
+impl Changes {
+    pub(crate) fn service(&mut self, key: &ServiceKey) {
+        if !self.services.contains(key) {
+            self.services.insert(key.clone());
+        }
+    }
+}
+
+impl Graph {
+    /// An empty graph, to be built from scratch in hour `hour`.
+    pub(crate) fn new(hour: DateTime<Utc>) -> Self {
+        Self {
+            built: Some(hour),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the graph was built from scratch in hour `hour`.
+    pub(crate) fn built_in(&self, hour: DateTime<Utc>) -> bool {
+        self.built == Some(hour)
+    }
+
+    /// Replace the items and relations built from a part of the state.
+    pub(crate) fn replace(
+        &mut self,
+        part: Part,
+        items: impl IntoIterator<Item = (Uuid, Item)>,
+        relations: impl IntoIterator<Item = (Uuid, Relation)>,
+    ) {
+        self.remove(&part);
+        let mut built = Built::default();
+        for (id, item) in items {
+            built.items.push(id);
+            self.world.items.insert(id, item);
+        }
+        for (id, rel) in relations {
+            built.relations.push(id);
+            self.world.relations.insert(id, rel);
+        }
+        self.parts.insert(part, built);
+    }
+
+    /// Remove the items and relations built from a part of the state.
+    pub(crate) fn remove(&mut self, part: &Part) {
+        if let Some(built) = self.parts.remove(part) {
+            built.items.iter().for_each(|id| {
+                self.world.items.remove(id);
+            });
+            built.relations.iter().for_each(|id| {
+                self.world.relations.remove(id);
+            });
+        }
+    }
+}
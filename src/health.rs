@@ -0,0 +1,108 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Liveness and readiness of the daemon, served on `/healthz` and
+//! `/readyz` for Kubernetes probes. The daemon is live as long as no
+//! cycle is stuck, and ready once the state and configuration are
+//! loaded and the last requests to Opensearch and the relation graph
+//! succeeded.
+
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::error::Error;
+
+static HEALTH: LazyLock<Mutex<Health>> = LazyLock::new(Default::default);
+
+#[derive(Default)]
+struct Health {
+    /// The state and configuration were loaded and the sinks
+    /// initialized.
+    started: bool,
+    /// Start of the running cycle, if any.
+    cycle_start: Option<Instant>,
+    /// Result of the last request to Opensearch.
+    opensearch: Option<Result<(), String>>,
+    /// Result of the last request to every relation graph, by sink
+    /// name.
+    relation_graphs: BTreeMap<String, Result<(), String>>,
+}
+
+/// Record that the daemon finished starting up.
+pub(crate) fn started() {
+    HEALTH.lock().unwrap().started = true;
+}
+
+/// Record the start of a discovery cycle.
+pub(crate) fn cycle_started() {
+    HEALTH.lock().unwrap().cycle_start = Some(Instant::now());
+}
+
+/// Record the end of a discovery cycle, successful or not.
+pub(crate) fn cycle_finished() {
+    HEALTH.lock().unwrap().cycle_start = None;
+}
+
+/// Record the result of a request to Opensearch.
+pub(crate) fn opensearch<T>(res: &Result<T, Error>) {
+    HEALTH.lock().unwrap().opensearch = Some(outcome(res));
+}
+
+/// Record the result of a request to a relation graph.
+pub(crate) fn relation_graph<T>(name: &str, res: &Result<T, Error>) {
+    HEALTH
+        .lock()
+        .unwrap()
+        .relation_graphs
+        .insert(name.to_string(), outcome(res));
+}
+
+/// For how long the running cycle, if any, has been running.
+pub(crate) fn cycle_running() -> Option<Duration> {
+    HEALTH
+        .lock()
+        .unwrap()
+        .cycle_start
+        .map(|start| start.elapsed())
+}
+
+/// Whether the daemon is live: no cycle has been running for longer
+/// than `stuck_after`.
+pub(crate) fn liveness(stuck_after: Duration) -> Result<(), String> {
+    match HEALTH.lock().unwrap().cycle_start {
+        Some(start) if start.elapsed() > stuck_after => Err(format!(
+            "discovery cycle running for {}s",
+            start.elapsed().as_secs()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the daemon is ready: started, and the last requests to
+/// Opensearch and the relation graphs succeeded.
+pub(crate) fn readiness() -> Result<(), String> {
+    let health = HEALTH.lock().unwrap();
+    if !health.started {
+        return Err(String::from("loading state and configuration"));
+    }
+    match &health.opensearch {
+        None => return Err(String::from("opensearch not queried yet")),
+        Some(Err(e)) => return Err(format!("opensearch: {e}")),
+        Some(Ok(())) => {}
+    }
+    health
+        .relation_graphs
+        .iter()
+        .try_for_each(|(name, res)| match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{name}: {e}")),
+        })
+}
+
+fn outcome<T>(res: &Result<T, Error>) -> Result<(), String> {
+    res.as_ref().map(|_| ()).map_err(ToString::to_string)
+}
@@ -0,0 +1,74 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Heap statistics of the jemalloc allocator, exposed on the metrics
+//! endpoint. Together with the estimated size of the trace cache,
+//! these show how the memory is divided between the trace cache and
+//! the service catalog.
+
+use std::sync::LazyLock;
+
+use prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec};
+use tikv_jemalloc_ctl::{epoch, stats};
+
+use crate::state::{State, TraceInfo};
+
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+static HEAP_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "jaeger_discovery_heap_bytes",
+        "Heap memory statistics of the allocator, by statistic.",
+        &["stat"]
+    )
+    .unwrap()
+});
+
+static TRACE_CACHE_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "jaeger_discovery_trace_cache_bytes",
+        "Estimated memory used by the traces kept in the state."
+    )
+    .unwrap()
+});
+
+/// Refresh the allocator statistics.
+pub(crate) fn update() {
+    if let Err(e) = epoch::advance() {
+        log::warn!("failed to refresh allocator statistics: {e}");
+        return;
+    }
+    let stats = [
+        ("allocated", stats::allocated::read()),
+        ("active", stats::active::read()),
+        ("metadata", stats::metadata::read()),
+        ("resident", stats::resident::read()),
+        ("mapped", stats::mapped::read()),
+        ("retained", stats::retained::read()),
+    ];
+    for (stat, value) in stats {
+        match value {
+            Ok(value) => HEAP_BYTES.with_label_values(&[stat]).set(value as i64),
+            Err(e) => log::warn!("failed to read allocator statistic {stat}: {e}"),
+        }
+    }
+}
+
+/// The number of bytes allocated, if available.
+pub(crate) fn allocated() -> Option<u64> {
+    epoch::advance().ok()?;
+    stats::allocated::read().ok().map(|bytes| bytes as u64)
+}
+
+/// Record the estimated size of the trace cache. The remainder of the
+/// allocated memory is mostly taken by the service catalog.
+pub(crate) fn record_traces(state: &State) {
+    let bytes = state
+        .traces
+        .values()
+        .map(TraceInfo::estimated_size)
+        .sum::<usize>();
+    TRACE_CACHE_BYTES.set(bytes as i64);
+}
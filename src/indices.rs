@@ -0,0 +1,123 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Resolution of the span indices covering the lookback window, so a
+//! short window is searched without fanning out over the shards of
+//! every span index.
+
+use std::{collections::BTreeMap, sync::LazyLock};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use regex::Regex;
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::Error;
+
+/// Prefix of the span indices written by Jaeger.
+const SPAN_INDEX_PREFIX: &str = "jaeger-span-";
+
+/// Pattern matching all span indices.
+pub(crate) const SPAN_INDEX_PATTERN: &str = "jaeger-span-*";
+
+/// Generation suffix of rollover and data stream backing indices,
+/// optionally preceded by their creation date.
+static GENERATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(.*?)(-\d{4}\.\d{2}\.\d{2})?-\d+$").unwrap());
+
+#[derive(Deserialize)]
+struct CatIndex {
+    index: String,
+    #[serde(rename = "creation.date")]
+    creation_date: String,
+}
+
+/// Resolve the indices holding spans starting at or after `start`, if
+/// the window spans at most `max_days` days. Returns the pattern of
+/// all span indices when the window is longer, or when the indices
+/// cannot be resolved.
+pub(crate) async fn resolve(
+    client: &Client,
+    url: &Url,
+    start: DateTime<Utc>,
+    max_days: u32,
+) -> String {
+    let days = (Utc::now().date_naive() - start.date_naive()).num_days() + 1;
+    if days > i64::from(max_days) {
+        return SPAN_INDEX_PATTERN.to_string();
+    }
+    match list(client, url).await {
+        Ok(indices) => {
+            let targets = covering(indices, start);
+            if targets.is_empty() {
+                return SPAN_INDEX_PATTERN.to_string();
+            }
+            log::debug!("searching indices {}", targets.join(","));
+            targets.join(",")
+        }
+        Err(e) => {
+            log::warn!("failed to resolve span indices: {e}");
+            SPAN_INDEX_PATTERN.to_string()
+        }
+    }
+}
+
+/// List the span indices (including data stream backing indices)
+/// with their creation time in milliseconds.
+async fn list(client: &Client, url: &Url) -> Result<Vec<(String, i64)>, Error> {
+    let indices = client
+        .get(url.join(&format!("_cat/indices/{SPAN_INDEX_PATTERN}"))?)
+        .query(&[("format", "json"), ("h", "index,creation.date")])
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())?
+        .json::<Vec<CatIndex>>()
+        .await?;
+    Ok(indices
+        .into_iter()
+        .filter_map(|index| Some((index.index, index.creation_date.parse().ok()?)))
+        .collect())
+}
+
+/// Select the indices that may hold spans starting at or after
+/// `start`. Daily indices are named after the day their spans
+/// started. Rollover and backing indices hold the spans written from
+/// their creation until that of the next generation, so the last one
+/// created before `start` is kept too.
+fn covering(indices: Vec<(String, i64)>, start: DateTime<Utc>) -> Vec<String> {
+    let start_day = start.date_naive();
+    let start_millis = start.timestamp_millis();
+    let mut targets = Vec::new();
+    let mut series = BTreeMap::<String, Vec<(i64, String)>>::new();
+    for (index, created) in indices {
+        let daily = index
+            .strip_prefix(SPAN_INDEX_PREFIX)
+            .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+        match daily {
+            Some(day) => {
+                if day >= start_day {
+                    targets.push(index);
+                }
+            }
+            None => {
+                let name = GENERATION
+                    .captures(&index)
+                    .map_or(index.as_str(), |m| m.get(1).unwrap().as_str())
+                    .to_string();
+                series.entry(name).or_default().push((created, index));
+            }
+        }
+    }
+    for mut generations in series.into_values() {
+        generations.sort();
+        let first = generations
+            .iter()
+            .rposition(|(created, _)| *created <= start_millis)
+            .unwrap_or(0);
+        targets.extend(generations.drain(first..).map(|(_, index)| index));
+    }
+    targets.sort();
+    targets
+}
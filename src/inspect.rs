@@ -0,0 +1,438 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Human-readable view of the state, for the `state show` command,
+//! and the summary logged on SIGUSR1.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+};
+
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use serde::Serialize;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{
+    health,
+    state::{
+        OperationName, OperationState, RelationState, RunRecord, ServiceKey, ServiceState, State,
+        TraceInfo,
+    },
+};
+
+/// Number of services listed in the state summary.
+const SUMMARY_SERVICES: usize = 10;
+
+/// Pending request for a state summary.
+pub(crate) static SUMMARY_REQUEST: LazyLock<SummaryRequest> = LazyLock::new(Default::default);
+
+/// A request for a state summary (on SIGUSR1). It is answered by the
+/// main loop when idle, or between batches while a cycle is running.
+#[derive(Default)]
+pub(crate) struct SummaryRequest {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl SummaryRequest {
+    pub(crate) fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+
+    /// Wait for a request. It may already have been answered by the
+    /// time this returns.
+    pub(crate) async fn requested(&self) {
+        self.notify.notified().await;
+    }
+
+    /// Take the pending request, if any.
+    pub(crate) fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// Log a summary of the state: its size, the services with the most
+/// operations and estimates of the memory it takes.
+pub(crate) fn log_summary(state: &State) {
+    let counts = state.counts();
+    let trace_bytes = state
+        .traces
+        .values()
+        .map(TraceInfo::estimated_size)
+        .sum::<usize>();
+    let mut services = state
+        .services
+        .iter()
+        .map(|(key, service)| (service.operations.len(), key))
+        .collect::<Vec<_>>();
+    services.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+    let largest_services = services
+        .iter()
+        .take(SUMMARY_SERVICES)
+        .map(|(n, key)| format!("{key} ({n})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    #[cfg(feature = "jemalloc")]
+    let heap_bytes = crate::heap::allocated();
+    #[cfg(not(feature = "jemalloc"))]
+    let heap_bytes = None::<u64>;
+
+    tracing::info!(
+        cycle_running_secs = health::cycle_running().map(|d| d.as_secs()),
+        traces = counts.traces,
+        spans = counts.spans,
+        services = counts.services,
+        operations = counts.operations,
+        relations = counts.relations,
+        trace_bytes,
+        heap_bytes,
+        largest_services,
+        "state summary"
+    );
+}
+
+/// Services, operations and relations in the state, with their ids
+/// and the time they were last seen. Entries are removed from the
+/// graph at `expires`.
+#[derive(Serialize)]
+pub(crate) struct StateView {
+    last_span: Option<DateTime<Utc>>,
+    traces: usize,
+    buffered: usize,
+    services: Vec<ServiceView>,
+    destinations: Vec<DestinationView>,
+    external_endpoints: Vec<ExternalView>,
+    history: Vec<RunRecord>,
+}
+
+#[derive(Serialize)]
+struct ServiceView {
+    service: String,
+    id: Uuid,
+    last_seen: Option<DateTime<Utc>>,
+    expires: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    instances: BTreeMap<String, DateTime<Utc>>,
+    relations: Vec<RelationView>,
+    produces: Vec<RelationView>,
+    operations: Vec<OperationView>,
+}
+
+#[derive(Serialize)]
+struct OperationView {
+    operation: String,
+    id: Uuid,
+    last_seen: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    relations: Vec<RelationView>,
+}
+
+#[derive(Serialize)]
+struct RelationView {
+    kind: &'static str,
+    target: String,
+    id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u64>,
+    last_seen: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+/// A service in the state, with the number of entries under it, for
+/// the `/admin/services` endpoint.
+#[derive(Serialize)]
+pub(crate) struct ServiceSummary {
+    service: String,
+    id: Uuid,
+    last_seen: Option<DateTime<Utc>>,
+    instances: usize,
+    operations: usize,
+    relations: usize,
+    links: usize,
+    produces: usize,
+}
+
+#[derive(Serialize)]
+struct DestinationView {
+    destination: String,
+    id: Uuid,
+    system: Option<String>,
+    last_seen: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExternalView {
+    endpoint: String,
+    id: Uuid,
+    last_seen: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    callers: Vec<RelationView>,
+}
+
+impl ServiceSummary {
+    /// Summarize the services in the state.
+    pub(crate) fn list(state: &State) -> Vec<Self> {
+        state
+            .services
+            .iter()
+            .map(|(key, service)| Self {
+                service: key.to_string(),
+                id: service.id,
+                last_seen: service.last_activity(),
+                instances: service.instances.len(),
+                operations: service.operations.len(),
+                relations: service.relations.len(),
+                links: service.links.len(),
+                produces: service.produces.len(),
+            })
+            .collect()
+    }
+}
+
+impl StateView {
+    pub(crate) fn new(state: &State, retention: TimeDelta) -> Self {
+        Self {
+            last_span: state.last_span,
+            traces: state.traces.len(),
+            buffered: state.buffered.len(),
+            services: state
+                .services
+                .iter()
+                .map(|(key, service)| ServiceView::new(key, service, retention))
+                .collect(),
+            destinations: state
+                .destinations
+                .iter()
+                .map(|(name, dest)| DestinationView {
+                    destination: name.to_string(),
+                    id: dest.id,
+                    system: dest.system.clone(),
+                    last_seen: dest.last_seen,
+                    expires: dest.last_seen + retention,
+                })
+                .collect(),
+            external_endpoints: state
+                .external_endpoints
+                .iter()
+                .map(|(host, endpoint)| ExternalView {
+                    endpoint: host.clone(),
+                    id: endpoint.id,
+                    last_seen: endpoint.last_seen,
+                    expires: endpoint.last_seen + retention,
+                    callers: endpoint
+                        .callers
+                        .iter()
+                        .map(|(key, rel)| {
+                            RelationView::new("called by", key.to_string(), rel, retention)
+                        })
+                        .collect(),
+                })
+                .collect(),
+            history: state.history.iter().cloned().collect(),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        let mut data = serde_json::to_vec_pretty(self).unwrap();
+        data.push(b'\n');
+        data
+    }
+
+    /// Render the state as an indented tree.
+    pub(crate) fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "last span: {}", opt_time(self.last_span)).unwrap();
+        writeln!(out, "traces: {} ({} buffered)", self.traces, self.buffered).unwrap();
+
+        for service in &self.services {
+            writeln!(
+                out,
+                "service {} [{}] last seen {}, expires {}",
+                service.service,
+                service.id,
+                opt_time(service.last_seen),
+                opt_time(service.expires)
+            )
+            .unwrap();
+            for (instance, last_seen) in &service.instances {
+                writeln!(out, "  instance {instance} last seen {}", time(*last_seen)).unwrap();
+            }
+            for rel in service.relations.iter().chain(&service.produces) {
+                rel.write(&mut out, 2);
+            }
+            for op in &service.operations {
+                writeln!(
+                    out,
+                    "  operation {} [{}] last seen {}, expires {}",
+                    op.operation,
+                    op.id,
+                    time(op.last_seen),
+                    time(op.expires)
+                )
+                .unwrap();
+                for rel in &op.relations {
+                    rel.write(&mut out, 4);
+                }
+            }
+        }
+
+        for dest in &self.destinations {
+            writeln!(
+                out,
+                "destination {} [{}]{} last seen {}, expires {}",
+                dest.destination,
+                dest.id,
+                dest.system
+                    .as_ref()
+                    .map(|s| format!(" ({s})"))
+                    .unwrap_or_default(),
+                time(dest.last_seen),
+                time(dest.expires)
+            )
+            .unwrap();
+        }
+
+        for endpoint in &self.external_endpoints {
+            writeln!(
+                out,
+                "external endpoint {} [{}] last seen {}, expires {}",
+                endpoint.endpoint,
+                endpoint.id,
+                time(endpoint.last_seen),
+                time(endpoint.expires)
+            )
+            .unwrap();
+            for rel in &endpoint.callers {
+                rel.write(&mut out, 2);
+            }
+        }
+
+        for run in &self.history {
+            write!(out, "run {} spans {}", time(run.time), run.spans).unwrap();
+            if let (Some(items), Some(relations)) = (run.items, run.relations) {
+                write!(out, ", items {items}, relations {relations}").unwrap();
+            }
+            match &run.error {
+                Some(error) => writeln!(out, ", failed: {error}").unwrap(),
+                None => writeln!(out).unwrap(),
+            }
+        }
+
+        out
+    }
+}
+
+impl ServiceView {
+    fn new(key: &ServiceKey, service: &ServiceState, retention: TimeDelta) -> Self {
+        Self {
+            service: key.to_string(),
+            id: service.id,
+            last_seen: service.last_seen,
+            expires: service.last_seen.map(|t| t + retention),
+            instances: service
+                .instances
+                .iter()
+                .map(|(id, t)| (id.to_string(), *t))
+                .collect(),
+            relations: service
+                .relations
+                .iter()
+                .map(|(target, rel)| RelationView::new("calls", target.to_string(), rel, retention))
+                .chain(service.links.iter().map(|(target, rel)| {
+                    RelationView::new("links to", target.to_string(), rel, retention)
+                }))
+                .collect(),
+            produces: service
+                .produces
+                .iter()
+                .map(|(dest, rel)| RelationView {
+                    kind: "produces to",
+                    target: dest.to_string(),
+                    id: rel.id,
+                    count: None,
+                    last_seen: rel.last_seen,
+                    expires: rel.last_seen + retention,
+                })
+                .collect(),
+            operations: service
+                .operations
+                .iter()
+                .map(|(name, op)| OperationView::new(name, op, retention))
+                .collect(),
+        }
+    }
+}
+
+impl OperationView {
+    fn new(name: &OperationName, op: &OperationState, retention: TimeDelta) -> Self {
+        let relations =
+            |kind, rels: &BTreeMap<ServiceKey, BTreeMap<OperationName, RelationState>>| {
+                rels.iter()
+                    .flat_map(move |(service, ops)| {
+                        ops.iter().map(move |(op, rel)| {
+                            RelationView::new(kind, format!("{service}: {op}"), rel, retention)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            };
+        Self {
+            operation: name.to_string(),
+            id: op.id,
+            last_seen: op.last_seen,
+            expires: op.last_seen + retention,
+            relations: relations("calls", &op.relations)
+                .into_iter()
+                .chain(relations("links to", &op.links))
+                .collect(),
+        }
+    }
+}
+
+impl RelationView {
+    fn new(kind: &'static str, target: String, rel: &RelationState, retention: TimeDelta) -> Self {
+        Self {
+            kind,
+            target,
+            id: rel.id,
+            count: Some(rel.count),
+            last_seen: rel.last_seen,
+            expires: rel.last_seen + retention,
+        }
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        write!(
+            out,
+            "{:indent$}{} {} [{}]",
+            "", self.kind, self.target, self.id
+        )
+        .unwrap();
+        if let Some(count) = self.count {
+            write!(out, " count {count},").unwrap();
+        }
+        writeln!(
+            out,
+            " last seen {}, expires {}",
+            time(self.last_seen),
+            time(self.expires)
+        )
+        .unwrap();
+    }
+}
+
+fn time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn opt_time(t: Option<DateTime<Utc>>) -> String {
+    t.map_or_else(|| String::from("never"), time)
+}
@@ -0,0 +1,96 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Interning of the names making up service and operation keys. These
+//! are repeated in every span and in many map keys of the state; with
+//! interning, equal names share one allocation and cloning them only
+//! increments a reference count.
+
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Display},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+static NAMES: LazyLock<Mutex<HashSet<Arc<str>>>> = LazyLock::new(Default::default);
+
+/// An interned string.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub(crate) struct Name(Arc<str>);
+
+impl Name {
+    pub(crate) fn new(s: &str) -> Self {
+        let mut names = NAMES.lock().unwrap();
+        match names.get(s) {
+            Some(name) => Self(name.clone()),
+            None => {
+                let name = Arc::<str>::from(s);
+                names.insert(name.clone());
+                Self(name)
+            }
+        }
+    }
+}
+
+/// Forget names that are no longer referenced outside the interner.
+/// Returns the number of names still interned.
+pub(crate) fn purge() -> usize {
+    let mut names = NAMES.lock().unwrap();
+    names.retain(|name| Arc::strong_count(name) > 1);
+    names.len()
+}
+
+impl From<&str> for Name {
+    fn from(s: &str) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<String> for Name {
+    fn from(s: String) -> Self {
+        Self::new(&s)
+    }
+}
+
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Debug for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Serialize for Name {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Name;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string")
+            }
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Name, E> {
+                Ok(Name::new(v))
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
@@ -0,0 +1,122 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Log output. Records from the `log` macros are forwarded to
+//! `tracing`, so that every line carries the context of the spans it
+//! was logged in (the discovery cycle, with its id and source, and the
+//! batch being processed). The level is set with `RUST_LOG`, as
+//! before. With the `otlp` feature, the spans can also be exported as
+//! traces of the discoverer itself.
+
+use std::io::IsTerminal;
+
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::Level;
+#[cfg(feature = "otlp")]
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{
+    filter::{filter_fn, FilterExt},
+    fmt,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+use crate::Args;
+
+/// Format of the log output.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum LogFormat {
+    /// Human-readable lines, with the span context as a prefix.
+    Text,
+    /// One json object per line, with the span context as fields.
+    Json,
+}
+
+/// Keeps the exporter of the discoverer's own traces, if any, running.
+/// The spans still buffered are exported when dropped.
+pub(crate) struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<SdkTracerProvider>,
+}
+
+/// Install the log subscriber. Logging is disabled below `error`
+/// unless configured otherwise in `RUST_LOG`. The spans of this crate
+/// are always enabled, so that the context is also shown on lines
+/// logged at a higher level than the spans themselves.
+pub(crate) fn init(args: &Args) -> Telemetry {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let spans = filter_fn(|meta| meta.is_span() && meta.target().starts_with("jaeger_discovery"))
+        .with_max_level_hint(Level::INFO);
+    let output = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let output = match args.log_format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(output.with_filter(filter.or(spans)));
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider = args.otlp_endpoint.as_ref().map(|url| {
+            SpanExporter::builder()
+                .with_http()
+                .with_endpoint(url.as_str())
+                .build()
+                .map(|exporter| {
+                    SdkTracerProvider::builder()
+                        .with_batch_exporter(exporter)
+                        .with_resource(
+                            Resource::builder()
+                                .with_service_name(env!("CARGO_PKG_NAME"))
+                                .build(),
+                        )
+                        .build()
+                })
+        });
+        let layer = match &provider {
+            Some(Ok(provider)) => Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+                    .with_filter(Targets::new().with_target("jaeger_discovery", Level::INFO)),
+            ),
+            _ => None,
+        };
+        registry.with(layer).init();
+        Telemetry {
+            provider: provider.and_then(|provider| {
+                provider
+                    .map_err(|e| log::warn!("failed to set up the OTLP exporter: {e}"))
+                    .ok()
+            }),
+        }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        Telemetry {}
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("failed to export the remaining spans: {e}");
+            }
+        }
+    }
+}
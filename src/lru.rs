@@ -0,0 +1,141 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Least-recently-used order of the traces in the trace map, with
+//! running totals of their spans and estimated size, so the trace
+//! limits are enforced without scanning or sorting the whole map.
+
+use std::collections::BTreeMap;
+
+use hashbrown::HashMap;
+
+use crate::state::{TraceId, TraceInfo};
+
+/// Limits on the traces kept in the state.
+pub(crate) struct TraceLimits {
+    pub(crate) traces: Option<usize>,
+    pub(crate) spans: Option<usize>,
+    pub(crate) bytes: Option<usize>,
+}
+
+pub(crate) struct TraceLru {
+    limits: TraceLimits,
+    /// Traces by the tick at which they were last updated.
+    order: BTreeMap<u64, TraceId>,
+    entries: HashMap<TraceId, Entry>,
+    /// Traces updated since the totals were last brought up to date.
+    dirty: Vec<TraceId>,
+    tick: u64,
+    spans: usize,
+    bytes: usize,
+}
+
+#[derive(Default)]
+struct Entry {
+    tick: u64,
+    spans: usize,
+    bytes: usize,
+    dirty: bool,
+}
+
+impl TraceLru {
+    /// Order the traces in the map by their last span, if any limit is
+    /// set.
+    pub(crate) fn new(limits: TraceLimits, traces: &HashMap<TraceId, TraceInfo>) -> Option<Self> {
+        if limits.traces.is_none() && limits.spans.is_none() && limits.bytes.is_none() {
+            return None;
+        }
+        let mut lru = Self {
+            limits,
+            order: BTreeMap::new(),
+            entries: HashMap::new(),
+            dirty: Vec::new(),
+            tick: 0,
+            spans: 0,
+            bytes: 0,
+        };
+        let mut ids = traces
+            .iter()
+            .map(|(id, info)| (info.last_seen, id))
+            .collect::<Vec<_>>();
+        ids.sort();
+        for (_, id) in ids {
+            lru.touch(id);
+        }
+        lru.update(traces);
+        Some(lru)
+    }
+
+    /// Mark a trace as the most recently used.
+    pub(crate) fn touch(&mut self, id: &TraceId) {
+        self.tick += 1;
+        let entry = self.entries.entry_ref(id).or_default();
+        let id = match entry.tick {
+            0 => id.clone(),
+            tick => self.order.remove(&tick).unwrap(),
+        };
+        entry.tick = self.tick;
+        if !entry.dirty {
+            entry.dirty = true;
+            self.dirty.push(id.clone());
+        }
+        self.order.insert(self.tick, id);
+    }
+
+    /// Stop tracking a trace removed from the map.
+    pub(crate) fn remove(&mut self, id: &TraceId) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.order.remove(&entry.tick);
+            self.spans -= entry.spans;
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    /// Evict the least recently used traces while the trace map
+    /// exceeds a limit, returning the traces evicted.
+    pub(crate) fn evict(&mut self, traces: &mut HashMap<TraceId, TraceInfo>) -> Vec<TraceInfo> {
+        self.update(traces);
+        let mut evicted = Vec::new();
+        while self.exceeded() {
+            let Some((_, id)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&id) {
+                self.spans -= entry.spans;
+                self.bytes -= entry.bytes;
+            }
+            evicted.extend(traces.remove(&id));
+        }
+        evicted
+    }
+
+    /// Bring the totals up to date with the traces updated since.
+    fn update(&mut self, traces: &HashMap<TraceId, TraceInfo>) {
+        for id in std::mem::take(&mut self.dirty) {
+            let Some(info) = traces.get(&id) else {
+                self.remove(&id);
+                continue;
+            };
+            if let Some(entry) = self.entries.get_mut(&id) {
+                let bytes = match self.limits.bytes {
+                    Some(_) => info.estimated_size(),
+                    None => 0,
+                };
+                self.spans = self.spans - entry.spans + info.spans.len();
+                self.bytes = self.bytes - entry.bytes + bytes;
+                entry.spans = info.spans.len();
+                entry.bytes = bytes;
+                entry.dirty = false;
+            }
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.limits
+            .traces
+            .is_some_and(|max| self.entries.len() > max)
+            || self.limits.spans.is_some_and(|max| self.spans > max)
+            || self.limits.bytes.is_some_and(|max| self.bytes > max)
+    }
+}
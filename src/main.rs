@@ -2,49 +2,549 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+mod config;
+mod delta;
+mod diff;
 mod discovery;
 mod error;
+mod export;
+mod fetch;
+mod fold;
+mod graph;
+mod health;
+#[cfg(feature = "jemalloc")]
+mod heap;
+mod indices;
+mod inspect;
+mod intern;
+mod logging;
+mod lru;
+mod merge;
+mod migrate;
+mod preflight;
 mod query;
+mod ratelimit;
+mod rename;
+mod retry;
+mod rules;
+mod schedule;
+mod schema;
+mod semconv;
+mod server;
+mod sink;
+#[cfg(feature = "sled")]
+mod spill;
 mod state;
+mod status;
+mod store;
+mod summary;
+mod wal;
 
 use std::{
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
     process::ExitCode,
-    time::Duration,
+    sync::{Arc, RwLock},
 };
 
-use clap::Parser;
-use discovery::Discovery;
-use flate2::{read::GzDecoder, Compression};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use diff::LiveDiff;
+use discovery::{Discovery, Granularity, Roots, RETENTION};
+use export::{ExportFormat, MermaidOptions};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use inspect::{ServiceSummary, StateView, SUMMARY_REQUEST};
+use logging::LogFormat;
 use reqwest::{Certificate, Identity};
+use schedule::Schedule;
 use serde::{de::DeserializeOwned, Serialize};
+use sink::RelationGraphSink;
+use store::{StateBackend, StateCompression, StateFormat, StateLock, StateStore};
+use tokio::{sync::Notify, time::Instant};
 use url::Url;
 
 use crate::error::Error;
 
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(long)]
-    es_url: Url,
-    #[clap(long)]
-    es_ca: PathBuf,
-    #[clap(long)]
-    es_cert: PathBuf,
-    #[clap(long)]
-    es_key: PathBuf,
-    #[clap(long)]
-    rg_url: Url,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(long, required = true)]
+    es_url: Option<Url>,
+    #[clap(long, required = true)]
+    es_ca: Option<PathBuf>,
+    #[clap(long, required = true)]
+    es_cert: Option<PathBuf>,
+    #[clap(long, required = true)]
+    es_key: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "do not request gzip-compressed responses from Opensearch"
+    )]
+    es_no_gzip: bool,
+    #[clap(
+        long,
+        help = "use HTTP/1.1 for Opensearch, instead of negotiating HTTP/2 where supported"
+    )]
+    es_http1: bool,
+    #[clap(
+        long,
+        help = "maximum number of idle connections kept open per Opensearch host (default: unlimited)"
+    )]
+    es_pool_max_idle: Option<usize>,
+    #[clap(
+        long,
+        default_value = "90",
+        help = "period in seconds idle Opensearch connections are kept open"
+    )]
+    es_pool_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "interval in seconds of TCP keep-alive probes on Opensearch connections"
+    )]
+    es_tcp_keepalive: Option<u64>,
+    #[clap(
+        long,
+        help = "relation graph url (required unless configured per sink)"
+    )]
+    rg_url: Option<Url>,
     #[clap(long, short, default_value = "60", help = "interval in seconds")]
     interval: u64,
-    #[clap(long, short)]
-    state: PathBuf,
+    #[clap(
+        long,
+        help = "stretch the interval up to the given number of seconds while cycles take longer than the interval"
+    )]
+    max_interval: Option<u64>,
+    #[cfg_attr(
+        not(feature = "kubernetes"),
+        clap(required_unless_present = "no_state")
+    )]
+    #[cfg_attr(
+        feature = "kubernetes",
+        clap(required_unless_present_any = ["no_state", "state_configmap"])
+    )]
+    #[clap(long, short, help = "state directory")]
+    state: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "keep the state in memory only, rebuilding the graph from the lookback window on every run"
+    )]
+    no_state: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "file",
+        help = "backend used to persist the state"
+    )]
+    state_backend: StateBackend,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "gzip",
+        help = "compression of the state file (file backend)"
+    )]
+    state_compression: StateCompression,
+    #[clap(long, help = "compression level of the state file")]
+    state_compression_level: Option<i32>,
+    #[clap(
+        long,
+        help = "object store url for the state (e.g. s3://bucket/prefix; object-store backend)"
+    )]
+    state_url: Option<Url>,
+    #[cfg(feature = "kubernetes")]
+    #[clap(
+        long,
+        help = "name of the ConfigMap holding the state (configmap backend)"
+    )]
+    state_configmap: Option<String>,
+    #[cfg(feature = "kubernetes")]
+    #[clap(
+        long,
+        help = "namespace of the state ConfigMap (default: the pod's namespace)"
+    )]
+    state_namespace: Option<String>,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "number of previous state files to keep, to recover from if the state cannot be loaded (file backend)"
+    )]
+    state_backups: usize,
+    #[cfg(feature = "encryption")]
+    #[clap(
+        long,
+        env = "JAEGER_DISCOVERY_STATE_KEY",
+        hide_env_values = true,
+        help = "hex-encoded 256-bit key to encrypt the state with (file and object-store backends)"
+    )]
+    state_key: Option<String>,
+    #[cfg(feature = "encryption")]
+    #[clap(
+        long,
+        help = "file holding the key to encrypt the state with, instead of --state-key"
+    )]
+    state_key_file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "operation",
+        help = "level of detail of the discovered topology"
+    )]
+    granularity: Granularity,
+    #[clap(long, help = "publish FaaS workloads as jaeger/function items")]
+    function_items: bool,
+    #[clap(long, help = "merge instances of a service into one item")]
+    merge_instances: bool,
+    #[clap(
+        long,
+        default_value = "3600",
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "period in seconds an instance is considered active after its last span"
+    )]
+    instance_window: i64,
+    #[clap(
+        long,
+        default_value = "0",
+        value_parser = clap::value_parser!(i64).range(0..),
+        help = "period in seconds to keep items marked as stale after the retention period"
+    )]
+    stale_grace: i64,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i64).range(0..),
+        help = "period in seconds after which relations observed within a single hour only are removed"
+    )]
+    one_off_retention: Option<i64>,
+    #[clap(
+        long,
+        default_value = "300",
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "period in seconds before the last span to keep traces for finding relations between their spans"
+    )]
+    trace_retention: i64,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "period in seconds after which traces no longer updated are removed, even if no newer spans are seen"
+    )]
+    trace_max_age: Option<i64>,
+    #[cfg(feature = "sled")]
+    #[clap(
+        long,
+        help = "number of traces kept in memory; older traces are moved to disk"
+    )]
+    spill_traces: Option<usize>,
+    #[clap(long, help = "maximum number of traces kept in the state")]
+    max_traces: Option<usize>,
+    #[clap(long, help = "maximum number of spans kept in the state")]
+    max_spans: Option<usize>,
+    #[clap(
+        long,
+        help = "maximum (estimated) size in bytes of the traces kept in the state"
+    )]
+    max_trace_bytes: Option<usize>,
+    #[clap(
+        long,
+        default_value = "20",
+        help = "number of cycles kept in the run history"
+    )]
+    run_history: usize,
+    #[clap(
+        long,
+        help = "derive item and relation ids from their keys, so that states can be merged"
+    )]
+    deterministic_ids: bool,
+    #[clap(
+        long,
+        help = "save the state every given number of span batches (of 1000 spans) during a cycle"
+    )]
+    checkpoint_batches: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        conflicts_with = "quiescence",
+        help = "number of threads tracing the spans of a batch in parallel, partitioned by trace id"
+    )]
+    fold_threads: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        help = "maximum number of spans processed in a cycle; the rest is processed in the next cycles"
+    )]
+    max_cycle_spans: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "publish the graph every given number of seconds during long cycles (e.g. a backfill)"
+    )]
+    interim_push: Option<i64>,
+    #[clap(
+        long,
+        help = "log processed span batches to a write-ahead log in the state directory, replayed after a crash"
+    )]
+    span_log: bool,
+    #[clap(
+        long,
+        default_value = "1",
+        help = "number of slices of the span query, fetched concurrently"
+    )]
+    query_slices: NonZeroU32,
+    #[clap(
+        long,
+        default_value = "1000",
+        help = "initial number of spans fetched per request; adapted to response times and sizes"
+    )]
+    batch_size: NonZeroU64,
+    #[clap(
+        long,
+        default_value = "100",
+        help = "minimum number of spans fetched per request"
+    )]
+    min_batch_size: NonZeroU64,
+    #[clap(
+        long,
+        default_value = "10000",
+        help = "maximum number of spans fetched per request"
+    )]
+    max_batch_size: NonZeroU64,
+    #[clap(
+        long,
+        help = "read only the doc values of the span fields needed for operation-level discovery"
+    )]
+    docvalue_fields: bool,
+    #[clap(
+        long,
+        help = "search only the span indices covering the window to fetch when it spans at most the given number of days"
+    )]
+    target_indices: Option<u32>,
+    #[clap(
+        long,
+        help = "scan only the spans of services with new operations, found by a cheap aggregation, and refresh the others hourly"
+    )]
+    preflight: bool,
+    #[clap(
+        long,
+        help = "number of worker threads of the runtime (default: the number of cpus)"
+    )]
+    worker_threads: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        help = "save the trace cache to a separate file, every given number of cycles (file backend)"
+    )]
+    trace_cache_interval: Option<NonZeroU32>,
+    #[clap(long, help = "log graph changes as a structured (json) event")]
+    structured_diff: bool,
+    #[clap(
+        long,
+        help = "publish a jaeger/discovery item with metadata on the last discovery run"
+    )]
+    discovery_item: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "none",
+        help = "items published as domain roots"
+    )]
+    roots: Roots,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i64).range(0..),
+        help = "buffer spans until their trace has been idle for the given number of seconds"
+    )]
+    quiescence: Option<i64>,
+    #[clap(
+        long,
+        help = "send only changes since the previous push to the relation graph"
+    )]
+    delta_updates: bool,
+    #[clap(
+        long,
+        help = "maximum number of items and relations per relation graph request"
+    )]
+    chunk_size: Option<usize>,
+    #[clap(long, help = "gzip-compress requests to the relation graph")]
+    gzip_requests: bool,
+    #[clap(
+        long,
+        help = "send conditional relation graph updates (If-Match) to detect concurrent changes"
+    )]
+    conditional_updates: bool,
+    #[clap(
+        long,
+        help = "delete removed items and relations explicitly (for additive relation graph updates)"
+    )]
+    explicit_deletes: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["delta_updates", "chunk_size"],
+        help = "push items, operations and relations as separate domain-scoped requests"
+    )]
+    partitioned_pushes: bool,
+    #[clap(
+        long,
+        help = "publish the graph from a background task, without waiting for it in the next cycle"
+    )]
+    background_push: bool,
+    #[clap(
+        long,
+        conflicts_with = "background_push",
+        help = "push each graph while the next cycle scans for spans, reporting failures in that cycle"
+    )]
+    overlap_push: bool,
+    #[clap(
+        long,
+        default_value = "3",
+        help = "number of retries for failed relation graph requests"
+    )]
+    push_retries: u32,
+    #[clap(
+        long,
+        default_value = "1",
+        help = "initial backoff in seconds between relation graph retries"
+    )]
+    push_backoff: u64,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "maximum backoff in seconds between relation graph retries"
+    )]
+    push_max_backoff: u64,
+    #[clap(
+        long,
+        value_parser = parse_rate,
+        help = "maximum rate of relation graph write requests per second (default: unlimited)"
+    )]
+    push_rate: Option<f64>,
+    #[clap(
+        long,
+        default_value = "1",
+        requires = "push_rate",
+        help = "number of relation graph write requests allowed in a burst above --push-rate"
+    )]
+    push_burst: u32,
+    #[clap(
+        long,
+        help = "address to serve metrics and health probes on (e.g. 0.0.0.0:9090)"
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        default_value = "3600",
+        help = "period in seconds after which a running cycle fails the liveness probe"
+    )]
+    stuck_timeout: u64,
+    #[clap(
+        long,
+        env = "JAEGER_DISCOVERY_ADMIN_TOKEN",
+        hide_env_values = true,
+        help = "bearer token enabling the admin api on the metrics address"
+    )]
+    admin_token: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "format of the log output"
+    )]
+    log_format: LogFormat,
+    #[cfg(feature = "otlp")]
+    #[clap(
+        long,
+        help = "OTLP/HTTP endpoint to export traces of the discovery cycles to \
+                (e.g. http://collector:4318/v1/traces)"
+    )]
+    otlp_endpoint: Option<Url>,
+    #[clap(long, help = "configuration file (json)")]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "validate the payload against a json schema before pushing"
+    )]
+    validate: bool,
+    #[clap(
+        long,
+        requires = "validate",
+        help = "json schema to validate against instead of the bundled one"
+    )]
+    schema: Option<PathBuf>,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> ExitCode {
-    env_logger::init();
+/// Commands operating on the state, without querying spans. Without a
+/// command, discovery is run as a daemon.
+#[derive(Subcommand)]
+enum Command {
+    /// Render the discovered graph for visualization.
+    Export {
+        #[clap(long, short, value_enum, default_value = "dot")]
+        format: ExportFormat,
+        #[clap(long, short, help = "output file (default: stdout)")]
+        output: Option<PathBuf>,
+        #[clap(flatten)]
+        mermaid: MermaidOptions,
+    },
+    /// Compare the graph in the relation graph (at --rg-url) with the
+    /// graph discovery would push, without writing anything.
+    Diff,
+    /// Inspect the state.
+    State {
+        #[clap(subcommand)]
+        command: StateCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    /// Print services, operations and relations in the state, with
+    /// their ids and the time they were last seen.
+    Show {
+        #[clap(long, help = "print json instead of a tree")]
+        json: bool,
+    },
+    /// Write the state to a file, e.g. to move it to another
+    /// environment or backend.
+    Export {
+        #[clap(long, short, help = "output file (default: stdout)")]
+        output: Option<PathBuf>,
+        #[clap(
+            long,
+            short,
+            value_enum,
+            help = "output format (default: by file extension, or json)"
+        )]
+        format: Option<StateFormat>,
+    },
+    /// Remove expired traces, services, operations and relations
+    /// from the state and rewrite it.
+    Compact,
+    /// Replace the state with one read from a file written by `state
+    /// export` or by the file backend.
+    Import {
+        input: PathBuf,
+        #[clap(long, help = "replace an existing state")]
+        force: bool,
+    },
+    /// Merge states written by other instances, e.g. shards scanning
+    /// a subset of the indices, into the state.
+    Merge {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[clap(long, help = "push the merged graph to the configured sinks")]
+        push: bool,
+    },
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
-    if let Err(e) = run(&args).await {
+    let _telemetry = logging::init(&args);
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = args.worker_threads {
+        runtime.worker_threads(threads.get());
+    }
+    let res = match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(&args)),
+        Err(e) => Err(Error::Runtime(e)),
+    };
+    if let Err(e) = res {
         log::error!("{e}");
         ExitCode::FAILURE
     } else {
@@ -53,33 +553,198 @@ async fn main() -> ExitCode {
 }
 
 async fn run(args: &Args) -> Result<(), Error> {
+    match &args.command {
+        None => run_daemon(args).await,
+        Some(Command::Export {
+            format,
+            output,
+            mermaid,
+        }) => {
+            let discovery = Discovery::load(args).await?;
+            let items = discovery.build(Utc::now(), RETENTION);
+            let data = format.render(&discovery.state, &items.items, mermaid)?;
+            match output {
+                Some(path) => tokio::fs::write(path, data)
+                    .await
+                    .map_err(|e| Error::WriteFile(path.clone(), e)),
+                None => std::io::stdout()
+                    .write_all(&data)
+                    .map_err(|e| Error::WriteFile(PathBuf::from("-"), e)),
+            }
+        }
+        Some(Command::Diff) => {
+            let discovery = Discovery::load(args).await?;
+            let items = discovery.payload(Utc::now(), RETENTION);
+            let remote = RelationGraphSink::new(args, None, None, discovery.renames().clone())?
+                .fetch_world()
+                .await?;
+            let diff = LiveDiff::new(&remote, &items, discovery.renames());
+            std::io::stdout()
+                .write_all(diff.render().as_bytes())
+                .map_err(|e| Error::WriteFile(PathBuf::from("-"), e))
+        }
+        Some(Command::State {
+            command: StateCommand::Show { json },
+        }) => {
+            let discovery = Discovery::load(args).await?;
+            let view = StateView::new(&discovery.state, RETENTION);
+            let data = match json {
+                true => view.to_json(),
+                false => view.to_text().into_bytes(),
+            };
+            std::io::stdout()
+                .write_all(&data)
+                .map_err(|e| Error::WriteFile(PathBuf::from("-"), e))
+        }
+        Some(Command::State {
+            command: StateCommand::Export { output, format },
+        }) => {
+            let state = StateStore::open(args)?.load().await?;
+            let format = format.unwrap_or_else(|| {
+                output
+                    .as_deref()
+                    .map_or(StateFormat::Json, StateFormat::from_path)
+            });
+            let data = format.encode(&state);
+            match output {
+                Some(path) => tokio::fs::write(path, data)
+                    .await
+                    .map_err(|e| Error::WriteFile(path.clone(), e)),
+                None => std::io::stdout()
+                    .write_all(&data)
+                    .map_err(|e| Error::WriteFile(PathBuf::from("-"), e)),
+            }
+        }
+        Some(Command::State {
+            command: StateCommand::Compact,
+        }) => {
+            let _lock = StateLock::acquire(args)?;
+            Discovery::load(args).await?.compact().await
+        }
+        Some(Command::State {
+            command: StateCommand::Merge { inputs, push },
+        }) => {
+            let _lock = StateLock::acquire(args)?;
+            Discovery::load(args)
+                .await?
+                .merge(args, inputs, *push)
+                .await
+        }
+        Some(Command::State {
+            command: StateCommand::Import { input, force },
+        }) => {
+            let data = tokio::fs::read(input)
+                .await
+                .map_err(|e| Error::ReadFile(input.clone(), e))?;
+            let state = StateFormat::decode(&data, input)?;
+            let _lock = StateLock::acquire(args)?;
+            let mut store = StateStore::open(args)?;
+            if !force && !store.load().await?.services.is_empty() {
+                return Err(Error::StateExists);
+            }
+            store.save(&state).await?;
+            log::info!(
+                "imported {} services from {}",
+                state.services.len(),
+                input.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn run_daemon(args: &Args) -> Result<(), Error> {
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .map_err(Error::Signal)?;
     let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
         .map_err(Error::Signal)?;
-    let mut interval = tokio::time::interval(Duration::from_secs(args.interval));
-    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut sigusr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+        .map_err(Error::Signal)?;
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            SUMMARY_REQUEST.request();
+        }
+    });
+    let mut schedule = Schedule::new(args.interval, args.max_interval)?;
+
+    /* Serve the probes while loading, so a long startup is not
+     * mistaken for a dead process. */
+    let ctx = Arc::new(server::Context {
+        stuck_after: std::time::Duration::from_secs(args.stuck_timeout),
+        admin_token: args.admin_token.clone(),
+        discover: Notify::new(),
+        published: RwLock::new(None),
+    });
+    if let Some(addr) = args.metrics_addr {
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(addr, ctx).await {
+                log::error!("{e}");
+            }
+        });
+    }
+
+    let _lock = StateLock::acquire(args)?;
     let mut discovery = Discovery::new(args).await?;
+    health::started();
 
     loop {
         tokio::select! {
-            _ = interval.tick() => {}
+            _ = schedule.tick() => {}
+            _ = ctx.discover.notified() => {}
+            _ = SUMMARY_REQUEST.requested() => {
+                if SUMMARY_REQUEST.take() {
+                    inspect::log_summary(&discovery.state);
+                }
+                continue;
+            }
             _ = sigterm.recv() => {
                 log::info!("caught SIGTERM; shutting down...");
+                discovery.shutdown().await;
                 return Ok(())
             }
             _ = sigint.recv() => {
                 log::info!("caught SIGINT; shutting down...");
+                discovery.shutdown().await;
                 return Ok(())
             }
         }
 
+        let start = Instant::now();
+        health::cycle_started();
         if let Err(e) = discovery.discover().await {
             log::warn!("discovery failed: {e}");
         }
+        health::cycle_finished();
+        if let Some(items) = discovery.latest() {
+            *ctx.published.write().unwrap() = Some(server::Published {
+                items,
+                services: ServiceSummary::list(&discovery.state),
+            });
+        }
+        schedule.finished(start);
     }
 }
 
+/// Parse a request rate: a positive, finite number of requests per
+/// second, low enough that the wait between two requests fits in a
+/// `Duration`.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if !(rate > 0.0 && rate.is_finite()) => Err(String::from("rate must be positive")),
+        Ok(rate) if std::time::Duration::try_from_secs_f64(1.0 / rate).is_err() => {
+            Err(String::from("rate is too low"))
+        }
+        Ok(rate) => Ok(rate),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Get an argument that is required when running as a daemon.
+fn required<'a, T>(arg: &'a Option<T>, name: &'static str) -> Result<&'a T, Error> {
+    arg.as_ref().ok_or(Error::MissingArgument(name))
+}
+
 async fn load_cert(path: &Path) -> Result<Certificate, Error> {
     let data = tokio::fs::read(path)
         .await
@@ -106,14 +771,21 @@ async fn load_json<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
         .map_err(|e| Error::Deserialize(path.to_path_buf(), e))
 }
 
-async fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
-    let mut data = Vec::new();
-    serde_json::to_writer(
-        flate2::write::GzEncoder::new(&mut data, Compression::fast()),
-        value,
-    )
-    .unwrap();
-    tokio::fs::write(path, &data)
+async fn load_config<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let data = tokio::fs::read(path)
         .await
-        .map_err(|e| Error::WriteFile(path.to_path_buf(), e))
+        .map_err(|e| Error::ReadFile(path.to_path_buf(), e))?;
+    serde_json::from_slice(&data).map_err(|e| Error::Deserialize(path.to_path_buf(), e))
+}
+
+/// Write a gzip-compressed json file, encoding the value while writing
+/// instead of compressing it into memory first.
+async fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    tokio::task::block_in_place(|| {
+        let file = std::fs::File::create(path)?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        serde_json::to_writer(&mut encoder, value)?;
+        encoder.finish()?.flush()
+    })
+    .map_err(|e| Error::WriteFile(path.to_path_buf(), e))
 }
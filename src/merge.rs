@@ -0,0 +1,205 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Merging of states written by discovery shards, each scanning a
+//! subset of the span indices, into one world.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::state::{
+    CustomItemState, CustomRelationState, DestinationState, ExternalEndpointState,
+    HttpStatusCounts, OperationState, ProducesState, RelationState, ServiceState, State,
+};
+
+/// Namespace for ids derived from item and relation keys.
+const ID_NAMESPACE: Uuid = Uuid::from_u128(0x5d1c_6a2e_93f4_4b0a_8e57_1f2d_c3a4_b6e9);
+
+/// Combine another copy of a state entry into this one. Entries are
+/// matched by key; for conflicting values the most recently seen one
+/// wins, timestamps and counts take the maximum. Merging is
+/// idempotent, so shard states can be merged again every cycle.
+pub(crate) trait Merge {
+    fn merge_from(&mut self, other: Self);
+}
+
+impl State {
+    /// Merge the topology of another state into this one. Traces in
+    /// progress and the run history are not merged.
+    pub(crate) fn merge(&mut self, other: State) {
+        self.services.merge_from(other.services);
+        self.destinations.merge_from(other.destinations);
+        self.external_endpoints.merge_from(other.external_endpoints);
+        self.custom_items.merge_from(other.custom_items);
+        self.last_span = self.last_span.max(other.last_span);
+    }
+
+    /// Replace the ids of all items and relations by ids derived from
+    /// their keys, so shards discovering the same entity agree on its
+    /// id.
+    pub(crate) fn assign_ids(&mut self) {
+        for (svc_key, svc_state) in &mut self.services {
+            let svc = svc_key.to_string();
+            svc_state.id = derive_id(&["service", &svc]);
+            for (target, rel) in &mut svc_state.relations {
+                rel.id = derive_id(&["calls", &svc, &target.to_string()]);
+            }
+            for (target, rel) in &mut svc_state.links {
+                rel.id = derive_id(&["links", &svc, &target.to_string()]);
+            }
+            for (dest, rel) in &mut svc_state.produces {
+                rel.id = derive_id(&["produces", &svc, &dest.to_string()]);
+            }
+            for (op_name, op_state) in &mut svc_state.operations {
+                let op = op_name.to_string();
+                op_state.id = derive_id(&["operation", &svc, &op]);
+                for (kind, rels) in [
+                    ("calls", &mut op_state.relations),
+                    ("links", &mut op_state.links),
+                ] {
+                    for (target_svc, ops) in rels {
+                        let target_svc = target_svc.to_string();
+                        for (target_op, rel) in ops {
+                            rel.id =
+                                derive_id(&[kind, &svc, &op, &target_svc, &target_op.to_string()]);
+                        }
+                    }
+                }
+            }
+        }
+        for (dest, dest_state) in &mut self.destinations {
+            dest_state.id = derive_id(&["destination", &dest.to_string()]);
+        }
+        for (host, endpoint) in &mut self.external_endpoints {
+            endpoint.id = derive_id(&["external", host]);
+            for (caller, rel) in &mut endpoint.callers {
+                rel.id = derive_id(&["external calls", host, &caller.to_string()]);
+            }
+        }
+        for (key, item) in &mut self.custom_items {
+            item.id = derive_id(&["custom", key]);
+            for (svc_key, rel) in &mut item.relations {
+                rel.id = derive_id(&["custom relation", key, &svc_key.to_string()]);
+            }
+        }
+    }
+}
+
+fn derive_id(parts: &[&str]) -> Uuid {
+    Uuid::new_v5(&ID_NAMESPACE, parts.join("\x1f").as_bytes())
+}
+
+impl<K: Ord, V: Merge> Merge for BTreeMap<K, V> {
+    fn merge_from(&mut self, other: Self) {
+        for (key, value) in other {
+            match self.entry(key) {
+                Entry::Vacant(ent) => {
+                    ent.insert(value);
+                }
+                Entry::Occupied(mut ent) => ent.get_mut().merge_from(value),
+            }
+        }
+    }
+}
+
+impl Merge for DateTime<Utc> {
+    fn merge_from(&mut self, other: Self) {
+        *self = (*self).max(other);
+    }
+}
+
+impl Merge for u64 {
+    fn merge_from(&mut self, other: Self) {
+        *self = (*self).max(other);
+    }
+}
+
+impl Merge for ServiceState {
+    fn merge_from(&mut self, other: Self) {
+        if other.last_activity() > self.last_activity() {
+            self.meta = other.meta;
+        }
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.instances.merge_from(other.instances);
+        self.relations.merge_from(other.relations);
+        self.links.merge_from(other.links);
+        self.produces.merge_from(other.produces);
+        self.operations.merge_from(other.operations);
+    }
+}
+
+impl Merge for OperationState {
+    fn merge_from(&mut self, other: Self) {
+        self.relations.merge_from(other.relations);
+        self.links.merge_from(other.links);
+        self.span_kinds.merge_from(other.span_kinds);
+        self.last_seen.merge_from(other.last_seen);
+    }
+}
+
+impl Merge for RelationState {
+    fn merge_from(&mut self, other: Self) {
+        self.last_seen.merge_from(other.last_seen);
+        self.count.merge_from(other.count);
+        self.http_status.merge_from(other.http_status);
+        self.hourly.merge_from(other.hourly);
+    }
+}
+
+impl Merge for HttpStatusCounts {
+    fn merge_from(&mut self, other: Self) {
+        self.informational.merge_from(other.informational);
+        self.success.merge_from(other.success);
+        self.redirection.merge_from(other.redirection);
+        self.client_error.merge_from(other.client_error);
+        self.server_error.merge_from(other.server_error);
+    }
+}
+
+impl Merge for DestinationState {
+    fn merge_from(&mut self, other: Self) {
+        if other.last_seen > self.last_seen && other.system.is_some() {
+            self.system = other.system;
+        }
+        self.last_seen.merge_from(other.last_seen);
+    }
+}
+
+impl Merge for ProducesState {
+    fn merge_from(&mut self, other: Self) {
+        self.last_seen.merge_from(other.last_seen);
+        self.consumer_last_seen = self.consumer_last_seen.max(other.consumer_last_seen);
+    }
+}
+
+impl Merge for ExternalEndpointState {
+    fn merge_from(&mut self, other: Self) {
+        self.last_seen.merge_from(other.last_seen);
+        self.paths.extend(other.paths);
+        self.callers.merge_from(other.callers);
+    }
+}
+
+impl Merge for CustomItemState {
+    fn merge_from(&mut self, other: Self) {
+        if other.last_seen > self.last_seen {
+            self.item_type = other.item_type;
+            self.properties = other.properties;
+            self.last_seen = other.last_seen;
+        }
+        self.relations.merge_from(other.relations);
+    }
+}
+
+impl Merge for CustomRelationState {
+    fn merge_from(&mut self, other: Self) {
+        if other.last_seen > self.last_seen {
+            self.relation_type = other.relation_type;
+            self.properties = other.properties;
+            self.last_seen = other.last_seen;
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Migration of state written by older versions.
+//!
+//! The state carries a schema version. When the state format changes
+//! in a way serde defaults cannot absorb (e.g. a renamed field), the
+//! version is increased and a migration is added, transforming the
+//! json representation of the previous version. Migrations are
+//! applied in sequence on load, so ids are preserved and the relation
+//! graph is not churned.
+
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::{error::Error, state::State};
+
+/// Migrations by source version: `MIGRATIONS[n]` transforms state of
+/// version `n` into version `n + 1`.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    /* 0 -> 1: state written before versioning was introduced. */
+    |_| {},
+];
+
+/// The current state schema version.
+pub(crate) const STATE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Migrate state to the current version and deserialize it. `path` is
+/// used in error messages only.
+pub(crate) fn load_state(mut value: Value, path: &Path) -> Result<State, Error> {
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .map_or(0, |v| v as u32);
+    if version > STATE_VERSION {
+        return Err(Error::StateVersion(version, STATE_VERSION));
+    }
+
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("migrating state from version {from} to {}", from + 1);
+        migrate(&mut value);
+    }
+    if let Value::Object(fields) = &mut value {
+        fields.insert(String::from("version"), Value::from(STATE_VERSION));
+    }
+
+    serde_json::from_value(value).map_err(|e| Error::Deserialize(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::state::{QueryPosition, SpanId};
+
+    #[test]
+    fn migrates_unversioned_state() {
+        let value = json!({
+            "traces": {},
+            "services": {},
+            "last_span": null,
+            "slices": [5, null, [7, "x"]],
+        });
+        let state = load_state(value, Path::new("state.json")).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(
+            state.slices,
+            Some(vec![
+                Some(QueryPosition::after(5)),
+                None,
+                Some(QueryPosition(7, SpanId(String::from("x")))),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_newer_state() {
+        let value = json!({ "version": STATE_VERSION + 1 });
+        assert!(matches!(
+            load_state(value, Path::new("state.json")),
+            Err(Error::StateVersion(version, STATE_VERSION)) if version == STATE_VERSION + 1
+        ));
+    }
+}
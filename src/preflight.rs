@@ -0,0 +1,112 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Pre-flight aggregation of the services and operations seen in the
+//! window to fetch, used to skip the span scan for services without
+//! new operations.
+
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::error::Error;
+
+/// Maximum number of buckets requested per aggregation level.
+const MAX_BUCKETS: usize = 10000;
+
+/// A service seen in the window, with its operations.
+pub(crate) struct ServiceBucket {
+    pub(crate) name: String,
+    pub(crate) operations: Vec<String>,
+    /// Not all operations fit in the aggregation.
+    pub(crate) truncated: bool,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    aggregations: Aggregations,
+}
+
+#[derive(Deserialize)]
+struct Aggregations {
+    services: Terms<Service>,
+}
+
+#[derive(Deserialize)]
+struct Terms<B> {
+    buckets: Vec<B>,
+    #[serde(default)]
+    sum_other_doc_count: u64,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    key: String,
+    operations: Terms<Operation>,
+}
+
+#[derive(Deserialize)]
+struct Operation {
+    key: String,
+}
+
+/// Aggregate the services and operations of the spans in `indices`
+/// starting at or after `from` (in microseconds). Returns `None` if
+/// not all services fit in the aggregation.
+pub(crate) async fn aggregate(
+    client: &Client,
+    url: &Url,
+    indices: &str,
+    from: i64,
+) -> Result<Option<Vec<ServiceBucket>>, Error> {
+    let res = client
+        .post(url.join(&format!("{indices}/_search"))?)
+        .json(&json!({
+            "size": 0,
+            "query": {
+                "range": {
+                    "startTime": {
+                        "gte": from
+                    }
+                }
+            },
+            "aggs": {
+                "services": {
+                    "terms": { "field": "process.serviceName", "size": MAX_BUCKETS },
+                    "aggs": {
+                        "operations": {
+                            "terms": { "field": "operationName", "size": MAX_BUCKETS }
+                        }
+                    }
+                }
+            }
+        }))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())?
+        .json::<Response>()
+        .await?;
+
+    let services = res.aggregations.services;
+    if services.sum_other_doc_count > 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        services
+            .buckets
+            .into_iter()
+            .map(|service| ServiceBucket {
+                name: service.key,
+                truncated: service.operations.sum_other_doc_count > 0,
+                operations: service
+                    .operations
+                    .buckets
+                    .into_iter()
+                    .map(|operation| operation.key)
+                    .collect(),
+            })
+            .collect(),
+    ))
+}
@@ -2,14 +2,46 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::marker::PhantomData;
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
 
-use reqwest::Client;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::json;
+use hyper::body::Bytes;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, Histogram, HistogramVec,
+};
+use reqwest::{Client, RequestBuilder};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use serde_json::{json, value::RawValue};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 use url::Url;
 
-use crate::error::Error;
+use crate::{error::Error, health};
+
+static REQUEST_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "jaeger_discovery_es_request_seconds",
+        "Duration of the requests to Opensearch, successful or not, by request \
+         (open_pit, search or delete_pit).",
+        &["request"],
+        exponential_buckets(0.005, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+static SEARCH_HITS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "jaeger_discovery_es_search_hits",
+        "Number of hits in the pages returned by Opensearch.",
+        exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct QueryResponse<T, S> {
@@ -27,7 +59,9 @@ pub(crate) struct Hits<T, S> {
 pub(crate) struct Hit<T, S> {
     #[serde(rename = "_index")]
     pub(crate) index: String,
-    #[serde(rename = "_source")]
+    /// The document, or its doc values when queried with
+    /// `docvalue_fields`.
+    #[serde(rename = "_source", alias = "fields")]
     pub(crate) source: T,
     pub sort: Option<S>,
 }
@@ -48,76 +82,92 @@ pub(crate) struct DeletePitAction {
     // pub(crate) pit_id: String,
 }
 
-#[derive(Clone)]
-pub(crate) struct EsPit<'a> {
-    client: &'a Client,
-    keep_alive: &'a str,
+pub(crate) struct EsPit {
+    client: Client,
+    keep_alive: &'static str,
     url: Url,
-    pit_id: Option<String>,
+    /// The most recent id of the pit, shared by its (sliced) queries.
+    pit_id: Mutex<Option<String>>,
 }
 
-impl<'a> EsPit<'a> {
+impl EsPit {
     pub(crate) async fn new(
-        client: &'a Client,
+        client: Client,
         base: &Url,
         index_pattern: &str,
-        keep_alive: &'a str,
+        keep_alive: &'static str,
     ) -> Result<Self, Error> {
-        let res = client
-            .post(base.join(&format!("{index_pattern}/_search/point_in_time"))?)
-            .query(&json!({"keep_alive": keep_alive}))
-            .send()
-            .await
-            .and_then(|r| r.error_for_status())
-            .map_err(Error::Reqwest)?
-            .json::<PitResponse>()
-            .await
-            .map_err(Error::Reqwest)?;
+        let start = Instant::now();
+        let res = async {
+            client
+                .post(base.join(&format!("{index_pattern}/_search/point_in_time"))?)
+                .query(&json!({"keep_alive": keep_alive}))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(Error::Reqwest)?
+                .json::<PitResponse>()
+                .await
+                .map_err(Error::Reqwest)
+        }
+        .instrument(tracing::info_span!("open_pit", indices = index_pattern))
+        .await;
+        observe("open_pit", start);
+        health::opensearch(&res);
+        let res = res?;
         Ok(Self {
             client,
             keep_alive,
             url: base.clone(),
-            pit_id: Some(res.pit_id),
+            pit_id: Mutex::new(Some(res.pit_id)),
         })
     }
 
-    pub(crate) fn query<'b, T, S, L, U>(
-        &'b mut self,
+    /// Prepare a query on the pit. The query and sort order are
+    /// serialized once, and reused in the request for every page.
+    pub(crate) fn query<T, S, L>(
+        &self,
         query: T,
         sort: Option<S>,
         last: Option<L>,
-        batch_size: u64,
-    ) -> EsQuery<'a, 'b, T, S, L, U>
+        batch_size: BatchSize,
+    ) -> EsQuery<'_, L>
     where
-        'a: 'b,
         T: Serialize,
         S: Serialize,
         L: Serialize + DeserializeOwned + Clone,
-        U: DeserializeOwned,
     {
         EsQuery {
             pit: self,
             batch_size,
-            query,
-            sort,
+            query: serde_json::value::to_raw_value(&query).unwrap(),
+            sort: sort.map(|sort| serde_json::value::to_raw_value(&sort).unwrap()),
             last,
-            marker: PhantomData,
+            slice: None,
+            docvalue_fields: None,
+            prefetch: None,
         }
     }
 
     pub(crate) async fn delete(mut self) -> Result<(), Error> {
-        if let Some(pit_id) = self.pit_id.take() {
-            let res = self
-                .client
-                .delete(self.url.join("_search/point_in_time")?)
-                .json(&json!({ "pit_id": [pit_id] }))
-                .send()
-                .await
-                .and_then(|r| r.error_for_status())
-                .map_err(Error::Reqwest)?
-                .json::<DeletePitResponse>()
-                .await
-                .map_err(Error::Reqwest)?;
+        if let Some(pit_id) = self.pit_id.get_mut().unwrap().take() {
+            let start = Instant::now();
+            let res = async {
+                self.client
+                    .delete(self.url.join("_search/point_in_time")?)
+                    .json(&json!({ "pit_id": [pit_id] }))
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(Error::Reqwest)?
+                    .json::<DeletePitResponse>()
+                    .await
+                    .map_err(Error::Reqwest)
+            }
+            .instrument(tracing::info_span!("delete_pit"))
+            .await;
+            observe("delete_pit", start);
+            let res = res?;
             res.pits
                 .into_iter()
                 .try_for_each(|pit| pit.successful.then_some(()).ok_or(Error::DeletePit))?
@@ -126,90 +176,273 @@ impl<'a> EsPit<'a> {
     }
 }
 
-impl Drop for EsPit<'_> {
+impl Drop for EsPit {
     fn drop(&mut self) {
-        if self.pit_id.is_some() {
+        if self.pit_id.get_mut().unwrap().is_some() {
             log::warn!("Elasticsearch PIT left open; use pit.delete().await");
         }
     }
 }
 
-pub(crate) struct EsQuery<'a: 'b, 'b, T, S, L, U> {
-    pit: &'b mut EsPit<'a>,
-    batch_size: u64,
-    query: T,
-    sort: Option<S>,
+pub(crate) struct EsQuery<'a, L> {
+    pit: &'a EsPit,
+    batch_size: BatchSize,
+    query: Box<RawValue>,
+    sort: Option<Box<RawValue>>,
     last: Option<L>,
-    marker: PhantomData<U>,
+    slice: Option<QuerySlice>,
+    docvalue_fields: Option<&'static [&'static str]>,
+    /// Request for the next page, sent ahead of time.
+    prefetch: Option<JoinHandle<Result<Response, Error>>>,
+}
+
+/// Number of documents requested per page, adapted to the observed
+/// response times and sizes within configured bounds.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BatchSize {
+    size: u64,
+    min: u64,
+    max: u64,
+}
+
+/// Response time above which the batch size is reduced; it is
+/// increased while responses take less than half of it.
+const TARGET_LATENCY: Duration = Duration::from_secs(2);
+/// Response size above which the batch size is reduced; it is
+/// increased while responses are less than half of it.
+const TARGET_RESPONSE_BYTES: usize = 16 << 20;
+
+/// A received page, with the time it took to fetch.
+struct Response {
+    body: Bytes,
+    latency: Duration,
+}
+
+/// A page of search results, kept as the raw response body. Only the
+/// sort values are read when it is received; the documents are
+/// deserialized from the body directly into their final type, without
+/// an intermediate copy of every document.
+pub(crate) struct Page<L> {
+    body: Bytes,
+    /// Sort values of the last hit, to continue the query from.
+    pub(crate) last: Option<L>,
 }
 
 #[derive(Serialize, Debug)]
-struct PitQuery<'a, T, S, L> {
-    query: T,
+struct PitQuery<'a, L> {
+    query: &'a RawValue,
     #[serde(skip_serializing_if = "Option::is_none")]
-    sort: Option<&'a S>,
+    sort: Option<&'a RawValue>,
     #[serde(skip_serializing_if = "Option::is_none")]
     search_after: Option<&'a L>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slice: Option<QuerySlice>,
+    #[serde(rename = "_source", skip_serializing_if = "Option::is_none")]
+    source: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    docvalue_fields: Option<&'static [&'static str]>,
     size: u64,
     pit: QueryPit<'a>,
 }
 
+/// Part of the documents in the pit, for running queries concurrently.
+#[derive(Serialize, Clone, Copy, Debug)]
+struct QuerySlice {
+    id: u32,
+    max: u32,
+}
+
 #[derive(Serialize, Debug)]
 struct QueryPit<'a> {
     id: &'a str,
     keep_alive: &'a str,
 }
 
-impl<'a: 'b, 'b, T, S, L, U> EsQuery<'a, 'b, T, S, L, U>
+impl<L> EsQuery<'_, L>
 where
-    T: Serialize,
-    S: Serialize,
     L: Serialize + DeserializeOwned + Clone,
-    U: DeserializeOwned,
 {
-    pub(crate) async fn next(&mut self) -> Result<Option<QueryResponse<U, L>>, Error> {
+    /// Restrict the query to slice `id` of `max` slices.
+    pub(crate) fn slice(mut self, id: u32, max: u32) -> Self {
+        self.slice = Some(QuerySlice { id, max });
+        self
+    }
+
+    /// Return only the doc values of the given fields, instead of the
+    /// documents.
+    pub(crate) fn docvalue_fields(mut self, fields: &'static [&'static str]) -> Self {
+        self.docvalue_fields = Some(fields);
+        self
+    }
+
+    /// Fetch the next page. As soon as a page is received, the request
+    /// for the page after it is sent, so it is fetched while the caller
+    /// processes this one.
+    pub(crate) async fn next(&mut self) -> Result<Option<Page<L>>, Error> {
+        let Response { body, latency } = loop {
+            let request = match self.prefetch.take() {
+                Some(request) => request,
+                None => match self.request()? {
+                    Some(request) => request,
+                    None => return Ok(None),
+                },
+            };
+            match request.await.map_err(Error::Prefetch)? {
+                Ok(res) => break res,
+                /* Retry a request that timed out with a smaller batch. */
+                Err(Error::Reqwest(e)) if e.is_timeout() && self.batch_size.shrink() => {
+                    log::warn!(
+                        "search request timed out; retrying with batch size {}",
+                        self.batch_size.size
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.batch_size.adapt(latency, body.len());
+        let res = serde_json::from_slice::<QueryResponse<IgnoredAny, L>>(&body)
+            .map_err(Error::SearchResponse)?;
+        *self.pit.pit_id.lock().unwrap() = res.pit_id;
+        SEARCH_HITS.observe(res.hits.hits.len() as f64);
+        let last = match res.hits.hits.into_iter().last() {
+            Some(hit) => hit.sort,
+            None => return Ok(None),
+        };
+        self.last = last;
+        self.prefetch = self.request()?;
+        Ok(Some(Page {
+            body,
+            last: self.last.clone(),
+        }))
+    }
+
+    /// Send the request for the page after `last`, unless the pit has
+    /// been closed.
+    fn request(&self) -> Result<Option<JoinHandle<Result<Response, Error>>>, Error> {
         log::debug!(
             "Query: last = {}",
             serde_json::to_string(&self.last).unwrap()
         );
 
-        let pit_id = match self.pit.pit_id.as_deref() {
+        let pit_id = match self.pit.pit_id.lock().unwrap().clone() {
             Some(id) => id,
             None => return Ok(None),
         };
 
-        let res = self
+        let request = self
             .pit
             .client
             .post(self.pit.url.join("_search")?)
             .json(&PitQuery {
                 query: &self.query,
-                sort: self.sort.as_ref(),
+                sort: self.sort.as_deref(),
                 search_after: self.last.as_ref(),
-                size: self.batch_size,
+                slice: self.slice,
+                source: self.docvalue_fields.map(|_| false),
+                docvalue_fields: self.docvalue_fields,
+                size: self.batch_size.size,
                 pit: QueryPit {
-                    id: pit_id,
+                    id: &pit_id,
                     keep_alive: self.pit.keep_alive,
                 },
-            })
-            .send()
-            .await?;
-        if res.status().is_success() {
-            let res = res
-                .json::<QueryResponse<U, L>>()
-                .await
-                .map_err(Error::Reqwest)?;
-            self.pit.pit_id = res.pit_id.clone();
-            self.last = res.hits.hits.last().and_then(|hit| hit.sort.clone());
-            Ok((!res.hits.hits.is_empty()).then_some(res))
+            });
+        let span = tracing::info_span!("search", slice = self.slice.map(|slice| slice.id));
+        Ok(Some(tokio::spawn(send(request).instrument(span))))
+    }
+}
+
+impl<L> Drop for EsQuery<'_, L> {
+    fn drop(&mut self) {
+        if let Some(request) = self.prefetch.take() {
+            request.abort();
+        }
+    }
+}
+
+async fn send(request: RequestBuilder) -> Result<Response, Error> {
+    let start = Instant::now();
+    let res = search(request).await;
+    observe("search", start);
+    health::opensearch(&res);
+    res
+}
+
+/// Record the duration of a request to Opensearch started at `start`.
+fn observe(request: &str, start: Instant) {
+    REQUEST_SECONDS
+        .with_label_values(&[request])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+async fn search(request: RequestBuilder) -> Result<Response, Error> {
+    let start = Instant::now();
+    let res = request.send().await?;
+    if res.status().is_success() {
+        let body = res.bytes().await.map_err(Error::Reqwest)?;
+        Ok(Response {
+            body,
+            latency: start.elapsed(),
+        })
+    } else {
+        let err = res.error_for_status_ref().unwrap_err();
+        let msg = res.json::<serde_json::Value>().await?;
+        log::debug!(
+            "error response: {}",
+            serde_json::to_string_pretty(&msg).unwrap()
+        );
+        Err(err.into())
+    }
+}
+
+impl<L> Page<L> {
+    /// Size of the response body.
+    pub(crate) fn size(&self) -> usize {
+        self.body.len()
+    }
+
+    /// Deserialize the documents in the page, possibly borrowing from
+    /// the response body.
+    pub(crate) fn documents<'a, U: Deserialize<'a>>(&'a self) -> Result<Vec<U>, serde_json::Error> {
+        let res = serde_json::from_slice::<QueryResponse<U, IgnoredAny>>(&self.body)?;
+        Ok(res.hits.hits.into_iter().map(|hit| hit.source).collect())
+    }
+}
+
+impl BatchSize {
+    pub(crate) fn new(initial: u64, min: u64, max: u64) -> Self {
+        Self {
+            size: initial.clamp(min, max),
+            min,
+            max,
+        }
+    }
+
+    /// Shrink after a slow or large response, grow after a fast and
+    /// small one.
+    fn adapt(&mut self, latency: Duration, bytes: usize) {
+        let size = if latency > TARGET_LATENCY || bytes > TARGET_RESPONSE_BYTES {
+            self.size / 2
+        } else if latency < TARGET_LATENCY / 2 && bytes < TARGET_RESPONSE_BYTES / 2 {
+            self.size + self.size / 2
         } else {
-            let err = res.error_for_status_ref().unwrap_err();
-            let msg = res.json::<serde_json::Value>().await?;
+            self.size
+        }
+        .clamp(self.min, self.max);
+        if size != self.size {
             log::debug!(
-                "error response: {}",
-                serde_json::to_string_pretty(&msg).unwrap()
+                "batch size {} -> {size} (response of {bytes} bytes in {latency:?})",
+                self.size
             );
-            Err(err.into())
+            self.size = size;
         }
     }
+
+    /// Halve the batch size, returning false if it is already at its
+    /// minimum.
+    fn shrink(&mut self) -> bool {
+        let size = (self.size / 2).max(self.min);
+        let shrunk = size < self.size;
+        self.size = size;
+        shrunk
+    }
 }
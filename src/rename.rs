@@ -0,0 +1,273 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Mapping of emitted type and property names, so the payload can
+//! match an existing relation graph schema. Payloads read back (a
+//! pending push, or the world in the relation graph) are mapped back
+//! to the original names and parsed as the items and relations they
+//! were built from, so they compare equal to freshly built ones.
+
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+use crate::{
+    config::Config,
+    discovery::{Item, Items, Relation, RenamedItem, RenamedRelation},
+};
+
+pub(crate) struct Renames {
+    types: BTreeMap<String, String>,
+    properties: BTreeMap<String, String>,
+    /// The reverse mappings, to read back renamed payloads.
+    original_types: BTreeMap<String, String>,
+    original_properties: BTreeMap<String, String>,
+}
+
+impl Renames {
+    pub(crate) fn new(config: &Config) -> Self {
+        let reverse = |names: &BTreeMap<String, String>| {
+            names
+                .iter()
+                .map(|(from, to)| (to.clone(), from.clone()))
+                .collect()
+        };
+        Self {
+            types: config.type_names.clone(),
+            properties: config.property_names.clone(),
+            original_types: reverse(&config.type_names),
+            original_properties: reverse(&config.property_names),
+        }
+    }
+
+    /// Whether no names are mapped.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.types.is_empty() && self.properties.is_empty()
+    }
+
+    /// Rename item and relation types and property names in the
+    /// payload. Names without a mapping are kept.
+    pub(crate) fn apply(&self, items: Items) -> Items {
+        if self.is_empty() {
+            return items;
+        }
+
+        let Items {
+            mut domain,
+            items: mut world,
+        } = items;
+
+        domain.types.items = std::mem::take(&mut domain.types.items)
+            .into_iter()
+            .map(|typ| self.rename_type(&typ))
+            .collect();
+        domain.types.relations = std::mem::take(&mut domain.types.relations)
+            .into_iter()
+            .map(|typ| self.rename_type(&typ))
+            .collect();
+
+        world.items = std::mem::take(&mut world.items)
+            .into_iter()
+            .map(|(id, item)| (id, self.rename_item(item)))
+            .collect();
+        world.relations = std::mem::take(&mut world.relations)
+            .into_iter()
+            .map(|(id, rel)| (id, self.rename_relation(rel)))
+            .collect();
+
+        Items {
+            domain,
+            items: world,
+        }
+    }
+
+    /// Parse a payload as published, with mapped names.
+    pub(crate) fn parse(&self, mut value: Value) -> Result<Items, serde_json::Error> {
+        if self.is_empty() {
+            return serde_json::from_value(value);
+        }
+        if let Some(types) = value.pointer_mut("/domain/types") {
+            for kind in ["items", "relations"] {
+                if let Some(Value::Array(types)) = types.get_mut(kind) {
+                    types.iter_mut().for_each(|typ| self.restore_name(typ));
+                }
+            }
+        }
+        for (kind, type_key) in [("items", "item_type"), ("relations", "relation_type")] {
+            if let Some(Value::Object(entries)) = value.pointer_mut(&format!("/items/{kind}")) {
+                entries
+                    .values_mut()
+                    .for_each(|entry| self.restore(entry, type_key));
+            }
+        }
+        serde_json::from_value(value).map(|items| self.apply(items))
+    }
+
+    /// Parse an item as published, with mapped names.
+    pub(crate) fn parse_item(&self, mut value: Value) -> Option<Item> {
+        self.restore(&mut value, "item_type");
+        let item = serde_json::from_value(value).ok()?;
+        Some(match self.is_empty() {
+            true => item,
+            false => self.rename_item(item),
+        })
+    }
+
+    /// Parse a relation as published, with mapped names.
+    pub(crate) fn parse_relation(&self, mut value: Value) -> Option<Relation> {
+        self.restore(&mut value, "relation_type");
+        let rel = serde_json::from_value(value).ok()?;
+        Some(match self.is_empty() {
+            true => rel,
+            false => self.rename_relation(rel),
+        })
+    }
+
+    /// Map the type (under `type_key`) and property names of an item or
+    /// relation back to the original names.
+    fn restore(&self, entry: &mut Value, type_key: &str) {
+        if let Some(typ) = entry.get_mut(type_key) {
+            self.restore_name(typ);
+        }
+        if let Some(Value::Object(properties)) = entry.get_mut("properties") {
+            *properties = std::mem::take(properties)
+                .into_iter()
+                .map(|(name, value)| {
+                    let name = self.original_properties.get(&name).cloned().unwrap_or(name);
+                    (name, value)
+                })
+                .collect();
+        }
+    }
+
+    fn restore_name(&self, typ: &mut Value) {
+        if let Some(original) = typ.as_str().and_then(|name| self.original_types.get(name)) {
+            *typ = Value::String(original.clone());
+        }
+    }
+
+    fn rename_item(&self, item: Item) -> Item {
+        let name = item.name();
+        let parent = item.parent();
+        let mut value = serde_json::to_value(&item).unwrap();
+        Item::Renamed(RenamedItem {
+            item_type: self.rename_type(item.item_type()),
+            parent,
+            properties: self.rename_properties(value["properties"].take()),
+            name,
+        })
+    }
+
+    fn rename_relation(&self, rel: Relation) -> Relation {
+        let (source, target) = rel.endpoints();
+        let mut value = serde_json::to_value(&rel).unwrap();
+        Relation::Renamed(RenamedRelation {
+            relation_type: self.rename_type(rel.relation_type()),
+            source,
+            target,
+            properties: self.rename_properties(value["properties"].take()),
+        })
+    }
+
+    fn rename_type(&self, typ: &str) -> String {
+        self.types.get(typ).map_or(typ, String::as_str).to_string()
+    }
+
+    fn rename_properties(&self, properties: Value) -> Map<String, Value> {
+        match properties {
+            Value::Object(properties) => properties
+                .into_iter()
+                .map(|(name, value)| {
+                    let name = self.properties.get(&name).cloned().unwrap_or(name);
+                    (name, value)
+                })
+                .collect(),
+            _ => Map::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn renames() -> Renames {
+        let config = Config {
+            type_names: BTreeMap::from([
+                (String::from("jaeger/service"), String::from("app/service")),
+                (
+                    String::from("jaeger/operation"),
+                    String::from("app/endpoint"),
+                ),
+            ]),
+            property_names: BTreeMap::from([(
+                String::from("jaeger/service_name"),
+                String::from("app/name"),
+            )]),
+            ..Config::default()
+        };
+        Renames::new(&config)
+    }
+
+    fn payload() -> Items {
+        let service = "00000000-0000-0000-0000-000000000001";
+        serde_json::from_value(json!({
+            "domain": {
+                "roots": null,
+                "types": {
+                    "items": ["jaeger/operation", "jaeger/service"],
+                    "relations": [],
+                },
+            },
+            "items": {
+                "items": {
+                    service: {
+                        "item_type": "jaeger/service",
+                        "properties": { "jaeger/service_name": { "string": "a" } },
+                    },
+                    "00000000-0000-0000-0000-000000000002": {
+                        "item_type": "jaeger/operation",
+                        "parent": service,
+                        "properties": { "jaeger/operation_name": { "string": "GET /" } },
+                    },
+                },
+                "relations": {},
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn renames_types_and_properties() {
+        let renamed = serde_json::to_value(renames().apply(payload())).unwrap();
+        assert_eq!(
+            renamed["domain"]["types"]["items"],
+            json!(["app/endpoint", "app/service"])
+        );
+        let service = &renamed["items"]["items"]["00000000-0000-0000-0000-000000000001"];
+        assert_eq!(service["item_type"], "app/service");
+        assert_eq!(
+            service["properties"],
+            json!({ "app/name": { "string": "a" } })
+        );
+        let operation = &renamed["items"]["items"]["00000000-0000-0000-0000-000000000002"];
+        assert_eq!(operation["item_type"], "app/endpoint");
+        assert_eq!(operation["parent"], "00000000-0000-0000-0000-000000000001");
+    }
+
+    #[test]
+    fn parses_renamed_payload() {
+        let renames = renames();
+        let renamed = serde_json::to_value(renames.apply(payload())).unwrap();
+        let parsed = renames.parse(renamed.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), renamed);
+
+        let item = renamed["items"]["items"]["00000000-0000-0000-0000-000000000002"].clone();
+        let item = renames.parse_item(item).unwrap();
+        assert_eq!(item.name(), "GET /");
+        assert_eq!(item.item_type(), "app/endpoint");
+    }
+}
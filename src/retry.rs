@@ -0,0 +1,127 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, HistogramVec,
+    IntCounterVec,
+};
+use reqwest::{RequestBuilder, Response};
+
+static REQUEST_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "jaeger_discovery_rg_request_seconds",
+        "Duration of the requests to the relation graph (every attempt, until the \
+         response headers are received), by sink and method.",
+        &["sink", "method"],
+        exponential_buckets(0.01, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+static REQUEST_BYTES: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "jaeger_discovery_rg_request_bytes",
+        "Size of the bodies sent to the relation graph (as sent, possibly \
+         compressed), by sink and method.",
+        &["sink", "method"],
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+static RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "jaeger_discovery_rg_responses_total",
+        "Number of responses from the relation graph, by sink and http status \
+         (\"error\" if no response was received).",
+        &["sink", "status"]
+    )
+    .unwrap()
+});
+
+static RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "jaeger_discovery_rg_retries_total",
+        "Number of requests to the relation graph retried, by sink.",
+        &["sink"]
+    )
+    .unwrap()
+});
+
+/// Retry policy for requests to the relation graph. Connection errors,
+/// timeouts and server errors (5xx) are retried with exponential
+/// backoff.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Send a request on behalf of `sink`, retrying according to the
+    /// policy. Returns the last response received, which may still
+    /// carry an error status.
+    pub(crate) async fn send(
+        &self,
+        sink: &str,
+        req: RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let res = match req.try_clone() {
+                Some(req) => observed(sink, req).await,
+                None => return observed(sink, req).await,
+            };
+
+            let reason = match &res {
+                Ok(res) if res.status().is_server_error() => res.status().to_string(),
+                Err(err) if err.is_connect() || err.is_timeout() => err.to_string(),
+                _ => return res,
+            };
+
+            if attempt >= self.retries {
+                return res;
+            }
+
+            attempt += 1;
+            RETRIES.with_label_values(&[sink]).inc();
+            log::warn!(
+                "request failed ({reason}); retrying in {}s ({attempt}/{})",
+                backoff.as_secs_f64(),
+                self.retries
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+/// Send a request, recording its duration, size and outcome.
+async fn observed(sink: &str, req: RequestBuilder) -> Result<Response, reqwest::Error> {
+    let (client, req) = req.build_split();
+    let req = req?;
+    let method = req.method().clone();
+    if let Some(body) = req.body().and_then(|body| body.as_bytes()) {
+        REQUEST_BYTES
+            .with_label_values(&[sink, method.as_str()])
+            .observe(body.len() as f64);
+    }
+    let start = Instant::now();
+    let res = client.execute(req).await;
+    REQUEST_SECONDS
+        .with_label_values(&[sink, method.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    let status = res.as_ref().ok().map(|res| res.status());
+    RESPONSES
+        .with_label_values(&[sink, status.as_ref().map_or("error", |s| s.as_str())])
+        .inc();
+    res
+}
@@ -0,0 +1,179 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Config-driven derivation of custom items and relations.
+//!
+//! A rule fires for every span on which all of its conditions hold,
+//! and emits (or refreshes) an item identified by the rendered `key`
+//! template, optionally with a relation from the span's service to
+//! that item. Templates may refer to span or process tags as `{tag}`.
+//!
+//! ```json
+//! {
+//!   "name": "redis",
+//!   "when": [{ "tag": "db.system", "matches": "^redis$" }],
+//!   "item": {
+//!     "type": "custom/cache_cluster",
+//!     "key": "{server.address}",
+//!     "properties": { "custom/host": "{server.address}" }
+//!   },
+//!   "relation": { "type": "custom/uses_cache" }
+//! }
+//! ```
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use serde_with::DeserializeFromStr;
+use uuid::Uuid;
+
+use crate::{
+    discovery::Span,
+    state::{CustomItemState, CustomRelationState, ServiceKey},
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rule {
+    pub(crate) name: String,
+    pub(crate) when: Vec<Condition>,
+    pub(crate) item: ItemTemplate,
+    #[serde(default)]
+    pub(crate) relation: Option<RelationTemplate>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Condition {
+    pub(crate) tag: String,
+    #[serde(default)]
+    pub(crate) matches: Option<Pattern>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ItemTemplate {
+    #[serde(rename = "type")]
+    pub(crate) item_type: String,
+    pub(crate) key: String,
+    #[serde(default)]
+    pub(crate) properties: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RelationTemplate {
+    #[serde(rename = "type")]
+    pub(crate) relation_type: String,
+    #[serde(default)]
+    pub(crate) properties: BTreeMap<String, String>,
+}
+
+#[derive(DeserializeFromStr, Debug)]
+pub(crate) struct Pattern(Regex);
+
+impl Rule {
+    /// Apply the rule to a span, updating the custom items map.
+    /// Returns whether the rule matched.
+    pub(crate) fn apply(
+        &self,
+        span: &Span,
+        service_key: &ServiceKey,
+        t: DateTime<Utc>,
+        items: &mut BTreeMap<String, CustomItemState>,
+    ) -> bool {
+        if !self.when.iter().all(|cond| cond.holds(span)) {
+            return false;
+        }
+
+        let key = match render(&self.item.key, span) {
+            Some(key) => format!("{}/{key}", self.name),
+            None => return false,
+        };
+
+        let item = items
+            .entry(key)
+            .or_insert_with(|| CustomItemState::new(self.item.item_type.clone(), t));
+        item.item_type.clone_from(&self.item.item_type);
+        item.properties = render_all(&self.item.properties, span);
+        item.last_seen = item.last_seen.max(t);
+
+        if let Some(relation) = &self.relation {
+            let rel = item
+                .relations
+                .entry(service_key.clone())
+                .or_insert_with(|| CustomRelationState::new(relation.relation_type.clone(), t));
+            rel.relation_type.clone_from(&relation.relation_type);
+            rel.properties = render_all(&relation.properties, span);
+            rel.last_seen = rel.last_seen.max(t);
+        }
+        true
+    }
+}
+
+impl Condition {
+    fn holds(&self, span: &Span) -> bool {
+        match (span.tag_value(&self.tag), &self.matches) {
+            (Some(value), Some(Pattern(re))) => re.is_match(&value),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Render a template, substituting `{tag}` references. Returns `None`
+/// if a referenced tag is not present on the span.
+fn render(template: &str, span: &Span) -> Option<String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        result.push_str(&rest[..start]);
+        result.push_str(&span.tag_value(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+/// Render a map of property templates, leaving out properties
+/// referring to missing tags.
+fn render_all(templates: &BTreeMap<String, String>, span: &Span) -> BTreeMap<String, String> {
+    templates
+        .iter()
+        .filter_map(|(name, template)| Some((name.clone(), render(template, span)?)))
+        .collect()
+}
+
+impl FromStr for Pattern {
+    type Err = regex::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Regex::new(s)?))
+    }
+}
+
+impl CustomItemState {
+    fn new(item_type: String, t: DateTime<Utc>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            item_type,
+            properties: BTreeMap::new(),
+            last_seen: t,
+            relations: BTreeMap::new(),
+        }
+    }
+}
+
+impl CustomRelationState {
+    fn new(relation_type: String, t: DateTime<Utc>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            relation_type,
+            properties: BTreeMap::new(),
+            last_seen: t,
+        }
+    }
+}
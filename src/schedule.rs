@@ -0,0 +1,103 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Scheduling of the discovery cycles. Ticks missed while a cycle was
+//! still running are skipped, and optionally the interval is
+//! stretched while cycles take longer than the interval.
+
+use std::time::Duration;
+
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::error::Error;
+
+pub(crate) struct Schedule {
+    interval: Interval,
+    /// The configured interval.
+    base: Duration,
+    /// Maximum interval, if the interval is to be stretched.
+    max: Option<Duration>,
+    skipped_ticks: IntCounter,
+    interval_seconds: IntGauge,
+}
+
+impl Schedule {
+    /// Schedule cycles every `interval` seconds, stretching the
+    /// interval up to `max_interval` seconds if given.
+    pub(crate) fn new(interval: u64, max_interval: Option<u64>) -> Result<Self, Error> {
+        if let Some(max) = max_interval.filter(|max| *max < interval) {
+            return Err(Error::IntervalBounds(max, interval));
+        }
+        let base = Duration::from_secs(interval);
+        let schedule = Self {
+            interval: new_interval(Instant::now(), base),
+            base,
+            max: max_interval.map(Duration::from_secs),
+            skipped_ticks: register_int_counter!(
+                "jaeger_discovery_skipped_ticks_total",
+                "Number of scheduled cycles skipped because the previous cycle was still running."
+            )?,
+            interval_seconds: register_int_gauge!(
+                "jaeger_discovery_interval_seconds",
+                "Current interval between discovery cycles."
+            )?,
+        };
+        schedule.interval_seconds.set(interval as i64);
+        Ok(schedule)
+    }
+
+    /// Wait for the next scheduled cycle.
+    pub(crate) async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// Account for a cycle started at `start`. Ticks that passed while
+    /// the cycle was running are skipped. When stretching is enabled,
+    /// the interval is doubled after a cycle that took longer than the
+    /// interval, and halved (down to the configured interval) after a
+    /// cycle that took less than half of it.
+    pub(crate) fn finished(&mut self, start: Instant) {
+        let elapsed = start.elapsed();
+        let period = self.interval.period();
+        let missed = (elapsed.as_nanos() / period.as_nanos()) as u64;
+        if missed > 0 {
+            log::warn!(
+                "discovery cycle took {}s, longer than the interval of {}s; skipping {missed} tick(s)",
+                elapsed.as_secs(),
+                period.as_secs()
+            );
+            self.skipped_ticks.inc_by(missed);
+        }
+
+        let Some(max) = self.max else {
+            return;
+        };
+        let next = if elapsed > period {
+            (period * 2).min(max)
+        } else if elapsed < period / 2 {
+            (period / 2).max(self.base)
+        } else {
+            period
+        };
+        if next != period {
+            log::info!(
+                "changing the interval from {}s to {}s",
+                period.as_secs(),
+                next.as_secs()
+            );
+            /* The first tick of the new interval after the end of
+             * the cycle, skipping the ones that passed. */
+            let ticks = elapsed.as_nanos() / next.as_nanos() + 1;
+            self.interval = new_interval(start + next * ticks as u32, next);
+            self.interval_seconds.set(next.as_secs() as i64);
+        }
+    }
+}
+
+fn new_interval(start: Instant, period: Duration) -> Interval {
+    let mut interval = tokio::time::interval_at(start, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    interval
+}
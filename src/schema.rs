@@ -0,0 +1,50 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Validation of the generated payload against a JSON schema, so
+//! malformed items are reported before anything is sent.
+
+use std::path::Path;
+
+use jsonschema::JSONSchema;
+
+use crate::{discovery::Items, error::Error, load_config};
+
+/// Bundled schema for the items payload.
+const BUNDLED: &str = include_str!("items.schema.json");
+
+pub(crate) struct PayloadSchema(JSONSchema);
+
+impl PayloadSchema {
+    /// Compile the schema at `path`, or the bundled schema if not given.
+    pub(crate) async fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let schema = match path {
+            Some(path) => load_config::<serde_json::Value>(path).await?,
+            None => serde_json::from_str(BUNDLED).unwrap(),
+        };
+        JSONSchema::compile(&schema)
+            .map(Self)
+            .map_err(|e| Error::InvalidSchema(e.to_string()))
+    }
+
+    /// Validate the payload, logging every violation with the path to
+    /// the offending item or property. Returns the number of
+    /// violations.
+    pub(crate) fn validate(&self, items: &Items) -> usize {
+        let instance = serde_json::to_value(items).unwrap();
+        let violations = match self.0.validate(&instance) {
+            Ok(()) => 0,
+            Err(errors) => errors
+                .map(|e| {
+                    log::error!(
+                        "invalid payload at {}: {e} (schema: {})",
+                        e.instance_path,
+                        e.schema_path
+                    )
+                })
+                .count(),
+        };
+        violations
+    }
+}
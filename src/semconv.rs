@@ -0,0 +1,122 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Mapping of (renamed) OpenTelemetry semantic convention attributes to
+//! the names used for tag matching.
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use crate::discovery::Span;
+
+/// Bundled mappings from alternative attribute names to the names
+/// matched by discovery.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("deployment.environment.name", "deployment.environment"),
+    ("http.response.status_code", "http.status_code"),
+    ("messaging.destination", "messaging.destination.name"),
+    ("url.full", "http.url"),
+    ("net.peer.name", "server.address"),
+    ("net.peer.port", "server.port"),
+];
+
+pub(crate) struct Semconv(BTreeMap<String, String>);
+
+impl Semconv {
+    /// Build the mapping table from the bundled defaults, extended or
+    /// overridden by configured mappings. Map a name to itself to
+    /// disable a default mapping.
+    pub(crate) fn new(overrides: &BTreeMap<String, String>) -> Self {
+        let mut mappings = DEFAULTS
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .chain(overrides.clone())
+            .collect::<BTreeMap<_, _>>();
+        mappings.retain(|from, to| from != to);
+        Self(mappings)
+    }
+
+    /// Rename span and process tags to their canonical names.
+    pub(crate) fn apply(&self, span: &mut Span) {
+        span.tags
+            .iter_mut()
+            .chain(&mut span.process.tags)
+            .for_each(|tag| {
+                if let Some(name) = self.0.get(&*tag.key) {
+                    tag.key = Cow::Owned(name.clone());
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// The span tag keys after applying the mappings to a span with
+    /// `keys` as span tags.
+    fn apply(semconv: &Semconv, keys: &[&str]) -> Vec<String> {
+        let data = json!({
+            "traceID": "t1",
+            "spanID": "s1",
+            "operationName": "op",
+            "references": [],
+            "startTime": 0,
+            "startTimeMillis": 0,
+            "duration": 0,
+            "tags": keys.iter().map(|key| json!({
+                "key": key,
+                "type": "string",
+                "value": "v",
+            })).collect::<Vec<_>>(),
+            "logs": [],
+            "process": { "serviceName": "svc", "tags": [] },
+        })
+        .to_string();
+        let mut span = serde_json::from_str::<Span>(&data).unwrap();
+        semconv.apply(&mut span);
+        span.tags.iter().map(|tag| tag.key.to_string()).collect()
+    }
+
+    #[test]
+    fn applies_default_mappings() {
+        let semconv = Semconv::new(&BTreeMap::new());
+        assert_eq!(
+            apply(&semconv, &["http.response.status_code", "other"]),
+            ["http.status_code", "other"]
+        );
+    }
+
+    #[test]
+    fn overrides_extend_and_replace_defaults() {
+        let semconv = Semconv::new(&BTreeMap::from([
+            (
+                String::from("deployment.environment.name"),
+                String::from("env"),
+            ),
+            (String::from("rpc.service"), String::from("peer.service")),
+        ]));
+        assert_eq!(
+            apply(&semconv, &["deployment.environment.name", "rpc.service"]),
+            ["env", "peer.service"]
+        );
+    }
+
+    #[test]
+    fn identity_override_disables_default() {
+        let semconv = Semconv::new(&BTreeMap::from([(
+            String::from("http.response.status_code"),
+            String::from("http.response.status_code"),
+        )]));
+        assert!(!semconv.0.contains_key("http.response.status_code"));
+        assert_eq!(
+            apply(
+                &semconv,
+                &["http.response.status_code", "messaging.destination"]
+            ),
+            ["http.response.status_code", "messaging.destination.name"]
+        );
+    }
+}
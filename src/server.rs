@@ -0,0 +1,158 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Embedded HTTP server exposing metrics, health probes and, when a
+//! token is configured, an admin api.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{discovery::Items, error::Error, health, inspect::ServiceSummary};
+
+/// State shared by the server and the main loop.
+pub(crate) struct Context {
+    /// A cycle running for longer than this fails the liveness probe.
+    pub(crate) stuck_after: Duration,
+    /// Bearer token required by the admin api, which is disabled
+    /// without it.
+    pub(crate) admin_token: Option<String>,
+    /// Wakes the main loop to run a discovery cycle.
+    pub(crate) discover: Notify,
+    /// The graph most recently published, once the admin api is
+    /// enabled and a cycle has run.
+    pub(crate) published: RwLock<Option<Published>>,
+}
+
+/// A published graph, as served by the admin api.
+pub(crate) struct Published {
+    pub(crate) items: Arc<Items>,
+    pub(crate) services: Vec<ServiceSummary>,
+}
+
+/// Serve metrics, health probes and the admin api on `addr`.
+pub(crate) async fn serve(addr: SocketAddr, ctx: Arc<Context>) -> Result<(), Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let ctx = ctx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let ctx = ctx.clone();
+                async move { handle(req, &ctx) }
+            }))
+        }
+    });
+    log::info!("serving metrics on {addr}");
+    Server::try_bind(&addr)
+        .map_err(Error::Server)?
+        .serve(make_svc)
+        .await
+        .map_err(Error::Server)
+}
+
+fn handle(req: Request<Body>, ctx: &Context) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(),
+        (&Method::GET, "/healthz") => probe(health::liveness(ctx.stuck_after)),
+        (&Method::GET, "/readyz") => probe(health::readiness()),
+        (&Method::POST, "/admin/discover") => admin(&req, ctx, || {
+            log::info!("discovery cycle requested through the admin api");
+            ctx.discover.notify_one();
+            text(StatusCode::ACCEPTED, "discovery cycle requested\n")
+        }),
+        (&Method::GET, "/admin/graph") => {
+            admin(&req, ctx, || match &*ctx.published.read().unwrap() {
+                Some(published) => json(&*published.items),
+                None => text(StatusCode::SERVICE_UNAVAILABLE, "no graph published yet\n"),
+            })
+        }
+        (&Method::GET, "/admin/services") => {
+            admin(&req, ctx, || match &*ctx.published.read().unwrap() {
+                Some(published) => json(&published.services),
+                None => text(StatusCode::SERVICE_UNAVAILABLE, "no graph published yet\n"),
+            })
+        }
+        _ => not_found(),
+    })
+}
+
+/// Handle a request to the admin api with `f` if it carries the
+/// configured bearer token. The api is not found when no token is
+/// configured.
+fn admin(req: &Request<Body>, ctx: &Context, f: impl FnOnce() -> Response<Body>) -> Response<Body> {
+    let Some(token) = &ctx.admin_token else {
+        return not_found();
+    };
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => f(),
+        _ => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+/// Compare a token without leaking the position of the first
+/// difference through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn metrics() -> Response<Body> {
+    #[cfg(feature = "jemalloc")]
+    crate::heap::update();
+    let encoder = TextEncoder::new();
+    let mut data = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut data).unwrap();
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(data))
+        .unwrap()
+}
+
+fn probe(res: Result<(), String>) -> Response<Body> {
+    match res {
+        Ok(()) => text(StatusCode::OK, "ok\n"),
+        Err(reason) => text(StatusCode::SERVICE_UNAVAILABLE, format!("{reason}\n")),
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body.into())
+        .unwrap()
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
+}
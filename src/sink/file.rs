@@ -0,0 +1,58 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::path::PathBuf;
+
+use chrono::Utc;
+use flate2::{write::GzEncoder, Compression};
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{discovery::Items, error::Error, state::State};
+
+use super::GraphSink;
+
+/// Sink writing the payload of every cycle to a timestamped file.
+pub(crate) struct FileSink {
+    dir: PathBuf,
+    gzip: bool,
+}
+
+impl FileSink {
+    pub(crate) fn new(dir: PathBuf, gzip: bool) -> Self {
+        Self { dir, gzip }
+    }
+
+    async fn write(&self, items: &Items) -> Result<(), Error> {
+        let name = format!("items-{}.json", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+        let mut data = Vec::new();
+        let path = match self.gzip {
+            true => {
+                serde_json::to_writer(GzEncoder::new(&mut data, Compression::fast()), items)
+                    .unwrap();
+                self.dir.join(format!("{name}.gz"))
+            }
+            false => {
+                serde_json::to_writer(&mut data, items).unwrap();
+                self.dir.join(name)
+            }
+        };
+        tokio::fs::write(&path, &data)
+            .await
+            .map_err(|e| Error::WriteFile(path, e))
+    }
+}
+
+impl GraphSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        _state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.write(items).boxed()
+    }
+}
@@ -0,0 +1,102 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use futures::{future::BoxFuture, FutureExt};
+use rskafka::{
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        ClientBuilder,
+    },
+    record::Record,
+};
+
+use crate::{
+    delta::{Fingerprints, ItemsDelta},
+    discovery::Items,
+    error::Error,
+    state::State,
+};
+
+use super::GraphSink;
+
+/// Sink publishing the payload of every cycle, or the delta against
+/// the previous cycle, to a Kafka topic. Records carry a `type` header
+/// of `full` or `delta`; a full payload is sent first and after any
+/// failure.
+pub(crate) struct KafkaSink {
+    brokers: Vec<String>,
+    topic: String,
+    partition: i32,
+    delta: bool,
+    client: Option<PartitionClient>,
+    published: Option<Fingerprints>,
+}
+
+impl KafkaSink {
+    pub(crate) fn new(brokers: Vec<String>, topic: String, partition: i32, delta: bool) -> Self {
+        Self {
+            brokers,
+            topic,
+            partition,
+            delta,
+            client: None,
+            published: None,
+        }
+    }
+
+    async fn publish(&mut self, items: &Items) -> Result<(), Error> {
+        let client = match &self.client {
+            Some(client) => client,
+            None => {
+                let client = ClientBuilder::new(self.brokers.clone()).build().await?;
+                self.client.insert(
+                    client
+                        .partition_client(
+                            self.topic.clone(),
+                            self.partition,
+                            UnknownTopicHandling::Retry,
+                        )
+                        .await?,
+                )
+            }
+        };
+
+        let fingerprints = self.delta.then(|| Fingerprints::new(items));
+        let (kind, value) = match (self.published.take(), &fingerprints) {
+            (Some(old), Some(new)) => (
+                "delta",
+                serde_json::to_vec(&ItemsDelta::new(&old, new, items)).unwrap(),
+            ),
+            _ => ("full", serde_json::to_vec(items).unwrap()),
+        };
+
+        let record = Record {
+            key: None,
+            value: Some(value),
+            headers: BTreeMap::from([(String::from("type"), kind.as_bytes().to_vec())]),
+            timestamp: Utc::now(),
+        };
+        client.produce(vec![record], Compression::Gzip).await?;
+
+        self.published = fingerprints;
+        Ok(())
+    }
+}
+
+impl GraphSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        _state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.publish(items).boxed()
+    }
+}
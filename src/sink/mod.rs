@@ -0,0 +1,145 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Destinations for the discovered graph.
+
+mod file;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "neo4j")]
+mod neo4j;
+mod prometheus;
+mod queue;
+#[cfg(test)]
+mod recording;
+mod relation_graph;
+mod webhook;
+
+use std::sync::Arc;
+
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{
+    config::SinkConfig, discovery::Items, error::Error, ratelimit::RateLimiter, rename::Renames,
+    state::State, Args,
+};
+
+pub(crate) use file::FileSink;
+#[cfg(feature = "kafka")]
+pub(crate) use kafka::KafkaSink;
+#[cfg(feature = "neo4j")]
+pub(crate) use neo4j::Neo4jSink;
+pub(crate) use prometheus::PrometheusSink;
+pub(crate) use queue::{PushQueue, PushTask};
+#[cfg(test)]
+pub(crate) use recording::RecordingSink;
+pub(crate) use relation_graph::RelationGraphSink;
+pub(crate) use webhook::WebhookSink;
+
+/// A destination the graph is published to after every discovery
+/// cycle.
+pub(crate) trait GraphSink: Send {
+    /// Name used to identify the sink in log messages.
+    fn name(&self) -> &str;
+
+    /// Prepare the sink at startup. Errors are fatal.
+    fn init(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// Complete work left over from previous cycles, such as failed
+    /// pushes. Called at the start of every cycle.
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// Publish the graph built in a discovery cycle, along with the
+    /// state it was built from.
+    fn push<'a>(
+        &'a mut self,
+        state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Build the sinks selected in the configuration. Sinks reading back
+/// published payloads parse them with `renames`.
+pub(crate) fn build(
+    configs: &[SinkConfig],
+    args: &Args,
+    renames: &Arc<Renames>,
+) -> Result<Vec<Box<dyn GraphSink>>, Error> {
+    let limiter = args
+        .push_rate
+        .map(|rate| Arc::new(RateLimiter::new(rate, args.push_burst)));
+    configs
+        .iter()
+        .map(|config| {
+            Ok(match config {
+                SinkConfig::RelationGraph { url } => Box::new(RelationGraphSink::new(
+                    args,
+                    url.as_ref(),
+                    limiter.clone(),
+                    renames.clone(),
+                )?) as Box<dyn GraphSink>,
+                SinkConfig::File { path, gzip } => Box::new(FileSink::new(path.clone(), *gzip)),
+                SinkConfig::Prometheus { service_graph } => {
+                    Box::new(PrometheusSink::new(*service_graph)?)
+                }
+                SinkConfig::Webhook { url, headers } => {
+                    Box::new(WebhookSink::new(url.clone(), headers.clone())?)
+                }
+                #[cfg(feature = "kafka")]
+                SinkConfig::Kafka {
+                    brokers,
+                    topic,
+                    partition,
+                    delta,
+                } => Box::new(KafkaSink::new(
+                    brokers.clone(),
+                    topic.clone(),
+                    *partition,
+                    *delta,
+                )),
+                #[cfg(feature = "neo4j")]
+                SinkConfig::Neo4j {
+                    uri,
+                    user,
+                    password,
+                } => Box::new(Neo4jSink::new(uri.clone(), user.clone(), password.clone())),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn builds_configured_sinks() {
+        let args = Args::parse_from([
+            "jaeger-discovery",
+            "--es-url=http://es",
+            "--es-ca=ca.pem",
+            "--es-cert=cert.pem",
+            "--es-key=key.pem",
+            "--rg-url=http://rg",
+            "--state=state",
+        ]);
+        let config = serde_json::from_str::<Config>("{}").unwrap();
+        let renames = Arc::new(Renames::new(&config));
+        let sinks = build(&config.sinks, &args, &renames).unwrap();
+        assert_eq!(
+            sinks.iter().map(|sink| sink.name()).collect::<Vec<_>>(),
+            ["relation graph at http://rg/"]
+        );
+
+        let config = serde_json::from_str::<Config>(r#"{"sinks": []}"#).unwrap();
+        assert!(build(&config.sinks, &args, &renames).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use futures::{future::BoxFuture, FutureExt};
+use neo4rs::{query, BoltType, Graph};
+
+use crate::{discovery::Items, error::Error, state::State};
+
+use super::GraphSink;
+
+/// Sink upserting services, operations and invokes relations into
+/// Neo4j. Nodes are keyed by the service key and operation name, so
+/// they remain stable when the state is reset. Nodes and relations not
+/// present in the latest push are removed.
+pub(crate) struct Neo4jSink {
+    uri: String,
+    user: String,
+    password: String,
+    graph: Option<Graph>,
+}
+
+type Row = HashMap<String, BoltType>;
+
+const UPSERT_SERVICES: &str = "UNWIND $rows AS row \
+     MERGE (s:Service {key: row.key}) \
+     SET s.id = row.id, s.name = row.name, s.namespace = row.namespace, \
+         s.instance_id = row.instance_id, s.pushed = $pushed";
+
+const UPSERT_OPERATIONS: &str = "UNWIND $rows AS row \
+     MATCH (s:Service {key: row.service}) \
+     MERGE (o:Operation {key: row.key}) \
+     SET o.id = row.id, o.name = row.name, o.pushed = $pushed \
+     MERGE (s)-[r:HAS_OPERATION]->(o) \
+     SET r.pushed = $pushed";
+
+const UPSERT_SERVICE_INVOKES: &str = "UNWIND $rows AS row \
+     MATCH (a:Service {key: row.source}), (b:Service {key: row.target}) \
+     MERGE (a)-[r:INVOKES]->(b) \
+     SET r.count = row.count, r.pushed = $pushed";
+
+const UPSERT_OPERATION_INVOKES: &str = "UNWIND $rows AS row \
+     MATCH (a:Operation {key: row.source}), (b:Operation {key: row.target}) \
+     MERGE (a)-[r:INVOKES]->(b) \
+     SET r.count = row.count, r.pushed = $pushed";
+
+const REMOVE_RELATIONS: &str = "MATCH (:Service)-[r:INVOKES|HAS_OPERATION]->() \
+     WHERE r.pushed <> $pushed DELETE r";
+
+const REMOVE_OPERATION_RELATIONS: &str = "MATCH (:Operation)-[r:INVOKES]->() \
+     WHERE r.pushed <> $pushed DELETE r";
+
+const REMOVE_NODES: &str = "MATCH (n) WHERE (n:Service OR n:Operation) \
+     AND n.pushed <> $pushed DETACH DELETE n";
+
+impl Neo4jSink {
+    pub(crate) fn new(uri: String, user: String, password: String) -> Self {
+        Self {
+            uri,
+            user,
+            password,
+            graph: None,
+        }
+    }
+
+    async fn upsert(&mut self, state: &State) -> Result<(), Error> {
+        let graph = match &self.graph {
+            Some(graph) => graph,
+            None => self.graph.insert(
+                Graph::new(
+                    self.uri.as_str(),
+                    self.user.as_str(),
+                    self.password.as_str(),
+                )
+                .await?,
+            ),
+        };
+
+        let mut services = Vec::new();
+        let mut operations = Vec::new();
+        let mut service_invokes = Vec::new();
+        let mut operation_invokes = Vec::new();
+
+        for (svc_key, svc_state) in &state.services {
+            services.push(row([
+                ("key", svc_key.to_string().into()),
+                ("id", svc_state.id.to_string().into()),
+                ("name", svc_key.name.to_string().into()),
+                (
+                    "namespace",
+                    svc_key.namespace.as_ref().map(|ns| ns.to_string()).into(),
+                ),
+                (
+                    "instance_id",
+                    svc_key.instance_id.as_ref().map(|id| id.to_string()).into(),
+                ),
+            ]));
+
+            for (parent_key, rel) in &svc_state.relations {
+                service_invokes.push(row([
+                    ("source", parent_key.to_string().into()),
+                    ("target", svc_key.to_string().into()),
+                    ("count", (rel.count as i64).into()),
+                ]));
+            }
+
+            for (oper_name, oper_state) in &svc_state.operations {
+                operations.push(row([
+                    ("key", format!("{svc_key}/{oper_name}").into()),
+                    ("service", svc_key.to_string().into()),
+                    ("id", oper_state.id.to_string().into()),
+                    ("name", oper_name.to_string().into()),
+                ]));
+
+                for (parent_key, parent_opers) in &oper_state.relations {
+                    for (parent_oper, rel) in parent_opers {
+                        operation_invokes.push(row([
+                            ("source", format!("{parent_key}/{parent_oper}").into()),
+                            ("target", format!("{svc_key}/{oper_name}").into()),
+                            ("count", (rel.count as i64).into()),
+                        ]));
+                    }
+                }
+            }
+        }
+
+        let pushed = Utc::now().timestamp_millis();
+        let mut txn = graph.start_txn().await?;
+        txn.run_queries([
+            query(UPSERT_SERVICES)
+                .param("rows", services)
+                .param("pushed", pushed),
+            query(UPSERT_OPERATIONS)
+                .param("rows", operations)
+                .param("pushed", pushed),
+            query(UPSERT_SERVICE_INVOKES)
+                .param("rows", service_invokes)
+                .param("pushed", pushed),
+            query(UPSERT_OPERATION_INVOKES)
+                .param("rows", operation_invokes)
+                .param("pushed", pushed),
+            query(REMOVE_RELATIONS).param("pushed", pushed),
+            query(REMOVE_OPERATION_RELATIONS).param("pushed", pushed),
+            query(REMOVE_NODES).param("pushed", pushed),
+        ])
+        .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+}
+
+fn row<const N: usize>(fields: [(&str, BoltType); N]) -> Row {
+    fields
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+impl GraphSink for Neo4jSink {
+    fn name(&self) -> &str {
+        "neo4j"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        state: &'a State,
+        _items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.upsert(state).boxed()
+    }
+}
@@ -0,0 +1,137 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use futures::{future::BoxFuture, FutureExt};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+
+use crate::{discovery::Items, error::Error, state::State};
+
+use super::GraphSink;
+
+/// Sink exposing the dependency graph as gauges on the metrics
+/// endpoint. Series for removed services and dependencies disappear
+/// on the next push.
+pub(crate) struct PrometheusSink {
+    dependencies: IntGaugeVec,
+    operations: IntGaugeVec,
+    service_graph: Option<ServiceGraphMetrics>,
+}
+
+/// Request counters between services, compatible with the metrics
+/// generated by the OpenTelemetry servicegraph connector, as used by
+/// Grafana's service graph.
+struct ServiceGraphMetrics {
+    requests: IntCounterVec,
+    failed: IntCounterVec,
+    /// Totals at the previous push, by client and server name.
+    previous: BTreeMap<(String, String), (u64, u64)>,
+}
+
+impl PrometheusSink {
+    pub(crate) fn new(service_graph: bool) -> Result<Self, Error> {
+        Ok(Self {
+            service_graph: service_graph.then(ServiceGraphMetrics::new).transpose()?,
+            dependencies: register_int_gauge_vec!(
+                "jaeger_discovery_service_dependency",
+                "Discovered invocations between services (always 1).",
+                &["source", "target"]
+            )?,
+            operations: register_int_gauge_vec!(
+                "jaeger_discovery_operations",
+                "Number of discovered operations per service.",
+                &["service"]
+            )?,
+        })
+    }
+
+    fn update(&mut self, state: &State) {
+        self.dependencies.reset();
+        self.operations.reset();
+
+        for (svc_key, svc_state) in &state.services {
+            let service = svc_key.to_string();
+            self.operations
+                .with_label_values(&[&service])
+                .set(svc_state.operations.len() as i64);
+            for parent_key in svc_state.relations.keys() {
+                self.dependencies
+                    .with_label_values(&[&parent_key.to_string(), &service])
+                    .set(1);
+            }
+        }
+
+        if let Some(service_graph) = &mut self.service_graph {
+            service_graph.update(state);
+        }
+    }
+}
+
+impl ServiceGraphMetrics {
+    fn new() -> Result<Self, Error> {
+        Ok(Self {
+            requests: register_int_counter_vec!(
+                "traces_service_graph_request_total",
+                "Total count of requests between two nodes.",
+                &["client", "server"]
+            )?,
+            failed: register_int_counter_vec!(
+                "traces_service_graph_request_failed_total",
+                "Total count of failed requests between two nodes.",
+                &["client", "server"]
+            )?,
+            previous: BTreeMap::new(),
+        })
+    }
+
+    /// Increment the counters by the requests observed since the
+    /// previous push. Relations are counted per service name, summing
+    /// over namespaces and instances.
+    fn update(&mut self, state: &State) {
+        let mut totals = BTreeMap::<_, (u64, u64)>::new();
+        for (svc_key, svc_state) in &state.services {
+            for (parent_key, rel) in &svc_state.relations {
+                let total = totals
+                    .entry((parent_key.name.to_string(), svc_key.name.to_string()))
+                    .or_default();
+                total.0 += rel.count;
+                total.1 += rel.http_status.server_error;
+            }
+        }
+
+        for ((client, server), (requests, failed)) in &totals {
+            let (prev_requests, prev_failed) = self
+                .previous
+                .get(&(client.clone(), server.clone()))
+                .copied()
+                .unwrap_or_default();
+            /* Totals decrease when relations expire; the counters
+             * then continue from the new total. */
+            self.requests
+                .with_label_values(&[client, server])
+                .inc_by(requests.saturating_sub(prev_requests));
+            self.failed
+                .with_label_values(&[client, server])
+                .inc_by(failed.saturating_sub(prev_failed));
+        }
+
+        self.previous = totals;
+    }
+}
+
+impl GraphSink for PrometheusSink {
+    fn name(&self) -> &str {
+        "prometheus"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        state: &'a State,
+        _items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.update(state);
+        async { Ok(()) }.boxed()
+    }
+}
@@ -0,0 +1,136 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Publishing from a background task, so the next discovery cycle can
+//! start while the previous graph is still being uploaded.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::Instrument;
+
+use super::GraphSink;
+use crate::{discovery::Items, state::State};
+
+/// A queue holding at most one graph waiting to be published. If
+/// uploads fall behind, a waiting graph is replaced by the newer one.
+pub(crate) struct PushQueue {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+struct Shared {
+    next: Mutex<Option<PushJob>>,
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+struct PushJob {
+    state: State,
+    items: Items,
+    /// The cycle that produced the graph.
+    span: tracing::Span,
+}
+
+impl PushQueue {
+    /// Start publishing to `sinks` in the background.
+    pub(crate) fn spawn(sinks: Vec<Box<dyn GraphSink>>) -> Self {
+        let shared = Arc::new(Shared {
+            next: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+        let task = tokio::spawn(run(sinks, shared.clone()));
+        Self { shared, task }
+    }
+
+    /// Queue a graph for publishing, replacing any graph still waiting.
+    pub(crate) fn push(&self, state: State, items: Items) {
+        let replaced = self
+            .shared
+            .next
+            .lock()
+            .unwrap()
+            .replace(PushJob {
+                state,
+                items,
+                span: tracing::Span::current(),
+            })
+            .is_some();
+        if replaced {
+            log::warn!("previous graph was not yet published; publishing the latest one instead");
+        }
+        self.shared.notify.notify_one();
+    }
+
+    /// Publish the graph still waiting, if any, and stop the task.
+    pub(crate) async fn close(self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+        if let Err(e) = self.task.await {
+            log::error!("push task failed: {e}");
+        }
+    }
+}
+
+async fn run(mut sinks: Vec<Box<dyn GraphSink>>, shared: Arc<Shared>) {
+    loop {
+        shared.notify.notified().await;
+        loop {
+            let job = shared.next.lock().unwrap().take();
+            let Some(job) = job else { break };
+            for sink in &mut sinks {
+                if let Err(e) = sink.flush().await {
+                    log::warn!("failed to flush {}: {e}", sink.name());
+                }
+                let span = tracing::info_span!(parent: &job.span, "push", sink = sink.name());
+                if let Err(e) = sink.push(&job.state, &job.items).instrument(span).await {
+                    log::warn!("failed to push to {}: {e}", sink.name());
+                }
+            }
+        }
+        if shared.closed.load(Ordering::Acquire) {
+            return;
+        }
+    }
+}
+
+/// A push running while the next cycle scans for spans. Unlike with
+/// the queue, every graph is published and failures are reported.
+pub(crate) struct PushTask(JoinHandle<(Vec<Box<dyn GraphSink>>, usize)>);
+
+impl PushTask {
+    /// Start publishing a graph to `sinks`.
+    pub(crate) fn spawn(mut sinks: Vec<Box<dyn GraphSink>>, state: State, items: Items) -> Self {
+        Self(tokio::spawn(
+            async move {
+                let mut failed = 0;
+                for sink in &mut sinks {
+                    if let Err(e) = sink.flush().await {
+                        log::warn!("failed to flush {}: {e}", sink.name());
+                    }
+                    let span = tracing::info_span!("push", sink = sink.name());
+                    if let Err(e) = sink.push(&state, &items).instrument(span).await {
+                        log::warn!("failed to push to {}: {e}", sink.name());
+                        failed += 1;
+                    }
+                }
+                (sinks, failed)
+            }
+            .in_current_span(),
+        ))
+    }
+
+    /// Wait for the push to finish, returning the sinks and the number
+    /// of sinks that failed.
+    pub(crate) async fn finish(self) -> (Vec<Box<dyn GraphSink>>, usize) {
+        match self.0.await {
+            Ok(res) => res,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::sync::{Arc, Mutex};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{discovery::Items, error::Error, state::State};
+
+use super::GraphSink;
+
+/// Sink keeping every published graph in memory, for tests. Clones
+/// share the recorded graphs, so a clone can be kept to inspect them
+/// after handing the sink to discovery.
+#[derive(Clone, Default)]
+pub(crate) struct RecordingSink {
+    pushed: Arc<Mutex<Vec<Items>>>,
+}
+
+impl RecordingSink {
+    /// The graphs pushed so far, oldest first.
+    pub(crate) fn pushed(&self) -> Vec<Items> {
+        self.pushed.lock().unwrap().clone()
+    }
+}
+
+impl GraphSink for RecordingSink {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        _state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.pushed.lock().unwrap().push(items.clone());
+        async { Ok(()) }.boxed()
+    }
+}
@@ -0,0 +1,755 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
+
+use flate2::{write::GzEncoder, Compression};
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MATCH},
+    Client, RequestBuilder, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    delta::{payload_hash, Fingerprints, ItemsDelta, Partition},
+    diff::RemoteWorld,
+    discovery::Items,
+    error::Error,
+    health, load_json,
+    ratelimit::RateLimiter,
+    rename::Renames,
+    required,
+    retry::RetryPolicy,
+    save_json,
+    state::State,
+    Args,
+};
+
+use super::GraphSink;
+
+/// Sink writing the graph to the ContinuousC Relation Graph Engine.
+pub(crate) struct RelationGraphSink {
+    name: String,
+    client: Client,
+    url: Url,
+    /// Failed payload, kept for retrying (not kept without a state
+    /// directory).
+    pending_path: Option<PathBuf>,
+    delta_updates: bool,
+    chunk_size: Option<usize>,
+    gzip_requests: bool,
+    retry: RetryPolicy,
+    /// Limiter for write requests, shared by all relation graph sinks.
+    limiter: Option<Arc<RateLimiter>>,
+    pushed: Option<Fingerprints>,
+    pushed_hash: Option<u64>,
+    conditional_updates: bool,
+    /// Version of the world after our last update.
+    etag: Option<String>,
+    explicit_deletes: bool,
+    partitioned: bool,
+    known_path: Option<PathBuf>,
+    known: Option<PushedIds>,
+    /// Api version negotiated with the relation graph.
+    api: Option<ApiVersion>,
+    /// Mapping of published names, to read back pending payloads.
+    renames: Arc<Renames>,
+}
+
+/// Relation graph api versions supported by the sink.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ApiVersion {
+    /// Updates are sent as independent requests to `items`.
+    Legacy,
+    /// Updates are sent within a transaction, applied atomically on
+    /// commit.
+    Transactions,
+}
+
+#[derive(Deserialize, Debug)]
+struct VersionInfo {
+    api_version: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Transaction {
+    id: String,
+}
+
+/// Ids of the items and relations after the last successful update,
+/// persisted so removals can be sent after a restart.
+#[derive(Serialize, Deserialize, Debug)]
+struct PushedIds {
+    items: BTreeSet<Uuid>,
+    relations: BTreeSet<Uuid>,
+}
+
+impl RelationGraphSink {
+    /// Create a sink for the relation graph at `url`, or at `--rg-url`
+    /// if not given. Sinks for different urls keep separate pending
+    /// payloads and delta state.
+    pub(crate) fn new(
+        args: &Args,
+        url: Option<&Url>,
+        limiter: Option<Arc<RateLimiter>>,
+        renames: Arc<Renames>,
+    ) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-PROXY-ROLE", HeaderValue::try_from("Editor").unwrap());
+
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(60))
+            .default_headers(headers)
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true) // TODO: disable
+            .build()
+            .map_err(Error::Reqwest)?;
+
+        let (url, suffix) = match url {
+            Some(url) => (url.clone(), format!("-{}", file_name(url))),
+            None => (required(&args.rg_url, "--rg-url")?.clone(), String::new()),
+        };
+
+        Ok(Self {
+            name: format!("relation graph at {url}"),
+            client,
+            url,
+            pending_path: args
+                .state
+                .as_ref()
+                .map(|dir| dir.join(format!("pending{suffix}.json.gz"))),
+            delta_updates: args.delta_updates,
+            chunk_size: args.chunk_size,
+            gzip_requests: args.gzip_requests,
+            retry: RetryPolicy {
+                retries: args.push_retries,
+                backoff: Duration::from_secs(args.push_backoff),
+                max_backoff: Duration::from_secs(args.push_max_backoff),
+            },
+            limiter,
+            pushed: None,
+            pushed_hash: None,
+            conditional_updates: args.conditional_updates,
+            etag: None,
+            explicit_deletes: args.explicit_deletes,
+            partitioned: args.partitioned_pushes,
+            known_path: args
+                .state
+                .as_ref()
+                .map(|dir| dir.join(format!("pushed{suffix}.json.gz"))),
+            known: None,
+            api: None,
+            renames,
+        })
+    }
+
+    /// Negotiate the api version at startup, failing if the relation
+    /// graph is too old or too new. If it cannot be reached, the
+    /// version is negotiated on the first push instead.
+    async fn init_api(&mut self) -> Result<(), Error> {
+        let res = self.negotiate().await;
+        health::relation_graph(&self.name, &res);
+        match res {
+            Ok(_) => Ok(()),
+            Err(e @ Error::UnsupportedApiVersion(..)) => Err(e),
+            Err(e) => {
+                log::warn!("{}: failed to query api version: {e}", self.name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Query the api version of the relation graph. Versions predating
+    /// the `version` endpoint use the legacy api.
+    async fn negotiate(&mut self) -> Result<ApiVersion, Error> {
+        if let Some(api) = self.api {
+            return Ok(api);
+        }
+
+        let res = self
+            .retry
+            .send(&self.name, self.client.get(self.url.join("version")?))
+            .await?;
+        let version = match res.status() {
+            StatusCode::NOT_FOUND => 1,
+            _ => {
+                if let Err(err) = res.error_for_status_ref() {
+                    let msg = res.text().await?;
+                    return Err(Error::RelationGraph(err, msg));
+                }
+                res.json::<VersionInfo>().await?.api_version
+            }
+        };
+
+        let api = match version {
+            1 => ApiVersion::Legacy,
+            2 => ApiVersion::Transactions,
+            _ => return Err(Error::UnsupportedApiVersion(version, 1, 2)),
+        };
+        log::info!("{}: using api version {version}", self.name);
+        self.api = Some(api);
+        Ok(api)
+    }
+
+    /// Retry a payload for which the push failed in a previous cycle
+    /// (or run).
+    async fn push_pending(&mut self) -> Result<(), Error> {
+        let Some(path) = self.pending_path.as_ref().filter(|path| path.exists()) else {
+            return Ok(());
+        };
+
+        log::info!("retrying pending relation graph update");
+        let items = self
+            .renames
+            .parse(load_json(path).await?)
+            .map_err(|e| Error::Deserialize(path.clone(), e))?;
+        self.send(&items).await?;
+        self.remove_pending().await
+    }
+
+    /// Send the world, keeping the payload if the push fails, so it
+    /// can be retried even if the next cycle fails before pushing.
+    async fn push_items(&mut self, items: &Items) -> Result<(), Error> {
+        let pushed = self.send(items).await;
+        health::relation_graph(&self.name, &pushed);
+        match (&pushed, &self.pending_path) {
+            (Ok(()), _) => self.remove_pending().await?,
+            (Err(_), Some(path)) => save_json(path, items).await?,
+            (Err(_), None) => {}
+        }
+        pushed
+    }
+
+    async fn remove_pending(&self) -> Result<(), Error> {
+        let Some(path) = &self.pending_path else {
+            return Ok(());
+        };
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::WriteFile(path.clone(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Send the world to the relation graph, as a delta against the
+    /// previous push if enabled and available, and split in chunks if
+    /// a chunk size was given.
+    async fn send(&mut self, items: &Items) -> Result<(), Error> {
+        match self.send_requests(items).await {
+            Err(Error::Conflict) => {
+                /* Our payload only covers our own domain: a full update
+                 * on top of the latest version keeps the changes made by
+                 * others outside of it, but overwrites those within. */
+                log::warn!(
+                    "{}: graph modified concurrently; overwriting our domain",
+                    self.name
+                );
+                self.etag = None;
+                self.send_requests(items).await
+            }
+            res => res,
+        }
+    }
+
+    async fn send_requests(&mut self, items: &Items) -> Result<(), Error> {
+        let hash = payload_hash(items);
+        if self.pushed_hash == Some(hash) {
+            log::debug!("graph unchanged; skipping relation graph update");
+            return Ok(());
+        }
+        self.pushed_hash = None;
+
+        /* Partitions are meant to be applied independently. */
+        let transaction = match self.negotiate().await? {
+            ApiVersion::Transactions if !self.partitioned => Some(self.begin().await?),
+            _ => None,
+        };
+        let base = match &transaction {
+            Some(id) => self.url.join(&format!("transactions/{id}/"))?,
+            None => self.url.clone(),
+        };
+
+        let url = base.join("items")?;
+        let chunk_size = self.chunk_size.unwrap_or(usize::MAX);
+        let fingerprints = self.delta_updates.then(|| Fingerprints::new(items));
+
+        /* Force a full update after a failed push. */
+        let previous = self.pushed.take();
+        let full = previous.is_none() || fingerprints.is_none();
+        let reqs = match (previous, &fingerprints) {
+            (Some(old), Some(new)) => {
+                let delta = ItemsDelta::new(&old, new, items);
+                log::debug!("sending {} changes to the relation graph", delta.len());
+                delta
+                    .split(chunk_size)
+                    .iter()
+                    .map(|chunk| self.body(self.client.patch(url.clone()), chunk))
+                    .collect::<Vec<_>>()
+            }
+            /* A PUT of the first chunk would leave only that chunk in
+             * the graph until the others are sent (or for good if one
+             * fails), so all chunks are added, and the items no longer
+             * present deleted afterwards. */
+            _ if self.chunk_size.is_some() => ItemsDelta::full(items)
+                .split(chunk_size)
+                .iter()
+                .map(|chunk| self.body(self.client.patch(url.clone()), chunk))
+                .collect(),
+            _ if self.partitioned => Partition::split(items)
+                .iter()
+                .map(|partition| self.body(self.client.put(url.clone()), partition))
+                .collect(),
+            _ => vec![self.body(self.client.put(url), items)],
+        };
+
+        let sent = match self.send_parts(reqs).await {
+            /* Delta updates carry their own removals. */
+            Ok(()) if self.tracks_pushed() && full => self.delete_removed(&base, items).await,
+            res => res,
+        };
+
+        if let Some(id) = &transaction {
+            match &sent {
+                Ok(()) => self.commit(id).await?,
+                Err(_) => self.abort(id).await,
+            }
+        }
+        sent?;
+
+        if self.tracks_pushed() {
+            self.remember_pushed(items).await?;
+        }
+        self.pushed = fingerprints;
+        self.pushed_hash = Some(hash);
+        Ok(())
+    }
+
+    /// Send the requests making up an update.
+    async fn send_parts(&mut self, reqs: Vec<RequestBuilder>) -> Result<(), Error> {
+        if self.conditional_updates && self.etag.is_none() {
+            self.etag = self.fetch_etag().await?;
+        }
+
+        let n = reqs.len();
+        let mut failed = None;
+        for (i, req) in reqs.into_iter().enumerate() {
+            if n > 1 {
+                log::debug!("sending part {}/{n} to the relation graph", i + 1);
+            }
+            let req = match (self.conditional_updates, &self.etag) {
+                (true, Some(etag)) => req.header(IF_MATCH, etag),
+                _ => req,
+            };
+            let res = self.write(req).await?;
+            if self.conditional_updates && res.status() == StatusCode::PRECONDITION_FAILED {
+                return Err(Error::Conflict);
+            }
+            if let Err(err) = res.error_for_status_ref() {
+                let msg = res.text().await?;
+                let err = Error::RelationGraph(err, msg);
+                /* Partitions are independent; let the others update. */
+                if !self.partitioned {
+                    return Err(err);
+                }
+                log::warn!("{}: failed to push part {}/{n}: {err}", self.name, i + 1);
+                failed = Some(err);
+                continue;
+            }
+            self.etag = etag(&res);
+        }
+
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Start a transaction (api version 2).
+    async fn begin(&self) -> Result<String, Error> {
+        let res = self
+            .write(self.client.post(self.url.join("transactions")?))
+            .await?;
+        if let Err(err) = res.error_for_status_ref() {
+            let msg = res.text().await?;
+            return Err(Error::RelationGraph(err, msg));
+        }
+        Ok(res.json::<Transaction>().await?.id)
+    }
+
+    /// Apply the changes sent within a transaction.
+    async fn commit(&mut self, id: &str) -> Result<(), Error> {
+        let req = self
+            .client
+            .post(self.url.join(&format!("transactions/{id}/commit"))?);
+        let req = match (self.conditional_updates, &self.etag) {
+            (true, Some(etag)) => req.header(IF_MATCH, etag),
+            _ => req,
+        };
+        let res = self.write(req).await?;
+        if self.conditional_updates && res.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(Error::Conflict);
+        }
+        if let Err(err) = res.error_for_status_ref() {
+            let msg = res.text().await?;
+            return Err(Error::RelationGraph(err, msg));
+        }
+        self.etag = etag(&res);
+        Ok(())
+    }
+
+    /// Discard a transaction after a failed update.
+    async fn abort(&self, id: &str) {
+        let res = async {
+            let url = self.url.join(&format!("transactions/{id}"))?;
+            self.write(self.client.delete(url))
+                .await?
+                .error_for_status()?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            log::warn!("{}: failed to abort transaction {id}: {e}", self.name);
+        }
+    }
+
+    /// Whether full updates add items rather than replacing the
+    /// domain, so removals are sent explicitly: with
+    /// `--explicit-deletes`, for relation graphs where `PUT` is
+    /// additive, and for chunked updates.
+    fn tracks_pushed(&self) -> bool {
+        self.explicit_deletes || self.chunk_size.is_some()
+    }
+
+    /// Delete items and relations sent in a previous push, but no
+    /// longer present. Without a record of the ids pushed before (on
+    /// the first push without a state directory), there is nothing to
+    /// delete yet.
+    async fn delete_removed(&mut self, base: &Url, items: &Items) -> Result<(), Error> {
+        if self.known.is_none() {
+            match &self.known_path {
+                Some(path) if path.exists() => self.known = Some(load_json(path).await?),
+                _ => {
+                    log::info!(
+                        "{}: no record of pushed ids; tracking removals from this push on",
+                        self.name
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        let known = self.known.as_ref().unwrap();
+        let removed = known
+            .relations
+            .difference(&items.items.relations.keys().copied().collect())
+            .map(|id| format!("relations/{id}"))
+            .chain(
+                known
+                    .items
+                    .difference(&items.items.items.keys().copied().collect())
+                    .map(|id| format!("items/{id}")),
+            )
+            .collect::<Vec<_>>();
+
+        for path in removed {
+            self.delete(base, &path).await?;
+        }
+        Ok(())
+    }
+
+    /// Record the ids in the graph after a successful update, full or
+    /// delta, as the ones to delete when they are removed.
+    async fn remember_pushed(&mut self, items: &Items) -> Result<(), Error> {
+        let known = PushedIds {
+            items: items.items.items.keys().copied().collect(),
+            relations: items.items.relations.keys().copied().collect(),
+        };
+        if let Some(path) = &self.known_path {
+            save_json(path, &known).await?;
+        }
+        self.known = Some(known);
+        Ok(())
+    }
+
+    /// Delete an item or relation, as part of an update. Like the other
+    /// writes, the delete is conditional on the version of the world
+    /// and returns the version after it.
+    async fn delete(&mut self, base: &Url, path: &str) -> Result<(), Error> {
+        log::debug!("{}: deleting {path}", self.name);
+        let req = self.client.delete(base.join(path)?);
+        let req = match (self.conditional_updates, &self.etag) {
+            (true, Some(etag)) => req.header(IF_MATCH, etag),
+            _ => req,
+        };
+        let res = self.write(req).await?;
+        if self.conditional_updates && res.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(Error::Conflict);
+        }
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        if let Err(err) = res.error_for_status_ref() {
+            let msg = res.text().await?;
+            return Err(Error::RelationGraph(err, msg));
+        }
+        self.etag = etag(&res);
+        Ok(())
+    }
+
+    /// Get the world currently in the relation graph.
+    pub(crate) async fn fetch_world(&self) -> Result<RemoteWorld, Error> {
+        let res = self
+            .retry
+            .send(&self.name, self.client.get(self.url.join("items")?))
+            .await?;
+        if let Err(err) = res.error_for_status_ref() {
+            let msg = res.text().await?;
+            return Err(Error::RelationGraph(err, msg));
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Get the current version of the world, without its content.
+    /// Only needed before the first update: every write returns the
+    /// version after it.
+    async fn fetch_etag(&self) -> Result<Option<String>, Error> {
+        let res = self
+            .retry
+            .send(&self.name, self.client.head(self.url.join("items")?))
+            .await?;
+        if let Err(err) = res.error_for_status_ref() {
+            return Err(Error::RelationGraph(err, String::new()));
+        }
+        Ok(etag(&res))
+    }
+
+    /// Send a write request, subject to the rate limit if configured.
+    async fn write(&self, req: RequestBuilder) -> Result<Response, reqwest::Error> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        self.retry.send(&self.name, req).await
+    }
+
+    /// Set a json request body, gzip-compressed if enabled.
+    fn body<T: Serialize>(&self, req: RequestBuilder, value: &T) -> RequestBuilder {
+        if self.gzip_requests {
+            let mut data = Vec::new();
+            serde_json::to_writer(GzEncoder::new(&mut data, Compression::fast()), value).unwrap();
+            req.header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(data)
+        } else {
+            req.json(value)
+        }
+    }
+}
+
+fn etag(res: &Response) -> Option<String> {
+    Some(res.headers().get(ETAG)?.to_str().ok()?.to_string())
+}
+
+/// A file name component identifying the url.
+fn file_name(url: &Url) -> String {
+    url.as_str()
+        .split_once("://")
+        .map_or(url.as_str(), |(_, rest)| rest)
+        .trim_end_matches('/')
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl GraphSink for RelationGraphSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn init(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        self.init_api().boxed()
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        self.push_pending().boxed()
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        _state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.push_items(items).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use clap::Parser;
+    use hyper::{
+        header::{ETAG, IF_MATCH},
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// A relation graph versioning the world like the real one: every
+    /// write requires the current version and returns the next one.
+    #[derive(Default)]
+    struct MockGraph {
+        version: u64,
+        /// Method, path and `If-Match` header of every request.
+        requests: Vec<(Method, String, Option<String>)>,
+    }
+
+    impl MockGraph {
+        fn handle(&mut self, req: &Request<Body>) -> Response<Body> {
+            let if_match = req
+                .headers()
+                .get(IF_MATCH)
+                .map(|value| value.to_str().unwrap().to_string());
+            self.requests.push((
+                req.method().clone(),
+                req.uri().path().to_string(),
+                if_match.clone(),
+            ));
+            let status = match (req.method(), req.uri().path()) {
+                (&Method::GET, "/version") => StatusCode::NOT_FOUND,
+                (&Method::HEAD, "/items") => StatusCode::OK,
+                (&Method::PUT | &Method::DELETE, _)
+                    if if_match != Some(format!("v{}", self.version)) =>
+                {
+                    StatusCode::PRECONDITION_FAILED
+                }
+                (&Method::PUT | &Method::DELETE, _) => {
+                    self.version += 1;
+                    StatusCode::OK
+                }
+                _ => StatusCode::NOT_FOUND,
+            };
+            Response::builder()
+                .status(status)
+                .header(ETAG, format!("v{}", self.version))
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+
+    async fn serve(graph: Arc<Mutex<MockGraph>>) -> SocketAddr {
+        let make_svc = make_service_fn(move |_conn| {
+            let graph = graph.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let res = graph.lock().unwrap().handle(&req);
+                    async move { Ok::<_, Infallible>(res) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap()
+            .serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn sink(addr: SocketAddr) -> RelationGraphSink {
+        let args = Args::parse_from([
+            "jaeger-discovery",
+            "--es-url=http://es",
+            "--es-ca=ca.pem",
+            "--es-cert=cert.pem",
+            "--es-key=key.pem",
+            "--no-state",
+            "--conditional-updates",
+            "--explicit-deletes",
+            "--push-retries=0",
+            &format!("--rg-url=http://{addr}/"),
+        ]);
+        RelationGraphSink::new(
+            &args,
+            None,
+            None,
+            Arc::new(Renames::new(&Config::default())),
+        )
+        .unwrap()
+    }
+
+    fn items(services: &[u128]) -> Items {
+        serde_json::from_value(json!({
+            "domain": {
+                "roots": null,
+                "types": { "items": ["jaeger/service"], "relations": [] },
+            },
+            "items": {
+                "items": services
+                    .iter()
+                    .map(|n| (Uuid::from_u128(*n).to_string(), json!({
+                        "item_type": "jaeger/service",
+                        "properties": { "jaeger/service_name": { "string": n.to_string() } },
+                    })))
+                    .collect::<serde_json::Map<_, _>>(),
+                "relations": {},
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn deletes_carry_the_latest_version() {
+        let graph = Arc::new(Mutex::new(MockGraph::default()));
+        let mut sink = sink(serve(graph.clone()).await);
+
+        sink.send(&items(&[1, 2, 3])).await.unwrap();
+        sink.send(&items(&[1])).await.unwrap();
+
+        let requests = graph
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|(method, _, _)| method != Method::GET)
+            .map(|(method, path, if_match)| (method.to_string(), path.clone(), if_match.clone()))
+            .collect::<Vec<_>>();
+        let request = |method: &str, path: String, version: Option<u64>| {
+            (method.to_string(), path, version.map(|v| format!("v{v}")))
+        };
+        assert_eq!(
+            requests,
+            [
+                request("HEAD", String::from("/items"), None),
+                request("PUT", String::from("/items"), Some(0)),
+                request("PUT", String::from("/items"), Some(1)),
+                request("DELETE", format!("/items/{}", Uuid::from_u128(2)), Some(2)),
+                request("DELETE", format!("/items/{}", Uuid::from_u128(3)), Some(3)),
+            ]
+        );
+        assert_eq!(sink.etag.as_deref(), Some("v4"));
+    }
+
+    #[tokio::test]
+    async fn stale_delete_is_a_conflict() {
+        let graph = Arc::new(Mutex::new(MockGraph::default()));
+        let mut sink = sink(serve(graph.clone()).await);
+        sink.etag = Some(String::from("v7"));
+
+        let base = sink.url.clone();
+        let res = sink.delete(&base, "items/1").await;
+        assert!(matches!(res, Err(Error::Conflict)), "{res:?}");
+    }
+}
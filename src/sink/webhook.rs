@@ -0,0 +1,92 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::Client;
+use serde_json::json;
+use url::Url;
+
+use crate::{
+    diff::{GraphDiff, Snapshot},
+    discovery::Items,
+    error::Error,
+    state::State,
+};
+
+use super::GraphSink;
+
+/// Sink posting a summary of the changes to a webhook whenever the
+/// graph changes between cycles.
+pub(crate) struct WebhookSink {
+    client: Client,
+    url: Url,
+    headers: BTreeMap<String, String>,
+    /// The graph as last notified successfully.
+    previous: Option<Snapshot>,
+}
+
+impl WebhookSink {
+    pub(crate) fn new(url: Url, headers: BTreeMap<String, String>) -> Result<Self, Error> {
+        Ok(Self {
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()?,
+            url,
+            headers,
+            previous: None,
+        })
+    }
+
+    async fn notify(&mut self, items: &Items) -> Result<(), Error> {
+        let snapshot = Snapshot::new(&items.items);
+        let previous = match &self.previous {
+            Some(previous) => previous,
+            None => {
+                self.previous = Some(snapshot);
+                return Ok(());
+            }
+        };
+
+        let diff = GraphDiff::new(previous, &snapshot);
+        if diff.is_empty() {
+            return Ok(());
+        }
+
+        let req = self
+            .headers
+            .iter()
+            .fold(self.client.post(self.url.clone()), |req, (name, value)| {
+                req.header(name, value)
+            });
+        let res = req
+            .json(&json!({
+                "event": "topology_changed",
+                "time": Utc::now(),
+                "diff": diff,
+            }))
+            .send()
+            .await?;
+        res.error_for_status()?;
+
+        self.previous = Some(snapshot);
+        Ok(())
+    }
+}
+
+impl GraphSink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn push<'a>(
+        &'a mut self,
+        _state: &'a State,
+        items: &'a Items,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.notify(items).boxed()
+    }
+}
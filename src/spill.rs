@@ -0,0 +1,163 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Spilling of the trace map to disk, so long lookback windows do not
+//! require keeping all traces in progress in memory.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    state::{TraceId, TraceInfo},
+};
+
+/// Traces moved out of the trace map into an embedded key-value store.
+/// The times they were last seen are kept in memory, so they can be
+/// expired without reading them back.
+pub(crate) struct TraceSpill {
+    path: PathBuf,
+    db: sled::Db,
+    /// Number of traces kept in memory.
+    limit: usize,
+    index: HashMap<TraceId, Spilled>,
+}
+
+/// The fields of a spilled trace needed to expire it.
+#[derive(Deserialize, Clone, Copy)]
+pub(crate) struct Spilled {
+    pub(crate) last_seen: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) touched: Option<DateTime<Utc>>,
+}
+
+impl TraceSpill {
+    /// Open the store, indexing traces spilled by a previous run.
+    pub(crate) fn open(path: PathBuf, limit: usize) -> Result<Self, Error> {
+        let db = sled::open(&path)?;
+        let mut index = HashMap::new();
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            match (
+                String::from_utf8(key.to_vec()),
+                serde_json::from_slice::<Spilled>(&value),
+            ) {
+                (Ok(id), Ok(spilled)) => {
+                    index.insert(TraceId(id), spilled);
+                }
+                _ => {
+                    db.remove(key)?;
+                }
+            }
+        }
+        if !index.is_empty() {
+            log::info!("found {} spilled traces", index.len());
+        }
+        Ok(Self {
+            path,
+            db,
+            limit,
+            index,
+        })
+    }
+
+    /// Move the oldest traces to disk while the trace map exceeds the
+    /// limit, returning their ids.
+    pub(crate) fn spill(
+        &mut self,
+        traces: &mut HashMap<TraceId, TraceInfo>,
+    ) -> Result<Vec<TraceId>, Error> {
+        if traces.len() <= self.limit {
+            return Ok(Vec::new());
+        }
+
+        let mut oldest = traces
+            .iter()
+            .map(|(id, info)| (info.last_seen, id.clone()))
+            .collect::<Vec<_>>();
+        oldest.sort();
+
+        let excess = traces.len() - self.limit;
+        let mut batch = sled::Batch::default();
+        let mut spilled = Vec::with_capacity(excess);
+        for (_, id) in oldest.into_iter().take(excess) {
+            if let Some(info) = traces.remove(&id) {
+                batch.insert(id.0.as_bytes(), serde_json::to_vec(&info).unwrap());
+                spilled.push(id.clone());
+                self.index.insert(
+                    id,
+                    Spilled {
+                        last_seen: info.last_seen,
+                        touched: info.touched,
+                    },
+                );
+            }
+        }
+        self.db.apply_batch(batch)?;
+        log::debug!(
+            "spilled {excess} traces to disk ({} in total)",
+            self.index.len()
+        );
+        Ok(spilled)
+    }
+
+    /// Move a spilled trace back into the trace map, returning whether
+    /// it was spilled. If the trace was seen again in the meantime, the
+    /// spilled spans are merged into it, so pending relations and
+    /// external calls are not lost.
+    pub(crate) fn page_in(
+        &mut self,
+        id: &TraceId,
+        traces: &mut HashMap<TraceId, TraceInfo>,
+    ) -> Result<bool, Error> {
+        if self.index.remove(id).is_none() {
+            return Ok(false);
+        }
+        if let Some(info) = self.take(id)? {
+            match traces.get_mut(id) {
+                Some(trace) => {
+                    trace.last_seen = trace.last_seen.max(info.last_seen);
+                    for (span_id, span_info) in info.spans {
+                        trace.spans.entry(span_id).or_insert(span_info);
+                    }
+                }
+                None => {
+                    traces.insert(id.clone(), info);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Remove the spilled traces that have expired, returning them.
+    pub(crate) fn expire(
+        &mut self,
+        expired: impl Fn(&Spilled) -> bool,
+    ) -> Result<Vec<TraceInfo>, Error> {
+        let ids = self
+            .index
+            .iter()
+            .filter(|(_, spilled)| expired(spilled))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        let mut traces = Vec::with_capacity(ids.len());
+        for id in ids {
+            self.index.remove(&id);
+            traces.extend(self.take(&id)?);
+        }
+        Ok(traces)
+    }
+
+    fn take(&self, id: &TraceId) -> Result<Option<TraceInfo>, Error> {
+        self.db
+            .remove(id.0.as_bytes())?
+            .map(|data| {
+                serde_json::from_slice(&data).map_err(|e| Error::Deserialize(self.path.clone(), e))
+            })
+            .transpose()
+    }
+}
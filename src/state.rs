@@ -2,107 +2,618 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::{collections::BTreeMap, convert::Infallible, fmt::Display, str::FromStr};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::Infallible,
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+};
 
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use hashbrown::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 use uuid::Uuid;
 
-use crate::discovery::ServiceMeta;
+use crate::{
+    discovery::{ServiceMeta, Span},
+    intern::Name,
+    migrate::STATE_VERSION,
+};
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub(crate) struct TraceId(pub(crate) String);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub(crate) struct SpanId(pub(crate) String);
+
+/* For looking up ids in the trace and span maps without cloning them,
+ * unless they are inserted. */
+
+impl From<&TraceId> for TraceId {
+    fn from(id: &TraceId) -> Self {
+        id.clone()
+    }
+}
+
+impl From<&SpanId> for SpanId {
+    fn from(id: &SpanId) -> Self {
+        id.clone()
+    }
+}
+
+/// Position of the span query: the `startTime` (in microseconds) and
+/// the id of the last span fetched, by which the spans are sorted.
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct TraceId(String);
+#[serde(from = "QueryPositionRepr")]
+pub(crate) struct QueryPosition(pub(crate) i64, pub(crate) SpanId);
+
+/// Positions were written as a bare `startTime` before spans were also
+/// sorted by id.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QueryPositionRepr {
+    Time(i64),
+    Span(i64, SpanId),
+}
+
+impl QueryPosition {
+    /// The position after all spans started at or before `time`.
+    pub(crate) fn after(time: i64) -> Self {
+        Self(time + 1, SpanId(String::new()))
+    }
+}
+
+impl From<QueryPositionRepr> for QueryPosition {
+    fn from(repr: QueryPositionRepr) -> Self {
+        match repr {
+            QueryPositionRepr::Time(time) => Self::after(time),
+            QueryPositionRepr::Span(time, id) => Self(time, id),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct SpanId(String);
+pub(crate) struct ServiceNamespace(pub(crate) Name);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct ServiceNamespace(pub(crate) String);
+pub(crate) struct ServiceName(pub(crate) Name);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct ServiceName(String);
+pub(crate) struct ServiceInstanceId(pub(crate) Name);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct ServiceInstanceId(pub(crate) String);
+pub(crate) struct OperationName(Name);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
-pub(crate) struct OperationName(String);
+pub(crate) struct DestinationName(pub(crate) Name);
 
 #[derive(SerializeDisplay, DeserializeFromStr, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
 pub(crate) struct ServiceKey {
     pub(crate) namespace: Option<ServiceNamespace>,
     pub(crate) name: ServiceName,
     pub(crate) instance_id: Option<ServiceInstanceId>,
+    /// Additional (configured) tags identifying the service, shared
+    /// between the clones of the key.
+    pub(crate) qualifiers: Arc<[(Name, Name)]>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub(crate) struct State {
-    pub(crate) traces: BTreeMap<TraceId, TraceInfo>,
+    /// Schema version (see `migrate`).
+    #[serde(default)]
+    pub(crate) version: u32,
+    pub(crate) traces: HashMap<TraceId, TraceInfo>,
+    /// Spans of traces that have not been idle long enough to be
+    /// processed (with `--quiescence`).
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) buffered: HashMap<TraceId, BufferedTrace>,
     pub(crate) services: BTreeMap<ServiceKey, ServiceState>,
+    #[serde(default)]
+    pub(crate) destinations: BTreeMap<DestinationName, DestinationState>,
+    /// Endpoints outside the mesh, by host.
+    #[serde(default)]
+    pub(crate) external_endpoints: BTreeMap<String, ExternalEndpointState>,
+    /// Items derived by custom rules, by rule name and rendered key.
+    #[serde(default)]
+    pub(crate) custom_items: BTreeMap<String, CustomItemState>,
     pub(crate) last_span: Option<DateTime<Utc>>,
+    /// Positions of the slices of the span query (a single one if the
+    /// query is not sliced).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) slices: Option<Vec<Option<QueryPosition>>>,
+    /// The last successful discovery cycle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_run: Option<RunInfo>,
+    /// Results of the most recent cycles, oldest first.
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub(crate) history: VecDeque<RunRecord>,
+}
+
+/// Result of a discovery cycle, successful or not.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RunRecord {
+    pub(crate) time: DateTime<Utc>,
+    /// Number of spans processed in the cycle.
+    pub(crate) spans: u64,
+    /// Number of items and relations built, if the cycle got that far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) items: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) relations: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RunInfo {
+    /// Id of the `jaeger/discovery` item.
+    pub(crate) id: Uuid,
+    pub(crate) time: DateTime<Utc>,
+    /// Number of spans processed in the cycle.
+    pub(crate) spans: u64,
+    /// Host name of the Opensearch cluster the spans were read from.
+    pub(crate) source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BufferedTrace {
+    pub(crate) last_seen: DateTime<Utc>,
+    #[serde(deserialize_with = "owned_spans")]
+    pub(crate) spans: Vec<Span<'static>>,
+}
+
+/// Deserialize spans without borrowing from the input.
+fn owned_spans<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Span<'static>>, D::Error> {
+    Ok(Vec::<Span>::deserialize(deserializer)?
+        .into_iter()
+        .map(Span::into_owned)
+        .collect())
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct TraceInfo {
     pub(crate) last_seen: DateTime<Utc>,
-    pub(crate) spans: BTreeMap<SpanId, SpanInfo>,
+    /// Start of the cycle in which the trace was last updated, when
+    /// traces are also expired by wall-clock time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) touched: Option<DateTime<Utc>>,
+    pub(crate) spans: HashMap<SpanId, SpanInfo>,
+}
+
+impl TraceInfo {
+    /// Rough estimate of the memory used by the trace.
+    pub(crate) fn estimated_size(&self) -> usize {
+        std::mem::size_of::<(TraceId, TraceInfo)>()
+            + self
+                .spans
+                .iter()
+                .map(|(id, span)| {
+                    std::mem::size_of::<(SpanId, SpanInfo)>()
+                        + id.0.len()
+                        + (span.parent_of.len() + span.linked_by.len())
+                            * std::mem::size_of::<RelationTarget>()
+                })
+                .sum::<usize>()
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub(crate) struct SpanInfo {
     pub(crate) key: Option<OperationKey>,
     #[serde(default)]
-    pub(crate) parent_of: Vec<OperationKey>,
+    pub(crate) parent_of: Vec<RelationTarget>,
+    #[serde(default)]
+    pub(crate) linked_by: Vec<RelationTarget>,
+    /// Messaging destination, for producer spans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) destination: Option<DestinationName>,
+    /// Called endpoint, for client spans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) external: Option<ExternalCall>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) has_children: bool,
 }
 
+/// A client call, considered external if no child span is seen
+/// before the trace expires.
 #[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ExternalCall {
+    pub(crate) service_key: ServiceKey,
+    pub(crate) host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) http_status: Option<u16>,
+    pub(crate) t: DateTime<Utc>,
+}
+
+/// A span waiting for its parent (or linked) span to be seen.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub(crate) struct RelationTarget {
+    #[serde(flatten)]
+    pub(crate) key: OperationKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) http_status: Option<u16>,
+    /// Whether the waiting span is a consumer span.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) consumer: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
 pub(crate) struct OperationKey {
     pub(crate) service_key: ServiceKey,
     pub(crate) operation_name: OperationName,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct ServiceState {
     pub(crate) id: Uuid,
     #[serde(default)]
     pub(crate) meta: ServiceMeta,
+    #[serde(default)]
+    pub(crate) last_seen: Option<DateTime<Utc>>,
+    /// Active instances, when instances are merged into one service.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) instances: BTreeMap<ServiceInstanceId, DateTime<Utc>>,
     pub(crate) relations: BTreeMap<ServiceKey, RelationState>,
+    #[serde(default)]
+    pub(crate) links: BTreeMap<ServiceKey, RelationState>,
+    #[serde(default)]
+    pub(crate) produces: BTreeMap<DestinationName, ProducesState>,
     pub(crate) operations: BTreeMap<OperationName, OperationState>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct OperationState {
     pub(crate) id: Uuid,
     pub(crate) relations: BTreeMap<ServiceKey, BTreeMap<OperationName, RelationState>>,
+    #[serde(default)]
+    pub(crate) links: BTreeMap<ServiceKey, BTreeMap<OperationName, RelationState>>,
+    /// Number of spans observed per span kind.
+    #[serde(default)]
+    pub(crate) span_kinds: BTreeMap<SpanKind, u64>,
     pub(crate) last_seen: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SpanKind {
+    Server,
+    Client,
+    Producer,
+    Consumer,
+    Internal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct RelationState {
     pub(crate) id: Uuid,
     pub(crate) last_seen: DateTime<Utc>,
+    /// Number of observations since the relation was discovered.
+    #[serde(default)]
+    pub(crate) count: u64,
+    #[serde(default)]
+    pub(crate) http_status: HttpStatusCounts,
+    /// Number of observations per hour, by the start of the hour,
+    /// within the retention period.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) hourly: BTreeMap<DateTime<Utc>, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DestinationState {
+    pub(crate) id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) system: Option<String>,
+    pub(crate) last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ProducesState {
+    pub(crate) id: Uuid,
+    pub(crate) last_seen: DateTime<Utc>,
+    /// Last time a consumer span was seen for one of the produced
+    /// messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) consumer_last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ExternalEndpointState {
+    pub(crate) id: Uuid,
+    pub(crate) last_seen: DateTime<Utc>,
+    /// Templated paths called on the endpoint.
+    #[serde(default)]
+    pub(crate) paths: BTreeSet<String>,
+    pub(crate) callers: BTreeMap<ServiceKey, RelationState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CustomItemState {
+    pub(crate) id: Uuid,
+    pub(crate) item_type: String,
+    #[serde(default)]
+    pub(crate) properties: BTreeMap<String, String>,
+    pub(crate) last_seen: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) relations: BTreeMap<ServiceKey, CustomRelationState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CustomRelationState {
+    pub(crate) id: Uuid,
+    pub(crate) relation_type: String,
+    #[serde(default)]
+    pub(crate) properties: BTreeMap<String, String>,
+    pub(crate) last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct HttpStatusCounts {
+    #[serde(default)]
+    pub(crate) informational: u64,
+    #[serde(default)]
+    pub(crate) success: u64,
+    #[serde(default)]
+    pub(crate) redirection: u64,
+    #[serde(default)]
+    pub(crate) client_error: u64,
+    #[serde(default)]
+    pub(crate) server_error: u64,
 }
 
 impl State {
     pub(crate) fn new() -> Self {
-        State::default()
+        State {
+            version: STATE_VERSION,
+            ..State::default()
+        }
+    }
+
+    /// Copy of the discovered topology, leaving out in-progress traces.
+    pub(crate) fn topology(&self) -> Self {
+        Self {
+            version: self.version,
+            traces: HashMap::new(),
+            buffered: HashMap::new(),
+            services: self.services.clone(),
+            destinations: self.destinations.clone(),
+            external_endpoints: self.external_endpoints.clone(),
+            custom_items: self.custom_items.clone(),
+            last_span: self.last_span,
+            slices: self.slices.clone(),
+            last_run: self.last_run.clone(),
+            history: self.history.clone(),
+        }
+    }
+
+    /// Add a cycle to the run history, keeping the last `len` cycles.
+    pub(crate) fn record_run(&mut self, record: RunRecord, len: usize) {
+        self.history.push_back(record);
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    /// Remove duplicate pending relations from the span map. Returns
+    /// the number of entries removed.
+    pub(crate) fn dedup(&mut self) -> usize {
+        let mut removed = 0;
+        for span in self.traces.values_mut().flat_map(|t| t.spans.values_mut()) {
+            for targets in [&mut span.parent_of, &mut span.linked_by] {
+                let len = targets.len();
+                targets.sort();
+                targets.dedup();
+                removed += len - targets.len();
+            }
+        }
+        removed
+    }
+
+    /// The number of traces, spans, services, operations and
+    /// relations.
+    pub(crate) fn counts(&self) -> StateCounts {
+        StateCounts {
+            traces: self.traces.len() + self.buffered.len(),
+            spans: self.traces.values().map(|t| t.spans.len()).sum::<usize>()
+                + self.buffered.values().map(|t| t.spans.len()).sum::<usize>(),
+            services: self.services.len(),
+            operations: self.services.values().map(|s| s.operations.len()).sum(),
+            relations: self
+                .services
+                .values()
+                .map(|s| {
+                    s.relations.len()
+                        + s.links.len()
+                        + s.produces.len()
+                        + s.operations
+                            .values()
+                            .flat_map(|o| o.relations.values().chain(o.links.values()))
+                            .map(BTreeMap::len)
+                            .sum::<usize>()
+                })
+                .sum(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct StateCounts {
+    pub(crate) traces: usize,
+    pub(crate) spans: usize,
+    pub(crate) services: usize,
+    pub(crate) operations: usize,
+    pub(crate) relations: usize,
+}
+
+impl SpanInfo {
+    /// The destination of this producer span consumed by `target`. Only
+    /// consumer spans following a producer span show that the produced
+    /// messages are consumed.
+    pub(crate) fn consumed_by(&self, target: &RelationTarget) -> Option<&DestinationName> {
+        self.destination.as_ref().filter(|_| target.consumer)
+    }
+}
+
+impl ServiceState {
+    /// The last time the service was seen. State written by older
+    /// versions lacks the service's timestamp; fall back to its
+    /// operations.
+    pub(crate) fn last_activity(&self) -> Option<DateTime<Utc>> {
+        self.last_seen.or_else(|| {
+            self.operations
+                .values()
+                .map(|oper_state| oper_state.last_seen)
+                .max()
+        })
+    }
+}
+
+impl OperationState {
+    /// The most frequently observed span kind.
+    pub(crate) fn dominant_span_kind(&self) -> Option<SpanKind> {
+        self.span_kinds
+            .iter()
+            .max_by_key(|(_, n)| **n)
+            .map(|(kind, _)| *kind)
+    }
+}
+
+impl RelationState {
+    pub(crate) fn new(t: DateTime<Utc>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            last_seen: t,
+            count: 0,
+            http_status: HttpStatusCounts::default(),
+            hourly: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, t: DateTime<Utc>, http_status: Option<u16>) {
+        self.last_seen = self.last_seen.max(t);
+        self.count += 1;
+        if let Some(status) = http_status {
+            self.http_status.add(status);
+        }
+        let hour = t.duration_trunc(TimeDelta::hours(1)).unwrap_or(t);
+        *self.hourly.entry(hour).or_default() += 1;
+    }
+
+    /// Drop the hourly counts from before the threshold. Returns
+    /// whether the relation was seen since.
+    pub(crate) fn expire(&mut self, threshold: DateTime<Utc>) -> bool {
+        self.hourly = self.hourly.split_off(
+            &threshold
+                .duration_trunc(TimeDelta::hours(1))
+                .unwrap_or(threshold),
+        );
+        self.last_seen >= threshold
+    }
+
+    /// Whether all observations in the retention period fell within
+    /// a single hour. Relations recorded before hourly counts were
+    /// kept are not considered one-offs.
+    pub(crate) fn is_one_off(&self) -> bool {
+        self.hourly.len() == 1
+    }
+
+    /// Number of observations in the hourly buckets starting at or
+    /// after `from` and before `to`.
+    pub(crate) fn observations(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> u64 {
+        self.hourly.range(from..to).map(|(_, n)| n).sum()
+    }
+}
+
+impl RelationState {
+    /// A score between 0 and 1, growing logarithmically with the number
+    /// of observations (reaching 1 at a thousand) and decaying linearly
+    /// with the time since the last observation over the retention
+    /// period.
+    pub(crate) fn confidence(&self, now: DateTime<Utc>, retention: TimeDelta) -> f64 {
+        let volume = ((1.0 + self.count.max(1) as f64).log10() / 3.0).min(1.0);
+        let age = (now - self.last_seen).num_seconds().max(0) as f64;
+        let recency = (1.0 - age / retention.num_seconds().max(1) as f64).max(0.0);
+        volume * recency
+    }
+}
+
+impl HttpStatusCounts {
+    pub(crate) fn add(&mut self, status: u16) {
+        match status {
+            100..=199 => self.informational += 1,
+            200..=299 => self.success += 1,
+            300..=399 => self.redirection += 1,
+            400..=499 => self.client_error += 1,
+            500..=599 => self.server_error += 1,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.informational == 0
+            && self.success == 0
+            && self.redirection == 0
+            && self.client_error == 0
+            && self.server_error == 0
     }
 }
 
 impl Display for ServiceKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(ns) = &self.namespace {
-            write!(f, "{ns}/")?;
+            write!(f, "{}/", escape_key(ns.0.as_ref()))?;
         }
-        write!(f, "{}", self.name)?;
+        write!(f, "{}", escape_key(self.name.0.as_ref()))?;
         if let Some(inst) = &self.instance_id {
-            write!(f, " {inst}")?;
+            write!(f, " {}", escape_key(inst.0.as_ref()))?;
+        }
+        if !self.qualifiers.is_empty() {
+            write!(
+                f,
+                "#{}",
+                url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(self.qualifiers.iter())
+                    .finish()
+            )?;
         }
         Ok(())
     }
 }
 
+impl Display for SpanKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpanKind::Server => write!(f, "server"),
+            SpanKind::Client => write!(f, "client"),
+            SpanKind::Producer => write!(f, "producer"),
+            SpanKind::Consumer => write!(f, "consumer"),
+            SpanKind::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+impl OperationName {
+    pub(crate) fn new(name: &str) -> Self {
+        Self(Name::new(name))
+    }
+}
+
+impl Display for OperationName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for DestinationName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl Display for ServiceNamespace {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -124,15 +635,28 @@ impl Display for ServiceInstanceId {
 impl FromStr for ServiceKey {
     type Err = Infallible;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        /* Keys written before qualifiers were introduced may contain
+         * an unescaped `#`. */
+        let qualified = s
+            .rsplit_once('#')
+            .filter(|(_, qs)| qs.split('&').all(|q| q.contains('=')));
+        let (s, qualifiers) = qualified.map_or((s, Arc::default()), |(s, qs)| {
+            (
+                s,
+                url::form_urlencoded::parse(qs.as_bytes())
+                    .map(|(k, v)| (Name::from(k.as_ref()), Name::from(v.as_ref())))
+                    .collect(),
+            )
+        });
         let (namespace, s) = s.split_once('/').map_or((None, s), |(ns, s)| {
-            (Some(ServiceNamespace(ns.to_string())), s)
+            (Some(ServiceNamespace(Name::new(&unescape_key(ns)))), s)
         });
         let (name, instance_id) = s.split_once(' ').map_or_else(
-            || (ServiceName(s.to_string()), None),
+            || (ServiceName(Name::new(&unescape_key(s))), None),
             |(name, id)| {
                 (
-                    ServiceName(name.to_string()),
-                    Some(ServiceInstanceId(id.to_string())),
+                    ServiceName(Name::new(&unescape_key(name))),
+                    Some(ServiceInstanceId(Name::new(&unescape_key(id)))),
                 )
             },
         );
@@ -140,6 +664,111 @@ impl FromStr for ServiceKey {
             namespace,
             name,
             instance_id,
+            qualifiers,
         })
     }
 }
+
+/// Escape the `#` separating the qualifiers from the rest of a
+/// service key (and the escape character itself).
+fn escape_key(s: &str) -> Cow<'_, str> {
+    match s.contains(['%', '#']) {
+        true => Cow::Owned(s.replace('%', "%25").replace('#', "%23")),
+        false => Cow::Borrowed(s),
+    }
+}
+
+fn unescape_key(s: &str) -> Cow<'_, str> {
+    match s.contains('%') {
+        true => Cow::Owned(s.replace("%23", "#").replace("%25", "%")),
+        false => Cow::Borrowed(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn target(consumer: bool) -> RelationTarget {
+        RelationTarget {
+            key: serde_json::from_value(json!({
+                "service_key": "billing",
+                "operation_name": "receive",
+            }))
+            .unwrap(),
+            http_status: None,
+            consumer,
+        }
+    }
+
+    #[test]
+    fn only_consumer_spans_consume() {
+        let producer = SpanInfo {
+            destination: Some(DestinationName(Name::new("orders"))),
+            ..SpanInfo::default()
+        };
+        assert_eq!(
+            producer.consumed_by(&target(true)),
+            producer.destination.as_ref()
+        );
+        assert_eq!(producer.consumed_by(&target(false)), None);
+        assert_eq!(SpanInfo::default().consumed_by(&target(true)), None);
+    }
+
+    fn service_key(name: &str, qualifiers: &[(&str, &str)]) -> ServiceKey {
+        ServiceKey {
+            namespace: Some(ServiceNamespace(Name::new("ns#1"))),
+            name: ServiceName(Name::new(name)),
+            instance_id: Some(ServiceInstanceId(Name::new("i%1"))),
+            qualifiers: qualifiers
+                .iter()
+                .map(|(k, v)| (Name::new(k), Name::new(v)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn service_key_round_trips() {
+        for key in [
+            service_key("svc", &[]),
+            service_key("svc#2%23", &[]),
+            service_key("svc#2", &[("k8s.cluster.name", "a#b")]),
+        ] {
+            assert_eq!(key.to_string().parse::<ServiceKey>().unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn service_key_keeps_unqualified_names() {
+        let key = "shop/cart#1".parse::<ServiceKey>().unwrap();
+        assert_eq!(key.name.0.as_ref(), "cart#1");
+        assert!(key.qualifiers.is_empty());
+    }
+
+    #[test]
+    fn query_position_reads_bare_start_time() {
+        let position = serde_json::from_str::<QueryPosition>("1000").unwrap();
+        assert_eq!(position, QueryPosition(1001, SpanId(String::new())));
+        assert_eq!(position, QueryPosition::after(1000));
+    }
+
+    #[test]
+    fn query_position_round_trips() {
+        let position = QueryPosition(1000, SpanId(String::from("abc")));
+        let data = serde_json::to_string(&position).unwrap();
+        assert_eq!(data, r#"[1000,"abc"]"#);
+        assert_eq!(
+            serde_json::from_str::<QueryPosition>(&data).unwrap(),
+            position
+        );
+    }
+
+    #[test]
+    fn query_position_after_sorts_after_spans_at_time() {
+        let at = QueryPosition(1000, SpanId(String::from("ffff")));
+        assert!(QueryPosition::after(1000) > at);
+        assert!(QueryPosition::after(999) <= QueryPosition(1000, SpanId(String::new())));
+    }
+}
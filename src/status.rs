@@ -0,0 +1,90 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Status of the last discovery cycle, written uncompressed as
+//! `status.json` in the state directory after every cycle, so its
+//! freshness can be checked without parsing the logs or decoding the
+//! state.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    state::{RunRecord, State, StateCounts},
+};
+
+/// Name of the status file in the state directory.
+pub(crate) const STATUS_FILE: &str = "status.json";
+
+#[derive(Serialize)]
+pub(crate) struct Status {
+    /// Start and end of the cycle.
+    time: DateTime<Utc>,
+    finished: DateTime<Utc>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Start of the last successful cycle in the run history, if any.
+    last_success: Option<DateTime<Utc>>,
+    /// Number of spans processed in the cycle.
+    spans: u64,
+    /// Number of items and relations built, if the cycle got that far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relations: Option<usize>,
+    /// Size of the state after the cycle.
+    state: StateCounts,
+}
+
+impl Status {
+    /// The status of the cycle of `record`, before it is added to the
+    /// run history of `state`.
+    pub(crate) fn new(record: &RunRecord, state: &State) -> Self {
+        Self {
+            time: record.time,
+            finished: record.time,
+            success: record.error.is_none(),
+            error: record.error.clone(),
+            last_success: state
+                .history
+                .iter()
+                .rev()
+                .find(|record| record.error.is_none())
+                .map(|record| record.time),
+            spans: record.spans,
+            items: record.items,
+            relations: record.relations,
+            state: state.counts(),
+        }
+    }
+
+    /// Complete the status with the result of saving the state, which
+    /// ends the cycle.
+    pub(crate) fn saved(&mut self, res: &Result<(), Error>) {
+        self.finished = Utc::now();
+        match res {
+            Ok(()) if self.success => self.last_success = Some(self.time),
+            Ok(()) => {}
+            Err(e) => {
+                self.success = false;
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Replace the status file in `dir`, on a blocking thread.
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join(STATUS_FILE);
+        let tmp = dir.join(format!("{STATUS_FILE}.tmp"));
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        tokio::task::block_in_place(|| {
+            std::fs::write(&tmp, data).map_err(|e| Error::WriteFile(tmp.clone(), e))?;
+            std::fs::rename(&tmp, &path).map_err(|e| Error::WriteFile(path, e))
+        })
+    }
+}
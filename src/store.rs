@@ -0,0 +1,1087 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Persistence of the discovery state.
+
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    io::{BufWriter, Read, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use hashbrown::HashMap;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    error::Error,
+    migrate::load_state,
+    state::{BufferedTrace, State, TraceId, TraceInfo},
+    Args,
+};
+
+/// Backend used to persist the state.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum StateBackend {
+    /// A single gzip-compressed json file, rewritten every cycle.
+    File,
+    /// An embedded key-value store (sled), written incrementally.
+    #[cfg(feature = "sled")]
+    Sled,
+    /// An object store (S3, GCS or Azure Blob) at `--state-url`, with
+    /// a local copy in the state directory.
+    #[cfg(feature = "object-store")]
+    ObjectStore,
+    /// A Kubernetes ConfigMap (`--state-configmap`) holding the
+    /// service catalog; the trace cache is not persisted.
+    #[cfg(feature = "kubernetes")]
+    Configmap,
+}
+
+/// Compression of the state file.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum StateCompression {
+    /// `state.json.gz` (levels 0 to 9, default 1).
+    Gzip,
+    /// `state.json.zst` (levels 1 to 22, default 3).
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Format of an exported state file.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum StateFormat {
+    /// Plain (pretty-printed) json.
+    Json,
+    /// Gzip-compressed json, as written by the file backend.
+    Gzip,
+    /// Zstd-compressed json, as written by the file backend.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Prefix of an encrypted state file.
+const ENCRYPTED_MAGIC: &[u8] = b"JDSTATE\x01";
+
+pub(crate) enum StateStore {
+    /// State is not persisted (`--no-state`).
+    Memory,
+    File(FileStore),
+    #[cfg(feature = "sled")]
+    Sled(sled_store::SledStore),
+    #[cfg(feature = "object-store")]
+    Object(object_store_store::ObjectStateStore),
+    #[cfg(feature = "kubernetes")]
+    ConfigMap(configmap_store::ConfigMapStore),
+}
+
+impl StateStore {
+    /// Open the state store in the state directory.
+    pub(crate) fn open(args: &Args) -> Result<Self, Error> {
+        if args.no_state {
+            return Ok(Self::Memory);
+        }
+        match args.state_backend {
+            StateBackend::File => {
+                let mut store = FileStore::new(args)?;
+                store.trace_cache = args
+                    .trace_cache_interval
+                    .map(|interval| TraceCacheSchedule { interval, saves: 0 });
+                store.backups = args.state_backups;
+                Ok(Self::File(store))
+            }
+            #[cfg(feature = "sled")]
+            StateBackend::Sled => {
+                #[cfg(feature = "encryption")]
+                if args.state_key.is_some() || args.state_key_file.is_some() {
+                    return Err(Error::EncryptionUnsupported("sled"));
+                }
+                Ok(Self::Sled(sled_store::SledStore::open(
+                    crate::required(&args.state, "--state")?.join("state.sled"),
+                )?))
+            }
+            #[cfg(feature = "object-store")]
+            StateBackend::ObjectStore => {
+                Ok(Self::Object(object_store_store::ObjectStateStore::open(
+                    crate::required(&args.state_url, "--state-url")?,
+                    FileStore::new(args)?,
+                )?))
+            }
+            #[cfg(feature = "kubernetes")]
+            StateBackend::Configmap => {
+                #[cfg(feature = "encryption")]
+                if args.state_key.is_some() || args.state_key_file.is_some() {
+                    return Err(Error::EncryptionUnsupported("configmap"));
+                }
+                Ok(Self::ConfigMap(configmap_store::ConfigMapStore::open(
+                    crate::required(&args.state_configmap, "--state-configmap")?,
+                    args.state_namespace.as_deref(),
+                )?))
+            }
+        }
+    }
+
+    /// Load the state, or create an empty state if none was saved.
+    pub(crate) async fn load(&mut self) -> Result<State, Error> {
+        match self {
+            Self::Memory => Ok(State::new()),
+            Self::File(store) => store.load().await,
+            #[cfg(feature = "sled")]
+            Self::Sled(store) => store.load(),
+            #[cfg(feature = "object-store")]
+            Self::Object(store) => store.load().await,
+            #[cfg(feature = "kubernetes")]
+            Self::ConfigMap(store) => store.load().await,
+        }
+    }
+
+    pub(crate) async fn save(&mut self, state: &State) -> Result<(), Error> {
+        match self {
+            Self::Memory => Ok(()),
+            Self::File(store) => store.save(state).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(store) => store.save(state).await,
+            #[cfg(feature = "object-store")]
+            Self::Object(store) => store.save(state).await,
+            #[cfg(feature = "kubernetes")]
+            Self::ConfigMap(store) => store.save(state).await,
+        }
+    }
+
+    /// Size of the saved state, as stored, if known.
+    pub(crate) fn size(&self) -> Option<u64> {
+        match self {
+            Self::Memory => None,
+            Self::File(store) => Some(store.size()),
+            #[cfg(feature = "sled")]
+            Self::Sled(store) => store.size(),
+            #[cfg(feature = "object-store")]
+            Self::Object(store) => Some(store.size()),
+            #[cfg(feature = "kubernetes")]
+            Self::ConfigMap(store) => store.size(),
+        }
+    }
+}
+
+/// Advisory lock on the state directory, held while the value lives,
+/// so two instances cannot write the same state.
+pub(crate) struct StateLock {
+    _file: std::fs::File,
+}
+
+impl StateLock {
+    /// Lock the state directory, if any, failing if another instance
+    /// holds the lock. The process id of the holder is written to the
+    /// lock file.
+    pub(crate) fn acquire(args: &Args) -> Result<Option<Self>, Error> {
+        let dir = match &args.state {
+            Some(dir) if !args.no_state => dir,
+            _ => return Ok(None),
+        };
+        let path = dir.join("lock");
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::WriteFile(path.clone(), e))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => return Err(Error::StateLocked(dir.clone())),
+            Err(std::fs::TryLockError::Error(e)) => return Err(Error::WriteFile(path, e)),
+        }
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| Error::WriteFile(path, e))?;
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// State stored as a single compressed json file, rewritten every
+/// cycle. Optionally, the trace cache is kept in a separate file,
+/// written less often.
+pub(crate) struct FileStore {
+    dir: PathBuf,
+    compression: StateCompression,
+    level: i32,
+    #[cfg(feature = "encryption")]
+    key: Option<encryption::StateKey>,
+    trace_cache: Option<TraceCacheSchedule>,
+    /// Number of previous state files kept.
+    backups: usize,
+}
+
+/// Schedule for writing the trace cache separately.
+struct TraceCacheSchedule {
+    interval: NonZeroU32,
+    saves: u32,
+}
+
+/// The part of the state written separately with
+/// `--trace-cache-interval`.
+#[derive(Serialize)]
+struct TraceCache<'a> {
+    traces: &'a HashMap<TraceId, TraceInfo>,
+    buffered: &'a HashMap<TraceId, BufferedTrace>,
+}
+
+impl FileStore {
+    fn new(args: &Args) -> Result<Self, Error> {
+        let compression = args.state_compression;
+        let level = args
+            .state_compression_level
+            .unwrap_or(compression.default_level());
+        if !compression.levels().contains(&level) {
+            return Err(Error::CompressionLevel(level, compression.name()));
+        }
+        Ok(Self {
+            dir: crate::required(&args.state, "--state")?.clone(),
+            compression,
+            level,
+            #[cfg(feature = "encryption")]
+            key: encryption::StateKey::from_args(args)?,
+            trace_cache: None,
+            backups: 0,
+        })
+    }
+
+    /// Load the state, falling back to a file written with another
+    /// compression, so the compression can be changed without losing
+    /// the state. A separately written trace cache is merged in.
+    async fn load(&self) -> Result<State, Error> {
+        let Some((compression, path, data)) = self.read(StateCompression::file_name).await? else {
+            return self.recover(None).await;
+        };
+        match self.load_file(compression, &data, &path).await {
+            Err(e) if self.backups > 0 && !matches!(e, Error::StateVersion(..)) => {
+                self.recover(Some(e)).await
+            }
+            res => res,
+        }
+    }
+
+    async fn load_file(
+        &self,
+        compression: StateCompression,
+        data: &[u8],
+        path: &Path,
+    ) -> Result<State, Error> {
+        let mut value = self.decode_value(compression, data, path)?;
+
+        if let Some((compression, trace_path, data)) =
+            self.read(StateCompression::trace_file_name).await?
+        {
+            if let (Value::Object(state), Value::Object(traces)) = (
+                &mut value,
+                self.decode_value(compression, &data, &trace_path)?,
+            ) {
+                state.extend(traces);
+            }
+        }
+
+        load_state(value, path)
+    }
+
+    /// Load the most recent readable backup, after the state file
+    /// failed to load (`error`) or is missing (e.g. after a crash
+    /// while it was being replaced).
+    async fn recover(&self, error: Option<Error>) -> Result<State, Error> {
+        if let Some(e) = &error {
+            log::warn!("failed to load state: {e}");
+        }
+        for n in 1..=self.backups {
+            for compression in StateCompression::value_variants() {
+                let path = backup_path(&self.dir.join(compression.file_name()), n);
+                if !path.exists() {
+                    continue;
+                }
+                let res = match tokio::fs::read(&path).await {
+                    Ok(data) => self.load_file(*compression, &data, &path).await,
+                    Err(e) => Err(Error::ReadFile(path.clone(), e)),
+                };
+                match res {
+                    Ok(state) => {
+                        log::warn!("recovered state from backup {}", path.display());
+                        return Ok(state);
+                    }
+                    Err(e) => log::warn!("failed to load state backup: {e}"),
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(State::new()),
+        }
+    }
+
+    /// Read the first existing file of the given kind, preferring the
+    /// configured compression.
+    async fn read(
+        &self,
+        file_name: fn(StateCompression) -> &'static str,
+    ) -> Result<Option<(StateCompression, PathBuf, Vec<u8>)>, Error> {
+        let others = StateCompression::value_variants()
+            .iter()
+            .filter(|c| **c != self.compression);
+        for compression in std::iter::once(&self.compression).chain(others) {
+            let path = self.dir.join(file_name(*compression));
+            if path.exists() {
+                let data = tokio::fs::read(&path)
+                    .await
+                    .map_err(|e| Error::ReadFile(path.clone(), e))?;
+                return Ok(Some((*compression, path, data)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn save(&mut self, state: &State) -> Result<(), Error> {
+        let Some(schedule) = &mut self.trace_cache else {
+            self.replace(|path| self.encode_file(path, state)).await?;
+            return self.remove(StateCompression::trace_file_name, None).await;
+        };
+
+        let write_traces = schedule.saves % schedule.interval.get() == 0;
+        schedule.saves = schedule.saves.wrapping_add(1);
+
+        self.replace(|path| self.encode_file(path, &state.topology()))
+            .await?;
+        if write_traces {
+            log::debug!("saving trace cache");
+            let traces = TraceCache {
+                traces: &state.traces,
+                buffered: &state.buffered,
+            };
+            self.write_file(StateCompression::trace_file_name, |path| {
+                self.encode_file(path, &traces)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Size of the state file and trace cache, as written.
+    fn size(&self) -> u64 {
+        [
+            self.compression.file_name(),
+            self.compression.trace_file_name(),
+        ]
+        .into_iter()
+        .filter_map(|name| std::fs::metadata(self.dir.join(name)).ok())
+        .map(|meta| meta.len())
+        .sum()
+    }
+
+    /// Encode (and encrypt) a value into a file. Unless the state is
+    /// encrypted, the value is compressed while it is written, so the
+    /// encoded state is never held in memory as a whole.
+    fn encode_file<T: Serialize>(&self, path: &Path, value: &T) -> Result<(), Error> {
+        #[cfg(feature = "encryption")]
+        if self.key.is_some() {
+            return write_data(path, &self.encode(value));
+        }
+        std::fs::File::create(path)
+            .and_then(|file| {
+                self.compression
+                    .encode_to(BufWriter::new(file), value, self.level)
+            })
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| Error::WriteFile(path.to_path_buf(), e))
+    }
+
+    #[cfg(any(feature = "encryption", feature = "object-store"))]
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let data = self.compression.encode(value, self.level);
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.key {
+            return key.encrypt(&data);
+        }
+        data
+    }
+
+    /// Decode (and decrypt) a state file written with `compression`.
+    /// `path` is used in error messages only.
+    fn decode_value(
+        &self,
+        compression: StateCompression,
+        data: &[u8],
+        path: &Path,
+    ) -> Result<Value, Error> {
+        let data = self.decrypt(data, path)?;
+        compression
+            .decode(&data)
+            .map_err(|e| Error::Deserialize(path.to_path_buf(), e))
+    }
+
+    /// Decrypt the state if it is encrypted. A state that is not
+    /// encrypted is loaded as is, and encrypted when saved if a key
+    /// is configured.
+    fn decrypt<'a>(&self, data: &'a [u8], path: &Path) -> Result<Cow<'a, [u8]>, Error> {
+        if !data.starts_with(ENCRYPTED_MAGIC) {
+            return Ok(Cow::Borrowed(data));
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.key {
+            return key
+                .decrypt(&data[ENCRYPTED_MAGIC.len()..])
+                .map(Cow::Owned)
+                .ok_or_else(|| Error::Decrypt(path.to_path_buf()));
+        }
+        Err(Error::StateEncrypted(path.to_path_buf()))
+    }
+
+    /// Write an encoded state.
+    #[cfg(feature = "object-store")]
+    async fn write(&self, data: &[u8]) -> Result<(), Error> {
+        self.replace(|path| write_data(path, data)).await
+    }
+
+    /// Replace the state file by one written by `write`, on a blocking
+    /// thread.
+    async fn replace(&self, write: impl FnOnce(&Path) -> Result<(), Error>) -> Result<(), Error> {
+        if self.backups == 0 {
+            return self.write_file(StateCompression::file_name, write).await;
+        }
+
+        /* Write the new state next to the current one, then rotate the
+         * current one into the backups, so there is always a readable
+         * state. */
+        let path = self.dir.join(self.compression.file_name());
+        let tmp = backup_path(&path, "tmp");
+        tokio::task::block_in_place(|| write(&tmp))?;
+        if path.exists() {
+            for n in (1..self.backups).rev() {
+                let from = backup_path(&path, n);
+                if from.exists() {
+                    rename(&from, &backup_path(&path, n + 1)).await?;
+                }
+            }
+            rename(&path, &backup_path(&path, 1)).await?;
+        }
+        rename(&tmp, &path).await?;
+
+        self.remove(StateCompression::file_name, Some(self.compression))
+            .await
+    }
+
+    async fn write_file(
+        &self,
+        file_name: fn(StateCompression) -> &'static str,
+        write: impl FnOnce(&Path) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let path = self.dir.join(file_name(self.compression));
+        tokio::task::block_in_place(|| write(&path))?;
+
+        /* Remove state written with another compression. */
+        self.remove(file_name, Some(self.compression)).await
+    }
+
+    /// Remove files of the given kind, except the one written with
+    /// `keep`.
+    async fn remove(
+        &self,
+        file_name: fn(StateCompression) -> &'static str,
+        keep: Option<StateCompression>,
+    ) -> Result<(), Error> {
+        for compression in StateCompression::value_variants() {
+            let path = self.dir.join(file_name(*compression));
+            if Some(*compression) != keep && path.exists() {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .map_err(|e| Error::WriteFile(path, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of backup `n` (1 being the most recent) of a state file.
+fn backup_path(path: &Path, n: impl Display) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+#[cfg(any(feature = "encryption", feature = "object-store"))]
+fn write_data(path: &Path, data: &[u8]) -> Result<(), Error> {
+    std::fs::write(path, data).map_err(|e| Error::WriteFile(path.to_path_buf(), e))
+}
+
+async fn rename(from: &Path, to: &Path) -> Result<(), Error> {
+    tokio::fs::rename(from, to)
+        .await
+        .map_err(|e| Error::WriteFile(to.to_path_buf(), e))
+}
+
+impl StateCompression {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Gzip => "state.json.gz",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "state.json.zst",
+        }
+    }
+
+    fn trace_file_name(self) -> &'static str {
+        match self {
+            Self::Gzip => "traces.json.gz",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "traces.json.zst",
+        }
+    }
+
+    fn default_level(self) -> i32 {
+        match self {
+            Self::Gzip => 1,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 3,
+        }
+    }
+
+    fn levels(self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Self::Gzip => 0..=9,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 1..=22,
+        }
+    }
+
+    fn encode<T: Serialize>(self, state: &T, level: i32) -> Vec<u8> {
+        self.encode_to(Vec::new(), state, level).unwrap()
+    }
+
+    /// Encode a value into `writer`, returning the writer.
+    fn encode_to<W: Write, T: Serialize>(
+        self,
+        writer: W,
+        state: &T,
+        level: i32,
+    ) -> std::io::Result<W> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(writer, Compression::new(level as u32));
+                serde_json::to_writer(&mut encoder, state)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, level)?;
+                serde_json::to_writer(&mut encoder, state)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decode(self, data: &[u8]) -> Result<Value, serde_json::Error> {
+        let reader: Box<dyn Read> = match self {
+            Self::Gzip => Box::new(GzDecoder::new(data)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Box::new(zstd::Decoder::new(data).map_err(serde_json::Error::io)?),
+        };
+        serde_json::from_reader(reader)
+    }
+}
+
+impl StateFormat {
+    /// The format for a file name, by extension (json by default).
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            #[cfg(feature = "zstd")]
+            Some("zst") => Self::Zstd,
+            _ => Self::Json,
+        }
+    }
+
+    /// The format of an encoded state, by its magic bytes.
+    fn detect(data: &[u8]) -> Self {
+        match data {
+            [0x1f, 0x8b, ..] => Self::Gzip,
+            #[cfg(feature = "zstd")]
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Self::Zstd,
+            _ => Self::Json,
+        }
+    }
+
+    pub(crate) fn encode(self, state: &State) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec_pretty(state).unwrap(),
+            Self::Gzip => StateCompression::Gzip.encode(state, 6),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => StateCompression::Zstd.encode(state, 3),
+        }
+    }
+
+    /// Decode and migrate a state in any of the formats. `path` is
+    /// used in error messages only.
+    pub(crate) fn decode(data: &[u8], path: &Path) -> Result<State, Error> {
+        let value = match Self::detect(data) {
+            Self::Json => serde_json::from_slice(data),
+            Self::Gzip => StateCompression::Gzip.decode(data),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => StateCompression::Zstd.decode(data),
+        }
+        .map_err(|e| Error::Deserialize(path.to_path_buf(), e))?;
+        load_state(value, path)
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod encryption {
+    use aes_gcm::{
+        aead::{Aead, AeadCore, KeyInit, OsRng},
+        Aes256Gcm, Key, Nonce,
+    };
+
+    use super::ENCRYPTED_MAGIC;
+    use crate::{error::Error, Args};
+
+    const NONCE_LEN: usize = 12;
+
+    /// Key for AES-256-GCM encryption of the state. Every write uses a
+    /// new random nonce, stored in front of the ciphertext.
+    pub(crate) struct StateKey(Box<Aes256Gcm>);
+
+    impl StateKey {
+        /// Get the key from `--state-key-file` or `--state-key`.
+        pub(crate) fn from_args(args: &Args) -> Result<Option<Self>, Error> {
+            let key = match (&args.state_key_file, &args.state_key) {
+                (Some(path), _) => {
+                    std::fs::read_to_string(path).map_err(|e| Error::ReadFile(path.clone(), e))?
+                }
+                (None, Some(key)) => key.clone(),
+                (None, None) => return Ok(None),
+            };
+            Self::parse(&key).map(Some).ok_or(Error::InvalidStateKey)
+        }
+
+        fn parse(hex: &str) -> Option<Self> {
+            let hex = hex.trim();
+            if hex.len() != 64 {
+                return None;
+            }
+            let key = (0..32)
+                .map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(Self(Box::new(Aes256Gcm::new(
+                Key::<Aes256Gcm>::from_slice(&key),
+            ))))
+        }
+
+        pub(crate) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = self.0.encrypt(&nonce, data).unwrap();
+            [ENCRYPTED_MAGIC, nonce.as_slice(), &ciphertext].concat()
+        }
+
+        /// Decrypt the data following the magic prefix.
+        pub(crate) fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+            if data.len() < NONCE_LEN {
+                return None;
+            }
+            let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+            self.0.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+        }
+    }
+}
+
+#[cfg(feature = "object-store")]
+mod object_store_store {
+    use std::path::PathBuf;
+
+    use object_store::{path::Path, ObjectStore};
+    use url::Url;
+
+    use super::FileStore;
+    use crate::{error::Error, migrate::load_state, state::State};
+
+    /// State stored in an object store. The local state file serves as
+    /// a cache: it is only downloaded if the object was changed (by
+    /// another instance) since we last wrote or read it.
+    pub(crate) struct ObjectStateStore {
+        store: Box<dyn ObjectStore>,
+        path: Path,
+        file: FileStore,
+        /// File holding the etag of the object the cache corresponds to.
+        etag_path: PathBuf,
+    }
+
+    impl ObjectStateStore {
+        /// Open the store at `url`. Credentials are taken from the
+        /// environment (e.g. `AWS_ACCESS_KEY_ID`,
+        /// `GOOGLE_SERVICE_ACCOUNT`, `AZURE_STORAGE_ACCOUNT_KEY`).
+        pub(crate) fn open(url: &Url, file: FileStore) -> Result<Self, Error> {
+            let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+            let (store, prefix) = object_store::parse_url_opts(url, options)?;
+            Ok(Self {
+                store,
+                path: prefix.child(file.compression.file_name()),
+                etag_path: file.dir.join("state.etag"),
+                file,
+            })
+        }
+
+        pub(crate) async fn load(&mut self) -> Result<State, Error> {
+            let meta = match self.store.head(&self.path).await {
+                Ok(meta) => meta,
+                Err(object_store::Error::NotFound { .. }) => return Ok(State::new()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let cached = tokio::fs::read_to_string(&self.etag_path).await.ok();
+            if meta.e_tag.is_some() && meta.e_tag == cached {
+                log::debug!("using cached state");
+                return self.file.load().await;
+            }
+
+            log::info!("downloading state from {}", self.path);
+            let res = self.store.get(&self.path).await?;
+            let etag = res.meta.e_tag.clone();
+            let data = res.bytes().await?;
+            self.file.write(&data).await?;
+            self.save_etag(etag).await?;
+            let path = PathBuf::from(self.path.as_ref());
+            load_state(
+                self.file
+                    .decode_value(self.file.compression, &data, &path)?,
+                &path,
+            )
+        }
+
+        pub(crate) async fn save(&mut self, state: &State) -> Result<(), Error> {
+            let data = self.file.encode(state);
+            self.file.write(&data).await?;
+            let res = self.store.put(&self.path, data.into()).await?;
+            self.save_etag(res.e_tag).await
+        }
+
+        /// Size of the local copy of the state.
+        pub(crate) fn size(&self) -> u64 {
+            self.file.size()
+        }
+
+        async fn save_etag(&self, etag: Option<String>) -> Result<(), Error> {
+            let res = match etag {
+                Some(etag) => tokio::fs::write(&self.etag_path, etag).await,
+                None => match tokio::fs::remove_file(&self.etag_path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    res => res,
+                },
+            };
+            res.map_err(|e| Error::WriteFile(self.etag_path.clone(), e))
+        }
+    }
+}
+
+#[cfg(feature = "kubernetes")]
+mod configmap_store {
+    use std::path::{Path, PathBuf};
+
+    use reqwest::{Certificate, Client, Method, StatusCode};
+    use serde_json::{json, Value};
+    use url::Url;
+
+    use crate::{error::Error, migrate::load_state, state::State};
+
+    /// Service account credentials mounted into the pod.
+    const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+    /// Key of the state in the ConfigMap's data.
+    const DATA_KEY: &str = "state.json";
+
+    /// Service catalog stored in a ConfigMap, using the in-cluster
+    /// service account to access the Kubernetes API. The state is
+    /// kept as plain json, so it can be inspected and edited with
+    /// kubectl.
+    pub(crate) struct ConfigMapStore {
+        client: Client,
+        url: Url,
+        namespace: String,
+        name: String,
+        /// Whether the ConfigMap exists, i.e. whether to replace or
+        /// create it on save.
+        exists: bool,
+        /// Size of the state last saved.
+        size: Option<u64>,
+    }
+
+    impl ConfigMapStore {
+        pub(crate) fn open(name: &str, namespace: Option<&str>) -> Result<Self, Error> {
+            let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| Error::NotInCluster)?;
+            let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+            let host = match host.contains(':') {
+                true => format!("[{host}]"),
+                false => host,
+            };
+            let ca_path = Path::new(SERVICE_ACCOUNT).join("ca.crt");
+            let ca = std::fs::read(&ca_path).map_err(|e| Error::ReadFile(ca_path.clone(), e))?;
+            let namespace = match namespace {
+                Some(namespace) => namespace.to_string(),
+                None => read_secret("namespace")?,
+            };
+            Ok(Self {
+                client: Client::builder()
+                    .timeout(std::time::Duration::from_secs(60))
+                    .add_root_certificate(Certificate::from_pem(&ca)?)
+                    .build()?,
+                url: Url::parse(&format!("https://{host}:{port}/"))?,
+                namespace,
+                name: name.to_string(),
+                exists: false,
+                size: None,
+            })
+        }
+
+        pub(crate) async fn load(&mut self) -> Result<State, Error> {
+            let res = self.request(Method::GET, Some(&self.name), None).await?;
+            let Some(configmap) = res else {
+                return Ok(State::new());
+            };
+            self.exists = true;
+            let path = self.path();
+            match configmap.get("data").and_then(|data| data.get(DATA_KEY)) {
+                Some(Value::String(data)) => load_state(
+                    serde_json::from_str(data).map_err(|e| Error::Deserialize(path.clone(), e))?,
+                    &path,
+                ),
+                _ => Ok(State::new()),
+            }
+        }
+
+        pub(crate) async fn save(&mut self, state: &State) -> Result<(), Error> {
+            let data = serde_json::to_string(&state.topology()).unwrap();
+            self.size = Some(data.len() as u64);
+            let body = json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": {
+                    "name": self.name,
+                    "namespace": self.namespace,
+                    "labels": { "app.kubernetes.io/managed-by": "jaeger-discovery" },
+                },
+                "data": { DATA_KEY: data },
+            });
+            if self.exists
+                && self
+                    .request(Method::PUT, Some(&self.name), Some(&body))
+                    .await?
+                    .is_some()
+            {
+                return Ok(());
+            }
+            self.request(Method::POST, None, Some(&body)).await?;
+            self.exists = true;
+            Ok(())
+        }
+
+        pub(crate) fn size(&self) -> Option<u64> {
+            self.size
+        }
+
+        /// Send a request for the ConfigMap (or the collection, when
+        /// creating it). Returns `None` if the ConfigMap does not exist.
+        async fn request(
+            &self,
+            method: Method,
+            name: Option<&str>,
+            body: Option<&Value>,
+        ) -> Result<Option<Value>, Error> {
+            let mut url = self
+                .url
+                .join(&format!("api/v1/namespaces/{}/configmaps/", self.namespace))?;
+            if let Some(name) = name {
+                url = url.join(name)?;
+            }
+            let mut req = self
+                .client
+                .request(method, url)
+                .bearer_auth(read_secret("token")?);
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+            let res = req.send().await?;
+            match res.status() {
+                StatusCode::NOT_FOUND => Ok(None),
+                status if status.is_success() => Ok(Some(res.json().await?)),
+                status => {
+                    let message = res
+                        .json::<Value>()
+                        .await
+                        .ok()
+                        .and_then(|v| v.get("message")?.as_str().map(String::from))
+                        .unwrap_or_default();
+                    Err(Error::Kubernetes(status, message))
+                }
+            }
+        }
+
+        /// Name of the ConfigMap, for error messages.
+        fn path(&self) -> PathBuf {
+            PathBuf::from(format!("configmap/{}/{}", self.namespace, self.name))
+        }
+    }
+
+    /// Read a file from the service account directory. The token is
+    /// read on every request, since it is rotated.
+    fn read_secret(name: &str) -> Result<String, Error> {
+        let path = Path::new(SERVICE_ACCOUNT).join(name);
+        std::fs::read_to_string(&path)
+            .map(|s| s.trim().to_string())
+            .map_err(|e| Error::ReadFile(path, e))
+    }
+}
+
+#[cfg(feature = "sled")]
+mod sled_store {
+    use std::{
+        collections::BTreeMap,
+        hash::{DefaultHasher, Hash, Hasher},
+        path::PathBuf,
+    };
+
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::{Map, Value};
+
+    use crate::{error::Error, migrate::load_state, state::State};
+
+    /// Trees holding a record per map entry of the state. Other fields
+    /// are kept in the `meta` tree.
+    const TREES: [&str; 6] = [
+        "traces",
+        "buffered",
+        "services",
+        "destinations",
+        "external_endpoints",
+        "custom_items",
+    ];
+
+    /// State stored as one record per trace, service, etc., in a tree
+    /// per state field. Only records that changed since they were
+    /// last loaded or saved are written.
+    pub(crate) struct SledStore {
+        path: PathBuf,
+        db: sled::Db,
+        /// Hashes of the records in the store, by tree and key.
+        written: BTreeMap<&'static str, BTreeMap<Vec<u8>, u64>>,
+    }
+
+    impl SledStore {
+        pub(crate) fn open(path: PathBuf) -> Result<Self, Error> {
+            let db = sled::open(&path)?;
+            Ok(Self {
+                path,
+                db,
+                written: BTreeMap::new(),
+            })
+        }
+
+        pub(crate) fn load(&mut self) -> Result<State, Error> {
+            if !self.db.was_recovered() {
+                return Ok(State::new());
+            }
+
+            /* Assemble the state as json, so it can be migrated. */
+            let mut value = self.load_tree::<Map<_, _>, String, Value>("meta")?;
+            for name in TREES {
+                let tree = self.load_tree(name)?;
+                value.insert(name.to_string(), Value::Object(tree));
+            }
+            load_state(Value::Object(value), &self.path)
+        }
+
+        pub(crate) async fn save(&mut self, state: &State) -> Result<(), Error> {
+            let meta = BTreeMap::from([
+                ("version", serde_json::to_value(state.version).unwrap()),
+                ("last_span", serde_json::to_value(state.last_span).unwrap()),
+                ("last_run", serde_json::to_value(&state.last_run).unwrap()),
+                ("slices", serde_json::to_value(&state.slices).unwrap()),
+            ]);
+            self.save_tree("meta", &meta)?;
+            self.save_tree("traces", &state.traces)?;
+            self.save_tree("buffered", &state.buffered)?;
+            self.save_tree("services", &state.services)?;
+            self.save_tree("destinations", &state.destinations)?;
+            self.save_tree("external_endpoints", &state.external_endpoints)?;
+            self.save_tree("custom_items", &state.custom_items)?;
+            self.db.flush_async().await?;
+            Ok(())
+        }
+
+        pub(crate) fn size(&self) -> Option<u64> {
+            self.db.size_on_disk().ok()
+        }
+
+        fn load_tree<M, K, V>(&mut self, name: &'static str) -> Result<M, Error>
+        where
+            M: FromIterator<(K, V)>,
+            K: DeserializeOwned,
+            V: DeserializeOwned,
+        {
+            let tree = self.db.open_tree(name)?;
+            let written = self.written.entry(name).or_default();
+            let path = self.path.join(name);
+            tree.iter()
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    written.insert(key.to_vec(), hash(&value));
+                    Ok((
+                        serde_json::from_slice(&key)
+                            .map_err(|e| Error::Deserialize(path.clone(), e))?,
+                        serde_json::from_slice(&value)
+                            .map_err(|e| Error::Deserialize(path.clone(), e))?,
+                    ))
+                })
+                .collect()
+        }
+
+        fn save_tree<'a, K, V>(
+            &mut self,
+            name: &'static str,
+            map: impl IntoIterator<Item = (&'a K, &'a V)>,
+        ) -> Result<(), Error>
+        where
+            K: Serialize + 'a,
+            V: Serialize + 'a,
+        {
+            let tree = self.db.open_tree(name)?;
+            let written = self.written.entry(name).or_default();
+            let mut batch = sled::Batch::default();
+            let mut current = BTreeMap::new();
+            for (key, value) in map {
+                let key = serde_json::to_vec(key).unwrap();
+                let value = serde_json::to_vec(value).unwrap();
+                let value_hash = hash(&value);
+                if written.get(&key) != Some(&value_hash) {
+                    batch.insert(key.clone(), value);
+                }
+                current.insert(key, value_hash);
+            }
+            written
+                .keys()
+                .filter(|key| !current.contains_key(*key))
+                .for_each(|key| batch.remove(key.clone()));
+            tree.apply_batch(batch)?;
+            *written = current;
+            Ok(())
+        }
+    }
+
+    fn hash(data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+}
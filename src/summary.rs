@@ -0,0 +1,233 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Summary of a discovery cycle: what was processed, what changed in
+//! the topology and where the time went. It is logged as a single
+//! structured event at the end of every cycle, and exported as
+//! metrics, together with the size of the state after the cycle.
+
+use std::{
+    collections::BTreeSet,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    register_gauge_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+use crate::state::{ServiceKey, State};
+
+static SPANS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "jaeger_discovery_spans_processed_total",
+        "Number of spans processed."
+    )
+    .unwrap()
+});
+
+static FETCHED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "jaeger_discovery_fetched_bytes_total",
+        "Size of the search responses received from Opensearch."
+    )
+    .unwrap()
+});
+
+static SERVICE_CHANGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "jaeger_discovery_service_changes_total",
+        "Number of services added to or removed from the topology, by change.",
+        &["change"]
+    )
+    .unwrap()
+});
+
+static RELATION_CHANGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "jaeger_discovery_relation_changes_total",
+        "Number of service relations added to or removed from the topology, by change.",
+        &["change"]
+    )
+    .unwrap()
+});
+
+static PHASE_SECONDS: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "jaeger_discovery_phase_seconds",
+        "Duration of the phases of the last discovery cycle, by phase.",
+        &["phase"]
+    )
+    .unwrap()
+});
+
+static STATE_ENTRIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "jaeger_discovery_state_entries",
+        "Number of entries in the state after the last cycle, by kind \
+         (traces, spans, services, operations or relations).",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+static STATE_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "jaeger_discovery_state_bytes",
+        "Size of the state as saved after the last cycle."
+    )
+    .unwrap()
+});
+
+/// The services and service relations (invocations and links, by
+/// target and source) in the state, to find the changes made by a
+/// cycle.
+struct Topology {
+    services: BTreeSet<ServiceKey>,
+    relations: BTreeSet<(ServiceKey, ServiceKey, bool)>,
+}
+
+impl Topology {
+    fn new(state: &State) -> Self {
+        Self {
+            services: state.services.keys().cloned().collect(),
+            relations: state
+                .services
+                .iter()
+                .flat_map(|(target, svc_state)| {
+                    let invokes = svc_state.relations.keys().map(|source| (source, false));
+                    let links = svc_state.links.keys().map(|source| (source, true));
+                    invokes
+                        .chain(links)
+                        .map(|(source, link)| (target.clone(), source.clone(), link))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A phase of a discovery cycle.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Phase {
+    Preflight,
+    Scan,
+    Expire,
+    Publish,
+    Save,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [
+        Phase::Preflight,
+        Phase::Scan,
+        Phase::Expire,
+        Phase::Publish,
+        Phase::Save,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Preflight => "preflight",
+            Phase::Scan => "scan",
+            Phase::Expire => "expire",
+            Phase::Publish => "publish",
+            Phase::Save => "save",
+        }
+    }
+}
+
+/// The summary of a cycle, filled in as it runs.
+pub(crate) struct CycleSummary {
+    start: Instant,
+    before: Topology,
+    pub(crate) spans: u64,
+    /// Size of the search responses received from Opensearch.
+    pub(crate) fetched_bytes: u64,
+    /// Time spent in every phase, by `Phase`.
+    phases: [Duration; Phase::ALL.len()],
+}
+
+impl CycleSummary {
+    /// Start the summary of a cycle on `state`.
+    pub(crate) fn new(state: &State) -> Self {
+        Self {
+            start: Instant::now(),
+            before: Topology::new(state),
+            spans: 0,
+            fetched_bytes: 0,
+            phases: Default::default(),
+        }
+    }
+
+    /// Record that `phase`, started at `start`, has finished.
+    pub(crate) fn phase(&mut self, phase: Phase, start: Instant) {
+        self.phases[phase as usize] += start.elapsed();
+    }
+
+    /// Log the summary and update the metrics, comparing the topology
+    /// with the one at the start of the cycle. `state_bytes` is the
+    /// size of the saved state, if known.
+    pub(crate) fn finish(self, state: &State, state_bytes: Option<u64>, error: Option<&str>) {
+        let after = Topology::new(state);
+        let services_added = after.services.difference(&self.before.services).count();
+        let services_removed = self.before.services.difference(&after.services).count();
+        let relations_added = after.relations.difference(&self.before.relations).count();
+        let relations_removed = self.before.relations.difference(&after.relations).count();
+        let phase_ms = |phase: Phase| self.phases[phase as usize].as_millis() as u64;
+
+        tracing::info!(
+            target: "jaeger_discovery::summary",
+            success = error.is_none(),
+            error,
+            spans = self.spans,
+            services_added,
+            services_removed,
+            relations_added,
+            relations_removed,
+            fetched_bytes = self.fetched_bytes,
+            preflight_ms = phase_ms(Phase::Preflight),
+            scan_ms = phase_ms(Phase::Scan),
+            expire_ms = phase_ms(Phase::Expire),
+            publish_ms = phase_ms(Phase::Publish),
+            save_ms = phase_ms(Phase::Save),
+            total_ms = self.start.elapsed().as_millis() as u64,
+            "discovery cycle finished"
+        );
+
+        SPANS.inc_by(self.spans);
+        FETCHED_BYTES.inc_by(self.fetched_bytes);
+        SERVICE_CHANGES
+            .with_label_values(&["added"])
+            .inc_by(services_added as u64);
+        SERVICE_CHANGES
+            .with_label_values(&["removed"])
+            .inc_by(services_removed as u64);
+        RELATION_CHANGES
+            .with_label_values(&["added"])
+            .inc_by(relations_added as u64);
+        RELATION_CHANGES
+            .with_label_values(&["removed"])
+            .inc_by(relations_removed as u64);
+        for phase in Phase::ALL {
+            PHASE_SECONDS
+                .with_label_values(&[phase.name()])
+                .set(self.phases[phase as usize].as_secs_f64());
+        }
+
+        let counts = state.counts();
+        for (kind, n) in [
+            ("traces", counts.traces),
+            ("spans", counts.spans),
+            ("services", counts.services),
+            ("operations", counts.operations),
+            ("relations", counts.relations),
+        ] {
+            STATE_ENTRIES.with_label_values(&[kind]).set(n as i64);
+        }
+        if let Some(bytes) = state_bytes {
+            STATE_BYTES.set(bytes as i64);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Write-ahead log of the span batches processed since the state was
+//! last saved, so a crash neither loses nor repeats their effect.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{discovery::Span, error::Error, state::QueryPosition};
+
+/// Append-only log of span batches, one json record per line. The log
+/// is flushed to disk after every batch and truncated when the state
+/// is saved.
+pub(crate) struct SpanLog {
+    path: PathBuf,
+    file: File,
+}
+
+/// A batch of spans, with the position of the query after it (the
+/// `startTime` of its last span, in microseconds) and the position of
+/// every slice of the query.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Batch<S> {
+    pub(crate) position: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) slices: Option<Vec<Option<QueryPosition>>>,
+    pub(crate) spans: Vec<S>,
+}
+
+impl SpanLog {
+    /// Open the log, returning the batches written since the state was
+    /// last saved. A record cut off by a crash is dropped, and removed
+    /// from the log so the next batch is not appended to it.
+    pub(crate) fn open(path: PathBuf) -> Result<(Self, Vec<Batch<Span<'static>>>), Error> {
+        let mut batches = Vec::new();
+        /* Length of the log up to the end of the last complete record. */
+        let mut len = 0;
+        match File::open(&path) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    let n = reader
+                        .read_until(b'\n', &mut line)
+                        .map_err(|e| Error::ReadFile(path.clone(), e))?;
+                    if n == 0 {
+                        break;
+                    }
+                    if line.last() != Some(&b'\n') {
+                        log::warn!("ignoring truncated span log record");
+                        break;
+                    }
+                    match serde_json::from_slice::<Batch<Span>>(&line) {
+                        Ok(batch) => batches.push(Batch {
+                            position: batch.position,
+                            slices: batch.slices,
+                            spans: batch.spans.into_iter().map(Span::into_owned).collect(),
+                        }),
+                        Err(e) => {
+                            log::warn!("ignoring truncated span log record: {e}");
+                            break;
+                        }
+                    }
+                    len += n as u64;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::ReadFile(path, e)),
+        }
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::WriteFile(path.clone(), e))?;
+        file.set_len(len)
+            .and_then(|()| file.sync_data())
+            .map_err(|e| Error::WriteFile(path.clone(), e))?;
+        Ok((Self { path, file }, batches))
+    }
+
+    /// Encode a batch as a log record. The spans are moved into the
+    /// state while processing, so the record is encoded beforehand and
+    /// appended once the batch was processed.
+    pub(crate) fn record(batch: &Batch<&Span<'_>>) -> Vec<u8> {
+        let mut data = serde_json::to_vec(batch).unwrap();
+        data.push(b'\n');
+        data
+    }
+
+    /// Append a record and wait until it is on disk.
+    pub(crate) fn append(&mut self, record: &[u8]) -> Result<(), Error> {
+        self.file
+            .write_all(record)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| Error::WriteFile(self.path.clone(), e))
+    }
+
+    /// Empty the log, once the batches are part of the saved state.
+    pub(crate) fn truncate(&mut self) -> Result<(), Error> {
+        self.file
+            .set_len(0)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| Error::WriteFile(self.path.clone(), e))
+    }
+}